@@ -0,0 +1,107 @@
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields};
+
+/// Derives `BeansStruct`, `FromValue` and `IntoValue` for a struct with
+/// named fields, so it can travel through `Value::StructInstance`.
+/// Every field type has to implement `Clone`, `FromValue` and `IntoValue`.
+#[proc_macro_derive(BeansStruct)]
+pub fn derive_beans_struct(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => fields.named.iter().map(|f| f.ident.clone().unwrap()),
+            _ => {
+                return syn::Error::new_spanned(name, "BeansStruct needs named fields")
+                    .to_compile_error()
+                    .into()
+            }
+        },
+        _ => {
+            return syn::Error::new_spanned(name, "BeansStruct can only be derived for structs")
+                .to_compile_error()
+                .into()
+        }
+    };
+    let fields: Vec<_> = fields.collect();
+    let names: Vec<_> = fields.iter().map(|f| f.to_string()).collect();
+    let name_str = name.to_string();
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let expanded = quote! {
+        impl #impl_generics ::beans_lang::convert::BeansStruct for #name #ty_generics #where_clause {
+            fn base_struct() -> ::std::rc::Rc<::beans_lang::environments::BaseStruct> {
+                thread_local! {
+                    static BASE: ::std::rc::Rc<::beans_lang::environments::BaseStruct> =
+                        ::std::rc::Rc::new(::beans_lang::environments::BaseStruct::new(
+                            vec![#( ::std::string::String::from(#names) ),*],
+                            ::std::string::String::from(#name_str),
+                        ));
+                }
+                BASE.with(::std::rc::Rc::clone)
+            }
+
+            fn get_field(&self, field: &str) -> ::std::option::Option<::beans_lang::environments::Value> {
+                match field {
+                    #( #names => ::std::option::Option::Some(
+                        ::beans_lang::convert::IntoValue::into_value(self.#fields.clone())
+                    ), )*
+                    _ => ::std::option::Option::None,
+                }
+            }
+
+            fn set_field(
+                &mut self,
+                field: &str,
+                value: ::beans_lang::environments::Value,
+            ) -> ::std::result::Result<(), ::beans_lang::errors::BeansError> {
+                match field {
+                    #( #names => {
+                        self.#fields = ::beans_lang::convert::FromValue::from_value(value)?;
+                        ::std::result::Result::Ok(())
+                    } )*
+                    _ => ::std::result::Result::Err(::beans_lang::errors::BeansError::new(
+                        ::beans_lang::errors::ErrorKind::InvalidAssignment,
+                        format!("{} is not a member of {}", field, #name_str),
+                    )),
+                }
+            }
+        }
+
+        impl #impl_generics ::beans_lang::convert::IntoValue for #name #ty_generics #where_clause {
+            fn into_value(self) -> ::beans_lang::environments::Value {
+                let mut fields = ::std::collections::HashMap::new();
+                #( fields.insert(
+                    ::std::string::String::from(#names),
+                    ::beans_lang::convert::IntoValue::into_value(self.#fields),
+                ); )*
+                ::beans_lang::environments::Value::StructInstance(
+                    ::beans_lang::environments::StructInstance::new(
+                        fields,
+                        <Self as ::beans_lang::convert::BeansStruct>::base_struct(),
+                    ),
+                )
+            }
+        }
+
+        impl #impl_generics ::beans_lang::convert::FromValue for #name #ty_generics #where_clause {
+            fn from_value(
+                value: ::beans_lang::environments::Value,
+            ) -> ::std::result::Result<Self, ::beans_lang::errors::BeansError> {
+                let instance = ::beans_lang::convert::struct_instance::<Self>(value)?;
+                ::std::result::Result::Ok(#name {
+                    #( #fields: ::beans_lang::convert::FromValue::from_value(
+                        instance.get(&::std::string::String::from(#names)).cloned()
+                            .unwrap_or(::beans_lang::environments::Value::Nil),
+                    )?, )*
+                })
+            }
+        }
+    };
+    expanded.into()
+}
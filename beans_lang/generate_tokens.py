@@ -3,6 +3,8 @@ import sys
 top = """
 use std::string::String;
 use std::collections::HashMap;
+use std::rc::Rc;
+use super::span::Span;
 #[derive(PartialEq, PartialOrd, Copy, Clone, Debug)]
 pub enum TokenType { 
 """
@@ -26,22 +28,24 @@ token_struct = """
 pub enum Value {
     Empty,
     Num(f64),
+    Int(i64),
     Str(String),
-    Id(String),
+    // Interned by the lexer, so repeated names share their text
+    Id(Rc<str>),
 }
 
 #[derive(Debug)]
 pub struct Token {
     token_type : TokenType,
-    line: u16,
+    span: Span,
     val: Value,
 }
 
 impl Token{
-    pub fn new(token_type : TokenType, line : u16, val : Value) -> Token {
+    pub fn new(token_type : TokenType, span : Span, val : Value) -> Token {
         Token {
             token_type : token_type,
-            line : line,
+            span : span,
             val : val,
         }
     }
@@ -54,8 +58,12 @@ impl Token{
         self.token_type
     }
 
-    pub fn get_line(&self) -> u16 {
-        self.line
+    pub fn get_line(&self) -> usize {
+        self.span.line
+    }
+
+    pub fn get_span(&self) -> Span {
+        self.span
     }
 
     pub fn as_f64(&self) -> f64 {
@@ -65,6 +73,13 @@ impl Token{
         }
     }
 
+    pub fn as_i64(&self) -> i64 {
+        match self.val {
+            Value::Int(n) => n,
+            _ => panic!("Falied converting Value to i64!")
+        }
+    }
+
     pub fn as_String(&self) -> String {
         match &self.val {
             Value::Str(s) => s.clone(),
@@ -74,7 +89,7 @@ impl Token{
 
     pub fn as_Id(&self) -> String {
         match &self.val {
-            Value::Id(s) => s.clone(),
+            Value::Id(s) => s.to_string(),
             _ => panic!("Falied converting Value to Id!")
         }
     }
@@ -132,8 +147,10 @@ def main():
         print("Usage:", sys.argv[0], "input_grammar output_file")
 
     input_file = open(sys.argv[1], "r")
-    tokens = {("", "Eof"), ("", "Str"), ("", "Num"),
-              ("", "Identifier")}
+    # Produced by the lexer without appearing in the grammar as literals
+    tokens = {("", "Eof"), ("", "Str"), ("", "Num"), ("", "Int"),
+              ("", "Identifier"), ("", "InterpolationStart"),
+              ("", "InterpolationMid"), ("", "InterpolationEnd")}
     c = input_file.read(1)
     while c != '':
         if c == '"':
@@ -141,6 +158,9 @@ def main():
             tokens = tokens | {(generator, token)}
         c = input_file.read(1)
 
+    # Sorted, so that regenerating the file only changes what the grammar did
+    tokens = sorted(tokens, key=lambda t: t[1])
+
     output_file = open(sys.argv[2], "w")
     output_file.write(top)
     for (_, token) in tokens:
@@ -0,0 +1,795 @@
+use super::environments::Value;
+use super::node::ExprKind::*;
+use super::node::*;
+use super::resolver::Resolver;
+use super::span::Span;
+use super::tokens::TokenType;
+use std::rc::Rc;
+
+/// A single VM instruction. Operands index into the tables of the `Chunk`
+/// the instruction belongs to.
+#[derive(Clone, Copy, Debug)]
+pub enum Op {
+    Constant(u32),
+    Nil,
+    True,
+    False,
+    Pop,
+    GetVar(u32),
+    SetVar(u32),
+    DefineVar(u32),
+    // Locals resolved to (depth, slot)
+    GetLocal(u32, u32),
+    SetLocal(u32, u32),
+    DefineLocal(u32),
+    // Like `SetVar` and `SetLocal`, popping the value, for assignments
+    // whose value is not used
+    StoreVar(u32),
+    StoreLocal(u32, u32),
+    Get(u32),
+    Set(u32),
+    Index,
+    SetIndex,
+    Slice,
+    Binary(TokenType),
+    // A binary operation with a constant right operand
+    BinaryConstant(TokenType, u32),
+    Negate,
+    Jump(u32),
+    JumpIfFalse(u32),
+    // Replaces the iterable on top of the stack with the state of a loop
+    // over it, the flag telling if the loop has two variables
+    Iterate(bool),
+    // Pushes the values of the loop variables, last variable first, or pops
+    // the loop state and jumps once it is done
+    ForNext(u32),
+    CheckCall(u32),
+    Call(u32),
+    Closure(u32),
+    Struct(u32),
+    EnumValue,
+    Enum(u32),
+    List(u32),
+    Dict(u32),
+    Interpolate(u32),
+    // Runs the module at the named path, pushing what it returns
+    Import(u32),
+    // Enter a new environment enclosing the current one, and leave it
+    PushScope,
+    PopScope,
+    // Replaces the current environment with a copy, for the next iteration
+    // of a `for` loop
+    CopyScope,
+    // Runs the code up to the matching `EndTry` with a handler jumping to
+    // the operand on errors, with the error pushed
+    Try(u32),
+    EndTry,
+    // Replaces the error a handler pushed with the value `catch` binds
+    Catch,
+    // Raises the error a handler pushed again
+    Raise,
+    // Suspends the running coroutine with the value on top of the stack,
+    // replaced by what resumes it
+    Yield,
+    InvalidAssignment,
+    ControlFlowError,
+    Return,
+    Done,
+    Break,
+    Continue,
+}
+
+/// Owned form of `Key`, stored in the chunk's key table
+pub enum ChunkKey {
+    Name(String),
+    Index(f64),
+    Invalid,
+}
+
+/// Compiled bytecode for a top-level statement or a function body
+#[derive(Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    pub spans: Vec<Span>,
+    pub constants: Vec<Value>,
+    pub names: Vec<String>,
+    pub keys: Vec<ChunkKey>,
+    pub functions: Vec<Rc<Function>>,
+    pub structs: Vec<(String, Vec<String>)>,
+    pub enums: Vec<(String, Vec<(String, bool)>)>,
+    // Where each statement of a program starts
+    pub statements: Vec<usize>,
+}
+
+/// A compiled function. The syntax tree is kept so the tree-walking
+/// backend can run closures created by the VM.
+pub struct Function {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Rc<Body>,
+    pub chunk: Rc<Chunk>,
+}
+
+pub struct Compiler {
+    chunk: Chunk,
+    // Every enclosing loop, innermost last
+    loops: Vec<Loop>,
+    // Environments entered by the code being compiled
+    scopes: usize,
+    // Values kept on the stack by the enclosing loops and `finally` bodies
+    held: usize,
+    // Every enclosing `try` statement whose `finally` body has to run on
+    // the way out, innermost last
+    tries: Vec<TryBlock>,
+    in_function: bool,
+}
+
+/// A loop being compiled
+struct Loop {
+    label: Option<String>,
+    // Values kept on the stack while its body runs
+    stack: usize,
+    // Environments entered when its body runs
+    scopes: usize,
+    // Pending jumps of the `break` and `continue` statements leaving it
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+impl Loop {
+    fn new(label: &Option<String>, stack: usize, scopes: usize) -> Loop {
+        Loop {
+            label: label.clone(),
+            stack,
+            scopes,
+            breaks: vec![],
+            continues: vec![],
+        }
+    }
+}
+
+/// A `try` statement being compiled
+#[derive(Clone)]
+struct TryBlock {
+    finally: Rc<Vec<Stmt>>,
+    // Environments entered and loops open when the statement started
+    scopes: usize,
+    loops: usize,
+    // Whether a handler has to be removed when leaving
+    handler: bool,
+}
+
+impl ChunkKey {
+    pub fn as_key(&self) -> Key<'_> {
+        match self {
+            ChunkKey::Name(s) => Key::Name(s),
+            ChunkKey::Index(n) => Key::Index(*n),
+            ChunkKey::Invalid => Key::Invalid,
+        }
+    }
+}
+
+impl Compiler {
+    fn new(in_function: bool) -> Compiler {
+        Compiler {
+            chunk: Chunk::default(),
+            loops: vec![],
+            scopes: 0,
+            held: 0,
+            tries: vec![],
+            in_function,
+        }
+    }
+
+    /// Compiles a top-level statement. Running the chunk yields the same
+    /// `StatementResult` the tree-walker would.
+    pub fn compile_statement(stmt: &Stmt) -> Chunk {
+        let mut compiler = Compiler::new(false);
+        compiler.statement(stmt, true);
+        compiler.emit(Op::Done, stmt.span);
+        compiler.chunk
+    }
+
+    /// Compiles a whole program once. Each statement runs from its entry
+    /// in `statements` to a `Done` of its own, like `compile_statement`.
+    pub fn compile_program(stmts: &[Stmt]) -> Chunk {
+        let mut compiler = Compiler::new(false);
+        for stmt in stmts {
+            compiler.chunk.statements.push(compiler.chunk.code.len());
+            compiler.statement(stmt, true);
+            compiler.emit(Op::Done, stmt.span);
+        }
+        compiler.chunk
+    }
+
+    pub fn compile_expression(expr: &Expr) -> Chunk {
+        let mut compiler = Compiler::new(false);
+        compiler.expr(expr);
+        compiler.emit(Op::Done, expr.span);
+        compiler.chunk
+    }
+
+    pub fn compile_function(name: String, params: Vec<String>, body: Rc<Body>) -> Function {
+        let mut compiler = Compiler::new(true);
+        compiler.block(&body);
+        let end = body.last().map(|s| s.span).unwrap_or_default();
+        compiler.emit(Op::Nil, end);
+        compiler.emit(Op::Return, end);
+        Function {
+            name,
+            params,
+            body,
+            chunk: Rc::new(compiler.chunk),
+        }
+    }
+
+    fn emit(&mut self, op: Op, span: Span) -> usize {
+        self.chunk.code.push(op);
+        self.chunk.spans.push(span);
+        self.chunk.code.len() - 1
+    }
+
+    fn here(&self) -> u32 {
+        self.chunk.code.len() as u32
+    }
+
+    fn patch(&mut self, at: usize) {
+        let target = self.here();
+        self.chunk.code[at] = match self.chunk.code[at] {
+            Op::Jump(_) => Op::Jump(target),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(target),
+            Op::ForNext(_) => Op::ForNext(target),
+            Op::Try(_) => Op::Try(target),
+            op => unreachable!("Cannot patch {:?}", op),
+        };
+    }
+
+    fn constant(&mut self, v: Value) -> u32 {
+        self.chunk.constants.push(v);
+        self.chunk.constants.len() as u32 - 1
+    }
+
+    fn name(&mut self, name: &str) -> u32 {
+        match self.chunk.names.iter().position(|n| n == name) {
+            Some(i) => i as u32,
+            None => {
+                self.chunk.names.push(String::from(name));
+                self.chunk.names.len() as u32 - 1
+            }
+        }
+    }
+
+    fn get_variable(&mut self, var: &Variable, span: Span) {
+        let op = match var.slot.get() {
+            Slot::Global => Op::GetVar(self.name(&var.name)),
+            Slot::Local { depth, slot } => Op::GetLocal(depth as u32, slot as u32),
+        };
+        self.emit(op, span);
+    }
+
+    fn set_variable(&mut self, var: &Variable, span: Span) {
+        let op = match var.slot.get() {
+            Slot::Global => Op::SetVar(self.name(&var.name)),
+            Slot::Local { depth, slot } => Op::SetLocal(depth as u32, slot as u32),
+        };
+        self.emit(op, span);
+    }
+
+    fn define_variable(&mut self, var: &Variable, span: Span) {
+        let op = match var.slot.get() {
+            Slot::Global => Op::DefineVar(self.name(&var.name)),
+            Slot::Local { slot, .. } => Op::DefineLocal(slot as u32),
+        };
+        self.emit(op, span);
+    }
+
+    /// Makes the assignment to a variable just compiled from `e` pop its
+    /// value, telling if it did
+    fn store(&mut self, e: &Expr) -> bool {
+        let last = self.chunk.code.len() - 1;
+        let store = match (&e.kind, self.chunk.code[last]) {
+            (Assign(target, _), Op::SetVar(i)) if matches!(target.kind, Id(_)) => Op::StoreVar(i),
+            (Assign(target, _), Op::SetLocal(depth, slot)) if matches!(target.kind, Id(_)) => {
+                Op::StoreLocal(depth, slot)
+            }
+            _ => return false,
+        };
+        self.chunk.code[last] = store;
+        true
+    }
+
+    fn key(&mut self, e: &Expr) -> u32 {
+        let key = match e.as_key() {
+            Key::Name(s) => ChunkKey::Name(String::from(s)),
+            Key::Index(n) => ChunkKey::Index(n),
+            Key::Invalid => ChunkKey::Invalid,
+        };
+        self.chunk.keys.push(key);
+        self.chunk.keys.len() as u32 - 1
+    }
+
+    fn function(&mut self, name: String, params: &[String], body: &Rc<Body>) -> u32 {
+        let function = Compiler::compile_function(name, params.to_vec(), body.clone());
+        self.chunk.functions.push(Rc::new(function));
+        self.chunk.functions.len() as u32 - 1
+    }
+
+    fn block(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.statement(stmt, false);
+        }
+    }
+
+    fn push_scope(&mut self, span: Span) {
+        self.emit(Op::PushScope, span);
+        self.scopes += 1;
+    }
+
+    fn pop_scope(&mut self, span: Span) {
+        self.emit(Op::PopScope, span);
+        self.scopes -= 1;
+    }
+
+    /// Compiles a block in an environment of its own if it declares names
+    fn scope(&mut self, stmts: &[Stmt], span: Span) {
+        let scoped = Resolver::declares(stmts);
+        if scoped {
+            self.push_scope(span);
+        }
+        self.block(stmts);
+        if scoped {
+            self.pop_scope(span);
+        }
+    }
+
+    /// Runs the `finally` bodies of the `try` statements entered after the
+    /// first `until` ones, innermost first, on the way out of them.
+    /// Returns how many environments are left entered.
+    fn leave_tries(&mut self, until: usize, span: Span) -> usize {
+        let (tries, loops, scopes) = (self.tries.clone(), self.loops.len(), self.scopes);
+        for block in tries[until..].iter().rev() {
+            for _ in block.scopes..self.scopes {
+                self.emit(Op::PopScope, span);
+            }
+            if block.handler {
+                self.emit(Op::EndTry, span);
+            }
+            // The body runs as if it followed the statement
+            self.scopes = block.scopes;
+            self.tries.truncate(self.tries.len() - 1);
+            let inner = self.loops.split_off(block.loops);
+            self.scope(&block.finally, span);
+            self.loops.extend(inner);
+        }
+        let left = self.scopes;
+        self.tries = tries;
+        debug_assert!(self.loops.len() == loops);
+        self.scopes = scopes;
+        left
+    }
+
+    /// Jumps out of the loop a `break` or `continue` statement is about.
+    /// Outside of loops the statement is left to whoever runs the chunk.
+    fn leave_loop(&mut self, label: &Option<String>, is_break: bool, span: Span) {
+        let target = match label {
+            Some(_) => self.loops.iter().rposition(|l| l.label == *label),
+            None => self.loops.len().checked_sub(1),
+        };
+        let target = match target {
+            Some(target) => target,
+            None if self.in_function => {
+                self.emit(Op::ControlFlowError, span);
+                return;
+            }
+            None => {
+                self.leave_tries(0, span);
+                self.emit(if is_break { Op::Break } else { Op::Continue }, span);
+                return;
+            }
+        };
+        let tries = self.tries.iter().filter(|t| t.loops > target).count();
+        let scopes = self.leave_tries(self.tries.len() - tries, span);
+        // Drop what the inner loops and `finally` bodies being left keep on
+        // the stack, and leave the environments entered since the body of
+        // the target started
+        for _ in self.loops[target].stack..self.held {
+            self.emit(Op::Pop, span);
+        }
+        for _ in self.loops[target].scopes..scopes {
+            self.emit(Op::PopScope, span);
+        }
+        let jump = self.emit(Op::Jump(0), span);
+        let target = &mut self.loops[target];
+        if is_break {
+            target.breaks.push(jump);
+        } else {
+            target.continues.push(jump);
+        }
+    }
+
+    fn try_statement(
+        &mut self,
+        body: &[Stmt],
+        catch: &Option<(Variable, Vec<Stmt>)>,
+        finally: &Rc<Vec<Stmt>>,
+        span: Span,
+    ) {
+        let block = TryBlock {
+            finally: finally.clone(),
+            scopes: self.scopes,
+            loops: self.loops.len(),
+            handler: true,
+        };
+        let handler = self.emit(Op::Try(0), span);
+        self.tries.push(block.clone());
+        self.scope(body, span);
+        self.tries.pop();
+        self.emit(Op::EndTry, span);
+        self.scope(finally, span);
+        let end = self.emit(Op::Jump(0), span);
+
+        // Errors land here, with the error on the stack
+        self.patch(handler);
+        let (var, handler_body) = match catch {
+            Some(catch) => catch,
+            None => {
+                self.held += 1;
+                self.scope(finally, span);
+                self.held -= 1;
+                self.emit(Op::Raise, span);
+                self.patch(end);
+                return;
+            }
+        };
+        self.emit(Op::Catch, span);
+        self.push_scope(span);
+        self.define_variable(var, span);
+        self.emit(Op::Pop, span);
+        if finally.is_empty() {
+            self.block(handler_body);
+            self.pop_scope(span);
+            self.patch(end);
+            return;
+        }
+        // Errors raised by the catch clause still run `finally`
+        let rethrow = self.emit(Op::Try(0), span);
+        self.tries.push(block);
+        self.block(handler_body);
+        self.tries.pop();
+        self.emit(Op::EndTry, span);
+        self.pop_scope(span);
+        self.scope(finally, span);
+        let done = self.emit(Op::Jump(0), span);
+        self.patch(rethrow);
+        // The handler left the environment of the catch clause entered
+        self.emit(Op::PopScope, span);
+        self.held += 1;
+        self.scope(finally, span);
+        self.held -= 1;
+        self.emit(Op::Raise, span);
+        self.patch(end);
+        self.patch(done);
+    }
+
+    /// Compiles a statement. With `keep` the value of the statement is left on the stack.
+    fn statement(&mut self, s: &Stmt, keep: bool) {
+        let span = s.span;
+        let has_value = match &s.kind {
+            StmtKind::ExprStmt(e) => {
+                self.expr(e);
+                if !keep && self.store(e) {
+                    return;
+                }
+                true
+            }
+            StmtKind::If(branches, else_block) => {
+                let mut ends = vec![];
+                for (cond, body) in branches {
+                    self.expr(cond);
+                    let next = self.emit(Op::JumpIfFalse(0), span);
+                    self.scope(body, span);
+                    ends.push(self.emit(Op::Jump(0), span));
+                    self.patch(next);
+                }
+                self.scope(else_block, span);
+                for end in ends {
+                    self.patch(end);
+                }
+                false
+            }
+            StmtKind::While(label, cond, body, updater) => {
+                let start = self.here();
+                self.expr(cond);
+                let exit = self.emit(Op::JumpIfFalse(0), span);
+                let scoped = Resolver::declares(body);
+                if scoped {
+                    self.push_scope(span);
+                }
+                self.loops.push(Loop::new(label, self.held, self.scopes));
+                self.block(body);
+                let compiled = self.loops.pop().unwrap();
+                for jump in compiled.continues {
+                    self.patch(jump);
+                }
+                if scoped {
+                    self.pop_scope(span);
+                }
+                if let Some(updater) = updater {
+                    // Closures created by this iteration keep its loop variable
+                    self.emit(Op::CopyScope, span);
+                    self.expr(updater);
+                    self.emit(Op::Pop, span);
+                }
+                self.emit(Op::Jump(start), span);
+                if scoped && !compiled.breaks.is_empty() {
+                    let skip = self.emit(Op::Jump(0), span);
+                    for jump in compiled.breaks {
+                        self.patch(jump);
+                    }
+                    self.emit(Op::PopScope, span);
+                    self.patch(skip);
+                } else {
+                    for jump in compiled.breaks {
+                        self.patch(jump);
+                    }
+                }
+                self.patch(exit);
+                false
+            }
+            StmtKind::ForIn(label, vars, iterable, body) => {
+                self.expr(iterable);
+                self.emit(Op::Iterate(vars.len() == 2), span);
+                let start = self.here();
+                let exit = self.emit(Op::ForNext(0), span);
+                // Every iteration binds the loop variables in a new environment
+                self.push_scope(span);
+                for var in vars {
+                    self.define_variable(var, span);
+                    self.emit(Op::Pop, span);
+                }
+                // The loop state stays on the stack
+                self.held += 1;
+                self.loops.push(Loop::new(label, self.held, self.scopes));
+                self.block(body);
+                let compiled = self.loops.pop().unwrap();
+                self.held -= 1;
+                for jump in compiled.continues {
+                    self.patch(jump);
+                }
+                self.pop_scope(span);
+                self.emit(Op::Jump(start), span);
+                if !compiled.breaks.is_empty() {
+                    for jump in compiled.breaks {
+                        self.patch(jump);
+                    }
+                    self.emit(Op::PopScope, span);
+                    self.emit(Op::Pop, span);
+                }
+                self.patch(exit);
+                false
+            }
+            StmtKind::Block(stmts) => {
+                self.scope(stmts, span);
+                false
+            }
+            StmtKind::Var(id, init) => {
+                match init {
+                    Some(e) => self.expr(e),
+                    None => {
+                        self.emit(Op::Nil, span);
+                    }
+                }
+                self.define_variable(id, span);
+                true
+            }
+            StmtKind::FunDef(id, params, body) => {
+                let function = self.function(id.name.clone(), params, body);
+                self.emit(Op::Closure(function), span);
+                self.define_variable(id, span);
+                true
+            }
+            StmtKind::StructDef(id, members) => {
+                self.chunk.structs.push((id.name.clone(), members.clone()));
+                let strukt = self.chunk.structs.len() as u32 - 1;
+                self.emit(Op::Struct(strukt), span);
+                self.define_variable(id, span);
+                true
+            }
+            StmtKind::EnumDef(id, values) => {
+                let mut variants = vec![];
+                for (variant, value) in values {
+                    if let Some(e) = value {
+                        self.expr(e);
+                        self.emit(Op::EnumValue, span);
+                    }
+                    variants.push((variant.clone(), value.is_some()));
+                }
+                self.chunk.enums.push((id.name.clone(), variants));
+                let enumt = self.chunk.enums.len() as u32 - 1;
+                self.emit(Op::Enum(enumt), span);
+                self.define_variable(id, span);
+                true
+            }
+            StmtKind::Return(e) => {
+                self.expr(e);
+                self.leave_tries(0, span);
+                self.emit(Op::Return, span);
+                return;
+            }
+            StmtKind::Import(var, module) => {
+                let module = self.name(module);
+                self.emit(Op::Import(module), span);
+                self.define_variable(var, span);
+                self.emit(Op::Pop, span);
+                false
+            }
+            StmtKind::Break(label) => {
+                self.leave_loop(label, true, span);
+                return;
+            }
+            StmtKind::Continue(label) => {
+                self.leave_loop(label, false, span);
+                return;
+            }
+            StmtKind::Try(body, catch, finally) => {
+                self.try_statement(body, catch, finally, span);
+                false
+            }
+        };
+        match (has_value, keep) {
+            (true, false) => {
+                self.emit(Op::Pop, span);
+            }
+            (false, true) => {
+                self.emit(Op::Nil, span);
+            }
+            _ => {}
+        }
+    }
+
+    fn expr(&mut self, e: &Expr) {
+        let span = e.span;
+        match &e.kind {
+            Num(n) => {
+                let c = self.constant(Value::Num(*n));
+                self.emit(Op::Constant(c), span);
+            }
+            Int(i) => {
+                let c = self.constant(Value::Int(*i));
+                self.emit(Op::Constant(c), span);
+            }
+            Str(s) => {
+                let c = self.constant(Value::Str(s.clone()));
+                self.emit(Op::Constant(c), span);
+            }
+            Bool(true) => {
+                self.emit(Op::True, span);
+            }
+            Bool(false) => {
+                self.emit(Op::False, span);
+            }
+            ExprKind::Nil => {
+                self.emit(Op::Nil, span);
+            }
+            Unary(TokenType::Plus, operand) => self.expr(operand),
+            Unary(_, operand) => {
+                self.expr(operand);
+                self.emit(Op::Negate, span);
+            }
+            Binary(l, TokenType::And, r) => {
+                self.expr(l);
+                let short = self.emit(Op::JumpIfFalse(0), span);
+                self.expr(r);
+                let end = self.emit(Op::Jump(0), span);
+                self.patch(short);
+                self.emit(Op::False, span);
+                self.patch(end);
+            }
+            Binary(l, TokenType::Or, r) => {
+                self.expr(l);
+                let rhs = self.emit(Op::JumpIfFalse(0), span);
+                self.emit(Op::True, span);
+                let end = self.emit(Op::Jump(0), span);
+                self.patch(rhs);
+                self.expr(r);
+                self.patch(end);
+            }
+            Binary(l, op, r) => {
+                self.expr(l);
+                let constant = match r.kind {
+                    Int(i) => Some(Value::Int(i)),
+                    Num(n) => Some(Value::Num(n)),
+                    _ => None,
+                };
+                match constant {
+                    Some(v) => {
+                        let c = self.constant(v);
+                        self.emit(Op::BinaryConstant(*op, c), span);
+                    }
+                    None => {
+                        self.expr(r);
+                        self.emit(Op::Binary(*op), span);
+                    }
+                }
+            }
+            Grouping(inner) => self.expr(inner),
+            Yield(value) => {
+                self.expr(value);
+                self.emit(Op::Yield, span);
+            }
+            Id(var) => self.get_variable(var, span),
+            Call(callee, args) => {
+                self.expr(callee);
+                self.emit(Op::CheckCall(args.len() as u32), span);
+                for arg in args {
+                    self.expr(arg);
+                }
+                self.emit(Op::Call(args.len() as u32), span);
+            }
+            Get(base, key) => {
+                self.expr(base);
+                let key = self.key(key);
+                self.emit(Op::Get(key), span);
+            }
+            Index(base, key) => {
+                self.expr(base);
+                self.expr(key);
+                self.emit(Op::Index, span);
+            }
+            Slice(base, lo, hi) => {
+                self.expr(base);
+                for bound in &[lo, hi] {
+                    match bound {
+                        Some(e) => self.expr(e),
+                        None => {
+                            self.emit(Op::Nil, span);
+                        }
+                    }
+                }
+                self.emit(Op::Slice, span);
+            }
+            Assign(target, value) => {
+                self.expr(value);
+                match &target.kind {
+                    Id(var) => self.set_variable(var, span),
+                    Get(base, key) => {
+                        self.expr(base);
+                        let key = self.key(key);
+                        self.emit(Op::Set(key), span);
+                    }
+                    Index(base, key) => {
+                        self.expr(base);
+                        self.expr(key);
+                        self.emit(Op::SetIndex, span);
+                    }
+                    _ => {
+                        self.emit(Op::InvalidAssignment, span);
+                    }
+                }
+            }
+            LambdaDef(params, body) => {
+                let function = self.function(String::from("<lambda>"), params, body);
+                self.emit(Op::Closure(function), span);
+            }
+            DictDef(elts) => {
+                for (key, value) in elts {
+                    self.expr(key);
+                    self.expr(value);
+                }
+                self.emit(Op::Dict(elts.len() as u32), span);
+            }
+            ListDef(elts) => {
+                for elt in elts {
+                    self.expr(elt);
+                }
+                self.emit(Op::List(elts.len() as u32), span);
+            }
+            Interpolation(parts) => {
+                for part in parts {
+                    self.expr(part);
+                }
+                self.emit(Op::Interpolate(parts.len() as u32), span);
+            }
+        }
+    }
+}
@@ -0,0 +1,337 @@
+use super::dict::{Dict, HashKey};
+use super::environments::*;
+use super::errors::*;
+use super::evaluator::Evaluator;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+#[cfg(feature = "derive")]
+pub use beans_derive::BeansStruct;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_round_trip() {
+        let v = vec![(1i64, String::from("a")), (2, String::from("b"))].into_value();
+        let back: Vec<(i64, String)> = FromValue::from_value(v).unwrap();
+        assert!(back == vec![(1, String::from("a")), (2, String::from("b"))]);
+
+        let mut map = HashMap::new();
+        map.insert(String::from("x"), Some(1.5));
+        map.insert(String::from("y"), None);
+        let back: HashMap<String, Option<f64>> = FromValue::from_value(map.into_value()).unwrap();
+        assert!(back.get("x") == Some(&Some(1.5)) && back.get("y") == Some(&None));
+    }
+
+    #[test]
+    fn test_conversion_errors() {
+        assert!(i64::from_value(Value::Num(1.5)).is_err());
+        assert!(matches!(5i64.into_value(), Value::Int(5)));
+        assert!(f64::from_value(Value::Int(2)).unwrap() == 2.0);
+        assert!(bool::from_value(Value::Nil).is_err());
+        let err = <(f64, f64)>::from_value(vec![1.0].into_value()).unwrap_err();
+        assert!(err.get_kind() == ErrorKind::TypeError);
+    }
+}
+
+/// Conversion from a script value into a Rust type
+pub trait FromValue: Sized {
+    fn from_value(v: Value) -> Result<Self, BeansError>;
+}
+
+/// Conversion from a Rust type into a script value
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+/// Rust structs exposed to scripts as struct instances.
+/// Usually derived with `#[derive(BeansStruct)]`.
+pub trait BeansStruct: FromValue + IntoValue {
+    /// The script-side struct definition, shared by every instance
+    fn base_struct() -> Rc<BaseStruct>;
+    fn get_field(&self, field: &str) -> Option<Value>;
+    fn set_field(&mut self, field: &str, value: Value) -> Result<(), BeansError>;
+
+    fn factory() -> StructFactory {
+        StructFactory::new(Self::base_struct())
+    }
+}
+
+/// Unwraps a struct instance created from `T`'s definition
+pub fn struct_instance<T: BeansStruct>(v: Value) -> Result<StructInstance, BeansError> {
+    let base = T::base_struct();
+    match v {
+        Value::StructInstance(inst) if Rc::ptr_eq(inst.get_base(), &base) => Ok(inst),
+        _ => Err(type_error(base.get_name(), &v)),
+    }
+}
+
+/// Return types accepted by functions passed to `Env::register_fn`
+pub trait IntoNativeResult {
+    fn into_native_result(self) -> NativeResult;
+}
+
+fn type_error(expected: &str, got: &Value) -> BeansError {
+    BeansError::new(
+        ErrorKind::TypeError,
+        format!("Expected {}, got {}", expected, got.type_name()),
+    )
+}
+
+impl FromValue for Value {
+    fn from_value(v: Value) -> Result<Self, BeansError> {
+        Ok(v)
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(v: Value) -> Result<Self, BeansError> {
+        match v {
+            Value::Num(n) => Ok(n),
+            Value::Int(i) => Ok(i as f64),
+            _ => Err(type_error("number", &v)),
+        }
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self) -> Value {
+        Value::Num(self)
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value(v: Value) -> Result<Self, BeansError> {
+        match v {
+            Value::Int(i) => Ok(i),
+            Value::Num(n) if n.fract() == 0.0 && n.abs() <= (1u64 << 53) as f64 => Ok(n as i64),
+            _ => Err(type_error("integer", &v)),
+        }
+    }
+}
+
+impl IntoValue for i64 {
+    fn into_value(self) -> Value {
+        Value::Int(self)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(v: Value) -> Result<Self, BeansError> {
+        match v {
+            Value::Bool(b) => Ok(b),
+            _ => Err(type_error("bool", &v)),
+        }
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl FromValue for String {
+    fn from_value(v: Value) -> Result<Self, BeansError> {
+        match v {
+            Value::Str(s) => Ok(s),
+            _ => Err(type_error("string", &v)),
+        }
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::Str(self)
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Value {
+        Value::Str(String::from(self))
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Value {
+        Value::Nil
+    }
+}
+
+impl FromValue for UserData {
+    fn from_value(v: Value) -> Result<Self, BeansError> {
+        match v {
+            Value::UserData(data) => Ok(data),
+            _ => Err(type_error("userdata", &v)),
+        }
+    }
+}
+
+impl IntoValue for UserData {
+    fn into_value(self) -> Value {
+        Value::UserData(self)
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(v: Value) -> Result<Self, BeansError> {
+        match v {
+            Value::Nil => Ok(None),
+            v => Ok(Some(T::from_value(v)?)),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Value {
+        match self {
+            Some(v) => v.into_value(),
+            None => Value::Nil,
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(v: Value) -> Result<Self, BeansError> {
+        match v {
+            Value::List(items) => items.borrow().iter().cloned().map(T::from_value).collect(),
+            _ => Err(type_error("list", &v)),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Value {
+        let items = self.into_iter().map(IntoValue::into_value).collect();
+        Value::List(Rc::new(RefCell::new(items)))
+    }
+}
+
+impl<T: FromValue> FromValue for HashMap<String, T> {
+    fn from_value(v: Value) -> Result<Self, BeansError> {
+        match v {
+            Value::Collection(map) => map
+                .borrow()
+                .iter()
+                .map(|(k, v)| match k {
+                    HashKey::Str(k) => Ok((k.clone(), T::from_value(v.clone())?)),
+                    _ => Err(type_error("dict with string keys", &k.to_value())),
+                })
+                .collect(),
+            _ => Err(type_error("dict", &v)),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for HashMap<String, T> {
+    fn into_value(self) -> Value {
+        let map: Dict = self
+            .into_iter()
+            .map(|(k, v)| (HashKey::Str(k), v.into_value()))
+            .collect();
+        Value::Collection(Rc::new(RefCell::new(map)))
+    }
+}
+
+impl<T: IntoValue> IntoNativeResult for T {
+    fn into_native_result(self) -> NativeResult {
+        Ok(self.into_value())
+    }
+}
+
+impl<T: IntoValue> IntoNativeResult for Result<T, BeansError> {
+    fn into_native_result(self) -> NativeResult {
+        self.map(IntoValue::into_value)
+    }
+}
+
+macro_rules! count {
+    () => { 0 };
+    ( $t: ident $( $ts: ident )* ) => { 1 + count!($( $ts )*) };
+}
+
+// Tuples are lists with a fixed length
+macro_rules! tuple_conversions {
+    ( $( $t: ident ),+ ) => {
+        impl<$( $t: FromValue ),+> FromValue for ($( $t, )+) {
+            fn from_value(v: Value) -> Result<Self, BeansError> {
+                let len = count!($( $t )+);
+                match v {
+                    Value::List(ref items) if items.borrow().len() == len => {
+                        let mut items = items.borrow().clone().into_iter();
+                        Ok(($( $t::from_value(items.next().unwrap())?, )+))
+                    }
+                    _ => Err(type_error(&format!("list of length {}", len), &v)),
+                }
+            }
+        }
+
+        impl<$( $t: IntoValue ),+> IntoValue for ($( $t, )+) {
+            #[allow(non_snake_case)]
+            fn into_value(self) -> Value {
+                let ($( $t, )+) = self;
+                Value::List(Rc::new(RefCell::new(vec![$( $t.into_value() ),+])))
+            }
+        }
+    };
+}
+
+tuple_conversions!(A);
+tuple_conversions!(A, B);
+tuple_conversions!(A, B, C);
+tuple_conversions!(A, B, C, D);
+
+/// Rust functions that can be exposed to scripts through `Env::register_fn`.
+/// `Args` is the tuple of argument types, used to tell the impls apart.
+pub trait NativeFunction<Args> {
+    fn arity() -> i8;
+    fn into_native(self) -> NativeBody;
+}
+
+macro_rules! native_function {
+    ( $( $t: ident ),* ) => {
+        impl<Fun, Ret, $( $t ),*> NativeFunction<($( $t, )*)> for Fun
+        where
+            Fun: Fn($( $t ),*) -> Ret + 'static,
+            Ret: IntoNativeResult,
+            $( $t: FromValue, )*
+        {
+            fn arity() -> i8 {
+                count!($( $t )*)
+            }
+
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn into_native(self) -> NativeBody {
+                Box::new(move |_: &mut Evaluator, args: Vec<Value>| {
+                    let mut args = args.into_iter().enumerate();
+                    $(
+                        let (i, arg) = args.next().unwrap();
+                        let $t = $t::from_value(arg).map_err(|why| {
+                            BeansError::new(
+                                why.get_kind(),
+                                format!("Argument {}: {}", i + 1, why.get_message()),
+                            )
+                        })?;
+                    )*
+                    (self)($( $t ),*).into_native_result()
+                })
+            }
+        }
+    };
+}
+
+native_function!();
+native_function!(A);
+native_function!(A, B);
+native_function!(A, B, C);
+native_function!(A, B, C, D);
+native_function!(A, B, C, D, E);
+native_function!(A, B, C, D, E, F);
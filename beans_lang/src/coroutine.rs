@@ -0,0 +1,228 @@
+use super::dict::{Dict, HashKey};
+use super::environments::*;
+use super::errors::*;
+use super::evaluator::Evaluator;
+use super::vm::Vm;
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// A function running in steps, pausing each time it yields.
+///
+/// Coroutines run on the bytecode VM whatever the backend, each with a VM
+/// of its own holding its stack and frames while it is paused. They can't
+/// yield from inside a native function, such as a callback given to one.
+pub(crate) struct Coroutine {
+    status: Rc<Cell<Status>>,
+    // Its VM while it is paused, with nothing to run once it is dead
+    vm: Option<Vm>,
+    // The function to start on the first resume, unless it already has a frame
+    body: Option<Rc<Box<dyn Call>>>,
+    started: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Status {
+    Suspended,
+    Running,
+    // Resumed another coroutine, waiting for it to yield
+    Normal,
+    Dead,
+}
+
+/// What a coroutine did when it was resumed
+pub(crate) enum Resumed {
+    Yielded(Value),
+    Returned(Value),
+}
+
+/// `coroutine.yield`, which the VM runs itself
+struct YieldFn;
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Suspended => "suspended",
+            Status::Running => "running",
+            Status::Normal => "normal",
+            Status::Dead => "dead",
+        }
+    }
+}
+
+fn coroutine_arg(args: &[Value], index: usize) -> Result<&UserData, BeansError> {
+    match args.get(index) {
+        Some(Value::UserData(data)) if data.is::<Coroutine>() => Ok(data),
+        Some(v) => Err(BeansError::new(
+            ErrorKind::TypeError,
+            format!("Expected coroutine argument, got {}", v.type_name()),
+        )),
+        None => Err(BeansError::new(
+            ErrorKind::ArityMismatch,
+            format!("Missing argument {}", index + 1),
+        )),
+    }
+}
+
+impl Coroutine {
+    fn value(coroutine: Coroutine) -> Value {
+        let methods = Rc::new(MethodTable::new("coroutine"));
+        Value::UserData(UserData::new(coroutine).with_methods(methods))
+    }
+
+    /// A coroutine starting `body` with the arguments of its first resume
+    fn create(body: Value) -> Result<Value, BeansError> {
+        match body {
+            Value::Callable(body) if body.as_closure().is_some() => {
+                Ok(Coroutine::value(Coroutine {
+                    status: Rc::new(Cell::new(Status::Suspended)),
+                    vm: None,
+                    body: Some(body),
+                    started: false,
+                }))
+            }
+            v => Err(BeansError::new(
+                ErrorKind::TypeError,
+                format!("Coroutines run script functions, got {}", v.type_name()),
+            )),
+        }
+    }
+
+    /// What calling a generator returns: a coroutine running its body with `args`
+    pub(crate) fn generator(closure: &Closure, args: Vec<Value>) -> Value {
+        let status = Rc::new(Cell::new(Status::Suspended));
+        Coroutine::value(Coroutine {
+            vm: Some(Vm::calling(closure, args, status.clone())),
+            status,
+            body: None,
+            started: false,
+        })
+    }
+
+    pub(crate) fn is_dead(data: &UserData) -> bool {
+        data.borrow_mut::<Coroutine>().unwrap().status.get() == Status::Dead
+    }
+
+    /// Runs the coroutine until it yields or returns, `arg` being what the
+    /// `yield` it paused at evaluates to. An error kills the coroutine.
+    pub(crate) fn resume(
+        eval: &mut Evaluator,
+        data: &UserData,
+        arg: Value,
+    ) -> Result<Resumed, BeansError> {
+        let (vm, arg) = {
+            let mut coroutine = data.borrow_mut::<Coroutine>().unwrap();
+            match coroutine.status.get() {
+                Status::Suspended => {}
+                Status::Dead => {
+                    return Err(BeansError::new(
+                        ErrorKind::InvalidControlFlow,
+                        String::from("Cannot resume a dead coroutine"),
+                    ))
+                }
+                _ => {
+                    return Err(BeansError::new(
+                        ErrorKind::InvalidControlFlow,
+                        String::from("Cannot resume a running coroutine"),
+                    ))
+                }
+            }
+            coroutine.status.set(Status::Running);
+            let started = std::mem::replace(&mut coroutine.started, true);
+            match coroutine.body.take() {
+                Some(body) => {
+                    let closure = body.as_closure().unwrap();
+                    let args = match arg {
+                        Value::Nil => vec![],
+                        arg => vec![arg],
+                    };
+                    (Vm::calling(closure, args, coroutine.status.clone()), None)
+                }
+                // Generators get their arguments when called, not on the first resume
+                None if !started => (coroutine.vm.take().unwrap(), None),
+                None => (coroutine.vm.take().unwrap(), Some(arg)),
+            }
+        };
+        let resumer = eval.running_coroutine();
+        if let Some(status) = &resumer {
+            status.set(Status::Normal);
+        }
+        let (vm, result) = eval.run_coroutine(vm, arg);
+        if let Some(status) = &resumer {
+            status.set(Status::Running);
+        }
+        let mut coroutine = data.borrow_mut::<Coroutine>().unwrap();
+        match result {
+            Ok(Resumed::Yielded(_)) => {
+                coroutine.status.set(Status::Suspended);
+                coroutine.vm = Some(vm);
+            }
+            _ => coroutine.status.set(Status::Dead),
+        }
+        result
+    }
+}
+
+impl Call for YieldFn {
+    // The VM suspends coroutines before calling, so this only runs where
+    // they can't be suspended
+    fn call(&self, eval: &mut Evaluator, _args: Vec<Value>) -> Result<Value, BeansError> {
+        Err(eval.yield_error())
+    }
+    fn arity(&self) -> i8 {
+        -1
+    }
+    fn to_string(&self) -> String {
+        String::from("coroutine.yield")
+    }
+    fn name(&self) -> String {
+        String::from("yield")
+    }
+    fn is_yield(&self) -> bool {
+        true
+    }
+}
+
+/// The `coroutine` module
+pub(crate) fn coroutine_library() -> Dict {
+    let mut coroutine = Dict::new();
+    coroutine.insert(
+        HashKey::from("create"),
+        Env::make_callable(|_, mut vals| Coroutine::create(vals.remove(0)), 1),
+    );
+    // The value the coroutine yields, or returns once it is done
+    coroutine.insert(
+        HashKey::from("resume"),
+        Env::make_callable(
+            |eval, vals| {
+                if vals.len() > 2 {
+                    return Err(BeansError::new(
+                        ErrorKind::ArityMismatch,
+                        format!("Expected 1 to 2 arguments, got {}", vals.len()),
+                    ));
+                }
+                let data = coroutine_arg(&vals, 0)?.clone();
+                let arg = vals.get(1).cloned().unwrap_or(Value::Nil);
+                match Coroutine::resume(eval, &data, arg)? {
+                    Resumed::Yielded(v) | Resumed::Returned(v) => Ok(v),
+                }
+            },
+            -1,
+        ),
+    );
+    coroutine.insert(
+        HashKey::from("status"),
+        Env::make_callable(
+            |_, vals| {
+                let data = coroutine_arg(&vals, 0)?;
+                let status = data.borrow_mut::<Coroutine>().unwrap().status.get();
+                Ok(Value::Str(String::from(status.name())))
+            },
+            1,
+        ),
+    );
+    coroutine.insert(
+        HashKey::from("yield"),
+        Value::Callable(Rc::new(Box::new(YieldFn))),
+    );
+    coroutine
+}
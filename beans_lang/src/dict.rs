@@ -0,0 +1,173 @@
+use super::environments::Value;
+use super::errors::*;
+use std::collections::HashMap;
+use std::fmt;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_insertion_order() {
+        let mut dict = Dict::new();
+        for k in &["z", "a", "m"] {
+            dict.insert(HashKey::Str(String::from(*k)), Value::Nil);
+        }
+        dict.insert(HashKey::Str(String::from("a")), Value::Num(1.0));
+        let keys: Vec<String> = dict.keys().map(|k| k.to_string()).collect();
+        assert!(keys == ["z", "a", "m"]);
+        assert!(
+            dict.get(&HashKey::Str(String::from("a")))
+                .unwrap()
+                .as_numeric()
+                == 1.0
+        );
+
+        assert!(dict.remove(&HashKey::Str(String::from("z"))).is_some());
+        let keys: Vec<String> = dict.keys().map(|k| k.to_string()).collect();
+        assert!(keys == ["a", "m"] && dict.len() == 2);
+        assert!(dict.get(&HashKey::Str(String::from("m"))).is_some());
+    }
+
+    #[test]
+    fn test_hash_keys() {
+        let zero = HashKey::from_value(&Value::Int(0)).unwrap();
+        assert!(zero == HashKey::from_value(&Value::Num(0.0)).unwrap());
+        assert!(zero == HashKey::from_value(&Value::Num(-0.0)).unwrap());
+        assert!(HashKey::from_value(&Value::Num(0.5)).unwrap() == HashKey::Num(0.5f64.to_bits()));
+        assert!(zero != HashKey::from_value(&Value::Str(String::from("0"))).unwrap());
+        assert!(zero != HashKey::from_value(&Value::Bool(false)).unwrap());
+        assert!(HashKey::from_value(&Value::Num(std::f64::NAN)).is_err());
+        assert!(HashKey::from_value(&Value::Nil).is_err());
+        assert!(zero.to_value().equals(&Value::Int(0)));
+    }
+}
+
+/// The values that can be dictionary keys
+///
+/// Enum members are numbers, so they key a dictionary by their value:
+/// `Color.Green` is the same key as the number it is associated to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum HashKey {
+    // Floats with an integer value are stored as integers, so 1 and 1.0 are the same key
+    Int(i64),
+    // Bits of the number
+    Num(u64),
+    Str(String),
+    Bool(bool),
+}
+
+/// A dictionary that remembers the order keys were first inserted in
+#[derive(Clone, Default)]
+pub struct Dict {
+    entries: Vec<(HashKey, Value)>,
+    positions: HashMap<HashKey, usize>,
+}
+
+impl HashKey {
+    pub fn from_value(v: &Value) -> Result<HashKey, BeansError> {
+        match v {
+            Value::Num(n) if n.is_nan() => Err(BeansError::new(
+                ErrorKind::TypeError,
+                String::from("NaN can't be a dictionary key"),
+            )),
+            Value::Num(n) if n.fract() == 0.0 && n.abs() < 9.2e18 => Ok(HashKey::Int(*n as i64)),
+            Value::Num(n) => Ok(HashKey::Num(n.to_bits())),
+            Value::Int(i) => Ok(HashKey::Int(*i)),
+            Value::Str(s) => Ok(HashKey::Str(s.clone())),
+            Value::Bool(b) => Ok(HashKey::Bool(*b)),
+            _ => Err(BeansError::new(
+                ErrorKind::TypeError,
+                format!("Values of type {} can't be dictionary keys", v.type_name()),
+            )),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            HashKey::Int(i) => Value::Int(*i),
+            HashKey::Num(bits) => Value::Num(f64::from_bits(*bits)),
+            HashKey::Str(s) => Value::Str(s.clone()),
+            HashKey::Bool(b) => Value::Bool(*b),
+        }
+    }
+}
+
+impl From<&str> for HashKey {
+    fn from(s: &str) -> HashKey {
+        HashKey::Str(String::from(s))
+    }
+}
+
+impl fmt::Display for HashKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HashKey::Int(i) => write!(f, "{}", i),
+            HashKey::Num(bits) => write!(f, "{}", f64::from_bits(*bits)),
+            HashKey::Str(s) => write!(f, "{}", s),
+            HashKey::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+impl Dict {
+    pub fn new() -> Dict {
+        Dict::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: &HashKey) -> Option<&Value> {
+        self.positions.get(key).map(|i| &self.entries[*i].1)
+    }
+
+    pub fn contains_key(&self, key: &HashKey) -> bool {
+        self.positions.contains_key(key)
+    }
+
+    /// Sets the value of `key`. A key that is already present keeps its place.
+    pub fn insert(&mut self, key: HashKey, value: Value) -> Option<Value> {
+        match self.positions.get(&key) {
+            Some(i) => Some(std::mem::replace(&mut self.entries[*i].1, value)),
+            None => {
+                self.positions.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+                None
+            }
+        }
+    }
+
+    /// Removes `key`, keeping the order of the other entries
+    pub fn remove(&mut self, key: &HashKey) -> Option<Value> {
+        let i = self.positions.remove(key)?;
+        let (_, value) = self.entries.remove(i);
+        for (k, _) in &self.entries[i..] {
+            *self.positions.get_mut(k).unwrap() -= 1;
+        }
+        Some(value)
+    }
+
+    /// Entries in insertion order
+    pub fn iter(&self) -> impl Iterator<Item = (&HashKey, &Value)> {
+        self.entries.iter().map(|(k, v)| (k, v))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &HashKey> {
+        self.entries.iter().map(|(k, _)| k)
+    }
+}
+
+impl std::iter::FromIterator<(HashKey, Value)> for Dict {
+    fn from_iter<I: IntoIterator<Item = (HashKey, Value)>>(iter: I) -> Dict {
+        let mut dict = Dict::new();
+        for (k, v) in iter {
+            dict.insert(k, v);
+        }
+        dict
+    }
+}
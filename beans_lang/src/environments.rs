@@ -1,9 +1,21 @@
+use super::compiler::{Compiler, Function};
+use super::convert::{BeansStruct, NativeFunction};
+use super::coroutine::{coroutine_library, Coroutine};
+use super::dict::{Dict, HashKey};
+use super::errors::*;
+use super::evaluator::Backend;
 use super::evaluator::Evaluator;
 use super::evaluator::StatementResult;
+use super::lexer::parse_number;
+use super::tokens::Value as TokenValue;
 
-use super::node::Stmt;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use super::node::{Body, Slot, Variable};
+use float_cmp::{ApproxEq, F64Margin};
+use std::any::Any;
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
 use std::rc::Rc;
 
 #[cfg(test)]
@@ -16,24 +28,94 @@ mod test {
         let enclosing = Env::new_enclosing(Rc::new(RefCell::new(env)));
         assert!(enclosing.get(&"x".to_string()).as_numeric() == 42.0);
     }
+
+    #[test]
+    fn test_equals() {
+        let s = |s: &str| Value::Str(String::from(s));
+        assert!(s("a").equals(&s("a")) && !s("a").equals(&s("b")));
+        assert!(Value::Nil.equals(&Value::Nil));
+        assert!(!Value::Nil.equals(&Value::Bool(false)));
+        assert!(!Value::Num(1.0).equals(&Value::Bool(true)));
+        assert!(!s("1").equals(&Value::Num(1.0)));
+
+        let list = |items| Value::List(Rc::new(RefCell::new(items)));
+        let l = list(vec![Value::Num(1.0), s("x"), list(vec![])]);
+        assert!(l.equals(&list(vec![Value::Num(1.0), s("x"), list(vec![])])));
+        assert!(!l.equals(&list(vec![Value::Num(1.0)])));
+
+        let dict = |v: f64| {
+            let mut map = Dict::new();
+            map.insert(HashKey::from("k"), Value::Num(v));
+            Value::Collection(Rc::new(RefCell::new(map)))
+        };
+        let d = dict(1.0);
+        assert!(d.equals(&d.clone()));
+        assert!(!d.equals(&dict(1.0)));
+        assert!(d.deep_equals(&dict(1.0)) && !d.deep_equals(&dict(2.0)));
+
+        let base = Rc::new(BaseStruct::new(vec![String::from("k")], String::from("P")));
+        let inst = |v: f64| {
+            let mut fields = HashMap::new();
+            fields.insert(String::from("k"), Value::Num(v));
+            Value::StructInstance(StructInstance::new(fields, base.clone()))
+        };
+        assert!(inst(1.0).equals(&inst(1.0)) && !inst(1.0).equals(&inst(2.0)));
+
+        let f = Env::make_callable(|_, _| Ok(Value::Nil), 0);
+        assert!(f.equals(&f.clone()));
+        assert!(!f.equals(&Env::make_callable(|_, _| Ok(Value::Nil), 0)));
+    }
+
+    #[test]
+    fn test_equals_cycles() {
+        // Lists and dicts holding themselves
+        let cyclic = |v: f64| {
+            let l = Rc::new(RefCell::new(vec![Value::Num(v)]));
+            l.borrow_mut().push(Value::List(l.clone()));
+            Value::List(l)
+        };
+        assert!(cyclic(1.0).equals(&cyclic(1.0)));
+        assert!(!cyclic(1.0).equals(&cyclic(2.0)));
+
+        let cyclic = |v: f64| {
+            let d = Rc::new(RefCell::new(Dict::new()));
+            d.borrow_mut().insert(HashKey::from("v"), Value::Num(v));
+            let this = Value::Collection(d.clone());
+            d.borrow_mut().insert(HashKey::from("self"), this.clone());
+            this
+        };
+        assert!(cyclic(1.0).deep_equals(&cyclic(1.0)));
+        assert!(!cyclic(1.0).deep_equals(&cyclic(2.0)));
+        assert!(!cyclic(1.0).equals(&cyclic(1.0)));
+    }
 }
 
 pub enum Value {
     Num(f64),
+    Int(i64),
     Str(String),
     Bool(bool),
     Callable(Rc<Box<dyn Call>>),
     Enum(String, HashMap<String, f64>),
     StructInstance(StructInstance),
-    Collection(Rc<RefCell<HashMap<String, Value>>>),
-    List(Vec<Value>),
+    Collection(Rc<RefCell<Dict>>),
+    List(Rc<RefCell<Vec<Value>>>),
+    UserData(UserData),
     Nil,
 }
 
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.stringify())
+    }
+}
+
 impl Clone for Value {
+    #[inline]
     fn clone(&self) -> Self {
         match self {
             Value::Num(n) => Value::Num(*n),
+            Value::Int(i) => Value::Int(*i),
             Value::Str(s) => Value::Str(s.clone()),
             Value::Bool(b) => Value::Bool(*b),
             Value::Callable(call) => Value::Callable(call.clone()),
@@ -41,19 +123,37 @@ impl Clone for Value {
             Value::StructInstance(inst) => Value::StructInstance(inst.clone()),
             Value::Collection(map) => Value::Collection(map.clone()),
             Value::List(lis) => Value::List(lis.clone()),
+            Value::UserData(data) => Value::UserData(data.clone()),
             Value::Nil => Value::Nil,
         }
     }
 }
 
 pub trait Call {
-    fn call(&self, eval: &mut Evaluator, args: Vec<Value>) -> Value;
+    fn call(&self, eval: &mut Evaluator, args: Vec<Value>) -> Result<Value, BeansError>;
     fn arity(&self) -> i8;
     fn to_string(&self) -> String;
+
+    /// Name shown in tracebacks
+    fn name(&self) -> String {
+        String::from("<native>")
+    }
+
+    /// Lets the VM run script functions in its own frames
+    fn as_closure(&self) -> Option<&Closure> {
+        None
+    }
+
+    /// Lets the VM suspend the running coroutine on calls to `coroutine.yield`
+    fn is_yield(&self) -> bool {
+        false
+    }
 }
 
 pub struct Env {
     symbols: HashMap<String, Value>,
+    // Locals of a function call, indexed by the slots the resolver assigned
+    slots: Vec<Value>,
     enclosing: Option<Rc<RefCell<Env>>>,
 }
 
@@ -72,25 +172,413 @@ pub struct StructInstance {
 }
 
 pub struct Closure {
+    name: String,
     env: Rc<RefCell<Env>>,
     params: Vec<String>,
-    fun: Rc<Vec<Stmt>>,
+    fun: Rc<Body>,
+    // Bytecode for the body, compiled on the first VM call
+    function: RefCell<Option<Rc<Function>>>,
 }
 
+/// An opaque Rust object handed to scripts, with optional methods
+#[derive(Clone)]
+pub struct UserData {
+    data: Rc<RefCell<dyn Any>>,
+    methods: Option<Rc<MethodTable>>,
+}
+
+/// Methods shared by every userdata of one host type.
+/// Each method gets the userdata itself as its first argument.
+pub struct MethodTable {
+    type_name: String,
+    methods: HashMap<String, Value>,
+}
+
+/// A userdata method together with the value it was looked up on
+pub struct BoundMethod {
+    receiver: Value,
+    method: Rc<Box<dyn Call>>,
+}
+
+pub type NativeResult = Result<Value, BeansError>;
+
+pub type NativeBody = Box<dyn Fn(&mut Evaluator, Vec<Value>) -> NativeResult>;
+
 pub struct NativeFn {
-    fun: fn(Vec<Value>) -> Value,
+    fun: NativeBody,
     arity: i8,
 }
 
 impl NativeFn {
-    pub fn new(fun: fn(Vec<Value>) -> Value, arity: i8) -> NativeFn {
+    pub fn new(fun: NativeBody, arity: i8) -> NativeFn {
         NativeFn { fun, arity }
     }
 }
 
+/// Fetches the numeric argument at `index`, failing with a script error
+/// when it is missing or not a number
+pub fn numeric_arg(args: &[Value], index: usize) -> Result<f64, BeansError> {
+    match args.get(index) {
+        Some(v) if v.is_numeric() => Ok(v.as_numeric()),
+        Some(v) => Err(BeansError::new(
+            ErrorKind::TypeError,
+            format!(
+                "Expected a number as argument {}, got {}",
+                index + 1,
+                v.type_name()
+            ),
+        )),
+        None => Err(BeansError::new(
+            ErrorKind::ArityMismatch,
+            format!("Missing argument {}", index + 1),
+        )),
+    }
+}
+
+/// Like `numeric_arg`, for integers and floats without a fractional part
+pub fn int_arg(args: &[Value], index: usize) -> Result<i64, BeansError> {
+    match args.get(index) {
+        Some(Value::Int(i)) => Ok(*i),
+        Some(Value::Num(n)) if n.fract() == 0.0 => Ok(*n as i64),
+        Some(v) => Err(BeansError::new(
+            ErrorKind::TypeError,
+            format!(
+                "Expected an integer as argument {}, got {}",
+                index + 1,
+                v.type_name()
+            ),
+        )),
+        None => Err(BeansError::new(
+            ErrorKind::ArityMismatch,
+            format!("Missing argument {}", index + 1),
+        )),
+    }
+}
+
+/// Like `numeric_arg`, for strings
+pub fn string_arg(args: &[Value], index: usize) -> Result<&str, BeansError> {
+    match args.get(index) {
+        Some(Value::Str(s)) => Ok(s),
+        Some(v) => Err(BeansError::new(
+            ErrorKind::TypeError,
+            format!(
+                "Expected a string as argument {}, got {}",
+                index + 1,
+                v.type_name()
+            ),
+        )),
+        None => Err(BeansError::new(
+            ErrorKind::ArityMismatch,
+            format!("Missing argument {}", index + 1),
+        )),
+    }
+}
+
+/// Checks the argument count of a variadic native function
+fn arg_count(args: &[Value], min: usize, max: usize) -> Result<(), BeansError> {
+    if args.len() < min || args.len() > max {
+        let expected = if min == max {
+            format!("{}", min)
+        } else {
+            format!("{} to {}", min, max)
+        };
+        return Err(BeansError::new(
+            ErrorKind::ArityMismatch,
+            format!("Expected {} arguments, got {}", expected, args.len()),
+        ));
+    }
+    Ok(())
+}
+
+/// The value a `catch` clause binds: an `Error` struct holding the kind,
+/// message and traceback of the error, and the value given to `error()`
+pub(crate) fn error_object(why: &BeansError) -> Value {
+    let names = ["kind", "message", "value", "traceback"];
+    let values = [
+        Value::Str(why.get_kind().to_string()),
+        Value::Str(why.get_message().clone()),
+        why.get_value().cloned().unwrap_or(Value::Nil),
+        Value::Str(why.format_traceback()),
+    ];
+    let fields = names
+        .iter()
+        .map(|name| String::from(*name))
+        .zip(values.iter().cloned());
+    let base = BaseStruct::new(
+        names.iter().map(|name| String::from(*name)).collect(),
+        String::from("Error"),
+    );
+    Value::StructInstance(StructInstance::new(fields.collect(), Rc::new(base)))
+}
+
+/// The error `error(value)` raises. Raising a caught error again keeps
+/// its kind and message.
+fn raised(value: Value) -> BeansError {
+    if let Value::StructInstance(inst) = &value {
+        let field = |name: &str| inst.get(&String::from(name)).cloned().unwrap_or(Value::Nil);
+        if let (Value::Str(kind), Value::Str(message)) = (field("kind"), field("message")) {
+            match ErrorKind::from_name(&kind) {
+                Some(kind) if inst.get_base().get_name() == "Error" => {
+                    return BeansError::with_value(kind, message, field("value"));
+                }
+                _ => {}
+            }
+        }
+    }
+    BeansError::with_value(ErrorKind::UserError, value.string_repr(), value)
+}
+
+/// Position of the char at `index` in `s`, negative indices counting from
+/// the end. Out of range indices are clamped.
+fn char_offset(s: &str, index: i64) -> usize {
+    let len = s.chars().count() as i64;
+    let index = if index < 0 { index + len } else { index };
+    let index = index.max(0).min(len) as usize;
+    s.char_indices()
+        .nth(index)
+        .map_or(s.len(), |(offset, _)| offset)
+}
+
+// Longest string, in bytes, that `string.repeat` builds instead of failing
+const MAX_REPEAT_LENGTH: usize = 1 << 30;
+
+/// The `string` module. Positions and lengths count chars, not bytes.
+fn string_library() -> Dict {
+    let mut string = Dict::new();
+    string.insert(
+        HashKey::from("len"),
+        Env::make_callable(
+            |_, vals| {
+                let s = string_arg(&vals, 0)?;
+                Ok(Value::Int(s.chars().count() as i64))
+            },
+            1,
+        ),
+    );
+    string.insert(
+        HashKey::from("sub"),
+        Env::make_callable(
+            |_, vals| {
+                arg_count(&vals, 2, 3)?;
+                string_arg(&vals, 0)?;
+                let hi = vals.get(2).cloned().unwrap_or(Value::Nil);
+                Evaluator::slice(vals[0].clone(), vals[1].clone(), hi)
+            },
+            -1,
+        ),
+    );
+    string.insert(
+        HashKey::from("split"),
+        Env::make_callable(
+            |_, vals| {
+                arg_count(&vals, 1, 2)?;
+                let s = string_arg(&vals, 0)?;
+                let parts: Vec<Value> = match vals.get(1) {
+                    None => s
+                        .split_whitespace()
+                        .map(|p| Value::Str(String::from(p)))
+                        .collect(),
+                    Some(_) => match string_arg(&vals, 1)? {
+                        "" => s.chars().map(|c| Value::Str(c.to_string())).collect(),
+                        sep => s.split(sep).map(|p| Value::Str(String::from(p))).collect(),
+                    },
+                };
+                Ok(Value::List(Rc::new(RefCell::new(parts))))
+            },
+            -1,
+        ),
+    );
+    string.insert(
+        HashKey::from("join"),
+        Env::make_callable(
+            |_, vals| {
+                let sep = string_arg(&vals, 1)?;
+                match &vals[0] {
+                    Value::List(parts) => {
+                        let parts: Vec<String> =
+                            parts.borrow().iter().map(|p| p.string_repr()).collect();
+                        Ok(Value::Str(parts.join(sep)))
+                    }
+                    v => Err(BeansError::new(
+                        ErrorKind::TypeError,
+                        format!("Expected a list as argument 1, got {}", v.type_name()),
+                    )),
+                }
+            },
+            2,
+        ),
+    );
+    string.insert(
+        HashKey::from("find"),
+        Env::make_callable(
+            |_, vals| {
+                arg_count(&vals, 2, 3)?;
+                let s = string_arg(&vals, 0)?;
+                let needle = string_arg(&vals, 1)?;
+                let start = match vals.get(2) {
+                    Some(_) => char_offset(s, int_arg(&vals, 2)?),
+                    None => 0,
+                };
+                Ok(match s[start..].find(needle) {
+                    Some(offset) => Value::Int(s[..start + offset].chars().count() as i64),
+                    None => Value::Nil,
+                })
+            },
+            -1,
+        ),
+    );
+    string.insert(
+        HashKey::from("replace"),
+        Env::make_callable(
+            |_, vals| {
+                let s = string_arg(&vals, 0)?;
+                let from = string_arg(&vals, 1)?;
+                let to = string_arg(&vals, 2)?;
+                if from.is_empty() {
+                    return Err(BeansError::new(
+                        ErrorKind::ValueError,
+                        String::from("Cannot replace an empty string"),
+                    ));
+                }
+                Ok(Value::Str(s.replace(from, to)))
+            },
+            3,
+        ),
+    );
+    string.insert(
+        HashKey::from("upper"),
+        Env::make_callable(
+            |_, vals| Ok(Value::Str(string_arg(&vals, 0)?.to_uppercase())),
+            1,
+        ),
+    );
+    string.insert(
+        HashKey::from("lower"),
+        Env::make_callable(
+            |_, vals| Ok(Value::Str(string_arg(&vals, 0)?.to_lowercase())),
+            1,
+        ),
+    );
+    string.insert(
+        HashKey::from("trim"),
+        Env::make_callable(
+            |_, vals| Ok(Value::Str(String::from(string_arg(&vals, 0)?.trim()))),
+            1,
+        ),
+    );
+    string.insert(
+        HashKey::from("starts_with"),
+        Env::make_callable(
+            |_, vals| {
+                let s = string_arg(&vals, 0)?;
+                Ok(Value::Bool(s.starts_with(string_arg(&vals, 1)?)))
+            },
+            2,
+        ),
+    );
+    string.insert(
+        HashKey::from("ends_with"),
+        Env::make_callable(
+            |_, vals| {
+                let s = string_arg(&vals, 0)?;
+                Ok(Value::Bool(s.ends_with(string_arg(&vals, 1)?)))
+            },
+            2,
+        ),
+    );
+    string.insert(
+        HashKey::from("repeat"),
+        Env::make_callable(
+            |_, vals| {
+                let s = string_arg(&vals, 0)?;
+                let n = int_arg(&vals, 1)?;
+                if n < 0 {
+                    return Err(BeansError::new(
+                        ErrorKind::ValueError,
+                        format!("Cannot repeat a string {} times", n),
+                    ));
+                }
+                let length = usize::try_from(n).ok().and_then(|n| n.checked_mul(s.len()));
+                match length {
+                    Some(length) if length <= MAX_REPEAT_LENGTH => {
+                        Ok(Value::Str(s.repeat(n as usize)))
+                    }
+                    _ => Err(BeansError::new(
+                        ErrorKind::ValueError,
+                        format!("Repeating a string {} times makes it too long", n),
+                    )),
+                }
+            },
+            2,
+        ),
+    );
+    string.insert(
+        HashKey::from("ord"),
+        Env::make_callable(
+            |_, vals| {
+                arg_count(&vals, 1, 2)?;
+                let s = string_arg(&vals, 0)?;
+                let index = match vals.get(1) {
+                    Some(_) => Value::Int(int_arg(&vals, 1)?),
+                    None => Value::Int(0),
+                };
+                let position = Evaluator::position(&index, s.chars().count())?;
+                Ok(Value::Int(s.chars().nth(position).unwrap() as i64))
+            },
+            -1,
+        ),
+    );
+    string.insert(
+        HashKey::from("chr"),
+        Env::make_callable(
+            |_, vals| {
+                let code = int_arg(&vals, 0)?;
+                match std::char::from_u32(code as u32).filter(|_| (0..=0x10FFFF).contains(&code)) {
+                    Some(c) => Ok(Value::Str(c.to_string())),
+                    None => Err(BeansError::new(
+                        ErrorKind::ValueError,
+                        format!("{} is not a unicode code point", code),
+                    )),
+                }
+            },
+            1,
+        ),
+    );
+    string.insert(
+        HashKey::from("tonumber"),
+        Env::make_callable(
+            |_, vals| {
+                let s = string_arg(&vals, 0)?.trim();
+                let (negative, digits) = match s.strip_prefix('-') {
+                    Some(digits) => (true, digits),
+                    None => (false, s.strip_prefix('+').unwrap_or(s)),
+                };
+                // Numbers are read like literals in scripts, nil if they don't parse
+                let starts_with_digit =
+                    digits.starts_with(|c: char| c.is_ascii_digit() || c == '.');
+                Ok(match parse_number(digits) {
+                    Ok((_, TokenValue::Int(i))) if starts_with_digit => {
+                        Value::Int(if negative { -i } else { i })
+                    }
+                    Ok((_, TokenValue::Num(n))) if starts_with_digit => {
+                        Value::Num(if negative { -n } else { n })
+                    }
+                    _ => Value::Nil,
+                })
+            },
+            1,
+        ),
+    );
+    string.insert(
+        HashKey::from("tostring"),
+        Env::make_callable(|_, vals| Ok(Value::Str(vals[0].string_repr())), 1),
+    );
+    string
+}
+
 impl Call for NativeFn {
-    fn call(&self, _eval: &mut Evaluator, args: Vec<Value>) -> Value {
-        (self.fun)(args)
+    fn call(&self, eval: &mut Evaluator, args: Vec<Value>) -> Result<Value, BeansError> {
+        (self.fun)(eval, args)
     }
     fn arity(&self) -> i8 {
         self.arity
@@ -100,10 +588,128 @@ impl Call for NativeFn {
     }
 }
 
+impl UserData {
+    pub fn new<T: Any>(data: T) -> UserData {
+        UserData {
+            data: Rc::new(RefCell::new(data)),
+            methods: None,
+        }
+    }
+
+    pub fn with_methods(mut self, methods: Rc<MethodTable>) -> UserData {
+        self.methods = Some(methods);
+        self
+    }
+
+    pub fn get_data(&self) -> &Rc<RefCell<dyn Any>> {
+        &self.data
+    }
+
+    pub fn get_methods(&self) -> Option<&Rc<MethodTable>> {
+        self.methods.as_ref()
+    }
+
+    pub fn get_type_name(&self) -> &str {
+        match &self.methods {
+            Some(methods) => &methods.type_name,
+            None => "userdata",
+        }
+    }
+
+    pub fn is<T: Any>(&self) -> bool {
+        self.data.borrow().is::<T>()
+    }
+
+    /// Borrows the wrapped object if it is a `T`
+    pub fn borrow<T: Any>(&self) -> Option<Ref<'_, T>> {
+        Ref::filter_map(self.data.borrow(), |d| d.downcast_ref::<T>()).ok()
+    }
+
+    pub fn borrow_mut<T: Any>(&self) -> Option<RefMut<'_, T>> {
+        RefMut::filter_map(self.data.borrow_mut(), |d| d.downcast_mut::<T>()).ok()
+    }
+}
+
+impl MethodTable {
+    pub fn new(type_name: &str) -> MethodTable {
+        MethodTable {
+            type_name: String::from(type_name),
+            methods: HashMap::new(),
+        }
+    }
+
+    /// Adds a callable taking the userdata as first argument
+    pub fn add(&mut self, name: &str, method: Value) -> &mut Self {
+        self.methods.insert(String::from(name), method);
+        self
+    }
+
+    /// Adds a method working on the wrapped `T`. `arity` does not count the receiver.
+    pub fn add_method<T, F>(&mut self, name: &str, arity: i8, fun: F) -> &mut Self
+    where
+        T: Any,
+        F: Fn(&mut T, Vec<Value>) -> NativeResult + 'static,
+    {
+        let type_name = self.type_name.clone();
+        let method = Env::make_callable(
+            move |_, mut args| {
+                let receiver = args.remove(0);
+                let data = match &receiver {
+                    Value::UserData(data) => data.borrow_mut::<T>(),
+                    _ => None,
+                };
+                match data {
+                    Some(mut data) => fun(&mut data, args),
+                    None => Err(BeansError::new(
+                        ErrorKind::TypeError,
+                        format!(
+                            "Expected {} receiver, got {}",
+                            type_name,
+                            receiver.type_name()
+                        ),
+                    )),
+                }
+            },
+            if arity < 0 { arity } else { arity + 1 },
+        );
+        self.add(name, method)
+    }
+
+    pub fn get_method(&self, name: &str) -> Option<&Value> {
+        self.methods.get(name)
+    }
+}
+
+impl BoundMethod {
+    pub fn new(receiver: Value, method: Rc<Box<dyn Call>>) -> BoundMethod {
+        BoundMethod { receiver, method }
+    }
+}
+
+impl Call for BoundMethod {
+    fn call(&self, eval: &mut Evaluator, mut args: Vec<Value>) -> Result<Value, BeansError> {
+        args.insert(0, self.receiver.clone());
+        self.method.call(eval, args)
+    }
+    fn arity(&self) -> i8 {
+        match self.method.arity() {
+            -1 => -1,
+            n => n - 1,
+        }
+    }
+    fn to_string(&self) -> String {
+        format!("bound method")
+    }
+    fn name(&self) -> String {
+        self.method.name()
+    }
+}
+
 impl Value {
     pub fn as_numeric(&self) -> f64 {
         match self {
             Value::Num(n) => *n,
+            Value::Int(i) => *i as f64,
             Value::Bool(b) => {
                 if *b {
                     1.0
@@ -115,9 +721,96 @@ impl Value {
         }
     }
 
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Num(_) => "float",
+            Value::Int(_) => "int",
+            Value::Str(_) => "string",
+            Value::Bool(_) => "bool",
+            Value::Callable(_) => "function",
+            Value::Enum(_, _) => "enum",
+            Value::StructInstance(_) => "struct",
+            Value::Collection(_) => "dict",
+            Value::List(_) => "list",
+            Value::UserData(_) => "userdata",
+            Value::Nil => "nil",
+        }
+    }
+
+    /// The `==` of scripts. Strings, lists, struct instances and enums
+    /// compare by content, dicts, functions and userdata by identity.
+    /// Values of different types are never equal.
+    pub fn equals(&self, other: &Value) -> bool {
+        self.compare(other, false, &mut HashSet::new())
+    }
+
+    /// Like `equals`, but dicts compare by content too
+    pub fn deep_equals(&self, other: &Value) -> bool {
+        self.compare(other, true, &mut HashSet::new())
+    }
+
+    /// `seen` holds the pairs of lists and dicts being compared further up.
+    /// Meeting one of them again adds nothing to tell the values apart, so
+    /// it counts as equal, and values containing themselves compare too.
+    fn compare(&self, other: &Value, deep: bool, seen: &mut HashSet<(usize, usize)>) -> bool {
+        match (self, other) {
+            (Value::Num(l), Value::Num(r)) => l.approx_eq(*r, F64Margin::default()),
+            (Value::Int(l), Value::Int(r)) => l == r,
+            // Ints and floats are both numbers
+            (Value::Int(_), Value::Num(_)) | (Value::Num(_), Value::Int(_)) => self
+                .as_numeric()
+                .approx_eq(other.as_numeric(), F64Margin::default()),
+            (Value::Str(l), Value::Str(r)) => l == r,
+            (Value::Bool(l), Value::Bool(r)) => l == r,
+            (Value::Nil, Value::Nil) => true,
+            (Value::List(l), Value::List(r)) => {
+                if Rc::ptr_eq(l, r)
+                    || !seen.insert((Rc::as_ptr(l) as usize, Rc::as_ptr(r) as usize))
+                {
+                    return true;
+                }
+                let (l, r) = (l.borrow(), r.borrow());
+                l.len() == r.len()
+                    && l.iter()
+                        .zip(r.iter())
+                        .all(|(l, r)| l.compare(r, deep, seen))
+            }
+            (Value::StructInstance(l), Value::StructInstance(r)) => {
+                Rc::ptr_eq(&l.parent, &r.parent)
+                    && l.fields.len() == r.fields.len()
+                    && l.fields.iter().all(|(k, v)| match r.fields.get(k) {
+                        Some(rv) => v.compare(rv, deep, seen),
+                        None => false,
+                    })
+            }
+            (Value::Enum(l_name, l), Value::Enum(r_name, r)) => l_name == r_name && l == r,
+            (Value::Collection(l), Value::Collection(r)) => {
+                if Rc::ptr_eq(l, r) {
+                    return true;
+                }
+                if !deep {
+                    return false;
+                }
+                if !seen.insert((Rc::as_ptr(l) as usize, Rc::as_ptr(r) as usize)) {
+                    return true;
+                }
+                let (l, r) = (l.borrow(), r.borrow());
+                l.len() == r.len()
+                    && l.iter().all(|(k, v)| match r.get(k) {
+                        Some(rv) => v.compare(rv, deep, seen),
+                        None => false,
+                    })
+            }
+            (Value::Callable(l), Value::Callable(r)) => Rc::ptr_eq(l, r),
+            (Value::UserData(l), Value::UserData(r)) => Rc::ptr_eq(&l.data, &r.data),
+            _ => false,
+        }
+    }
+
     pub fn is_numeric(&self) -> bool {
         match self {
             Value::Num(_) => true,
+            Value::Int(_) => true,
             Value::Bool(_) => true,
             _ => false,
         }
@@ -129,17 +822,28 @@ impl Value {
         }
     }
 
-    pub fn negate(&mut self) -> Result<Self, String> {
+    pub fn negate(&mut self) -> Result<Self, BeansError> {
         match self {
             Value::Num(n) => Ok(Value::Num(-*n)),
+            Value::Int(i) => match i.checked_neg() {
+                Some(i) => Ok(Value::Int(i)),
+                None => Err(BeansError::new(
+                    ErrorKind::ArithmeticError,
+                    format!("Integer overflow negating {}", i),
+                )),
+            },
             Value::Bool(b) => Ok(Value::Bool(!*b)),
-            _ => Err(format!("Cannot negate value {}", self.stringify())),
+            _ => Err(BeansError::new(
+                ErrorKind::TypeError,
+                format!("Cannot negate value {}", self.stringify()),
+            )),
         }
     }
 
     pub fn stringify(&self) -> String {
         match self {
             Value::Num(n) => format!("Num: {}", *n),
+            Value::Int(i) => format!("Int: {}", *i),
             Value::Str(s) => format!("Str: {}", s.clone()),
             Value::Bool(b) => format!("Bool: {}", *b),
             Value::Callable(call) => format!("Callable {}", call.to_string()),
@@ -171,13 +875,15 @@ impl Value {
 
                 format!("Collection: {{\n{}\n}}", content)
             }
-            Value::List(lis) => format!("List, {} elements", lis.len()),
+            Value::List(lis) => format!("List, {} elements", lis.borrow().len()),
+            Value::UserData(data) => format!("UserData {}", data.get_type_name()),
         }
     }
 
     pub fn string_repr(&self) -> String {
         match self {
             Value::Num(n) => format!("{}", *n),
+            Value::Int(i) => format!("{}", *i),
             Value::Str(s) => format!("{}", s.clone()),
             Value::Bool(b) => format!("{}", *b),
             _ => self.stringify(),
@@ -186,27 +892,71 @@ impl Value {
 }
 
 impl Closure {
-    pub fn new(fun: Rc<Vec<Stmt>>, env: Rc<RefCell<Env>>, params: Vec<String>) -> Closure {
-        Closure { env, params, fun }
+    pub fn new(name: String, fun: Rc<Body>, env: Rc<RefCell<Env>>, params: Vec<String>) -> Closure {
+        Closure {
+            name,
+            env,
+            params,
+            fun,
+            function: RefCell::new(None),
+        }
     }
-}
-impl Call for Closure {
-    fn call(&self, eval: &mut Evaluator, args: Vec<Value>) -> Value {
-        let enclosing_rc = self.env.clone();
 
-        let mut call_env = Env::new_enclosing(enclosing_rc);
-        for i in 0..self.arity() {
-            let name = self.params.get(i as usize).unwrap();
-            let arg = args.get(i as usize).unwrap();
-            call_env.insert(name.clone(), arg.clone());
+    pub(crate) fn with_function(function: Rc<Function>, env: Rc<RefCell<Env>>) -> Closure {
+        Closure {
+            name: function.name.clone(),
+            env,
+            params: function.params.clone(),
+            fun: function.body.clone(),
+            function: RefCell::new(Some(function)),
         }
+    }
+
+    /// Whether calls return a coroutine running the body instead of running it
+    pub(crate) fn is_generator(&self) -> bool {
+        self.fun.generator
+    }
+
+    pub(crate) fn get_function(&self) -> Rc<Function> {
+        let mut function = self.function.borrow_mut();
+        function
+            .get_or_insert_with(|| {
+                Rc::new(Compiler::compile_function(
+                    self.name.clone(),
+                    self.params.clone(),
+                    self.fun.clone(),
+                ))
+            })
+            .clone()
+    }
 
+    /// The environment a call runs in, with the parameters bound
+    pub(crate) fn call_env(&self, args: Vec<Value>) -> Env {
+        let mut call_env = Env::new_enclosing(self.env.clone());
+        // Parameters take the first slots of the call
+        call_env.slots = args;
+        call_env.slots.truncate(self.params.len());
+        call_env
+    }
+}
+impl Call for Closure {
+    fn call(&self, eval: &mut Evaluator, args: Vec<Value>) -> Result<Value, BeansError> {
+        if self.is_generator() {
+            return Ok(Coroutine::generator(self, args));
+        }
+        if eval.get_backend() == Backend::Bytecode {
+            return eval.call_closure(self, args);
+        }
+        let call_env = self.call_env(args);
         let result = eval.evaluate_in_env(&self.fun, call_env);
         match result {
-            StatementResult::Return(e) => e,
-            StatementResult::Ok(_) => Value::Nil,
-            StatementResult::Failure(why) => panic!(format!("Failure! {}", why)),
-            _ => panic!("Cannot break or continue inside function!"),
+            StatementResult::Return(e) => Ok(e),
+            StatementResult::Ok(_) => Ok(Value::Nil),
+            StatementResult::Failure(why) => Err(why),
+            _ => Err(BeansError::new(
+                ErrorKind::InvalidControlFlow,
+                String::from("Cannot break or continue inside function!"),
+            )),
         }
     }
     fn arity(&self) -> i8 {
@@ -215,6 +965,12 @@ impl Call for Closure {
     fn to_string(&self) -> String {
         format!("<function>")
     }
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+    fn as_closure(&self) -> Option<&Closure> {
+        Some(self)
+    }
 }
 
 impl StructFactory {
@@ -232,7 +988,7 @@ impl StructFactory {
 }
 
 impl Call for StructFactory {
-    fn call(&self, _eval: &mut Evaluator, args: Vec<Value>) -> Value {
+    fn call(&self, _eval: &mut Evaluator, args: Vec<Value>) -> Result<Value, BeansError> {
         let mut map: HashMap<String, Value> = HashMap::new();
 
         for i in 0..self.base.get_fields().len() {
@@ -241,11 +997,17 @@ impl Call for StructFactory {
             map.insert(name, arg);
         }
 
-        Value::StructInstance(StructInstance::new(map, self.base.clone()))
+        Ok(Value::StructInstance(StructInstance::new(
+            map,
+            self.base.clone(),
+        )))
     }
     fn arity(&self) -> i8 {
         self.base.get_fields().len() as i8
     }
+    fn name(&self) -> String {
+        self.get_name().clone()
+    }
 
     fn to_string(&self) -> String {
         let mut fields = format!("{{\n");
@@ -287,6 +1049,10 @@ impl StructInstance {
         self.fields.get(id)
     }
 
+    pub fn get_base(&self) -> &Rc<BaseStruct> {
+        &self.parent
+    }
+
     pub fn set(&mut self, id: &String, v: Value) -> Result<(), ()> {
         if self.fields.contains_key(id) {
             self.fields.insert(id.clone(), v);
@@ -307,6 +1073,7 @@ impl Env {
     pub fn new() -> Env {
         Env {
             symbols: HashMap::new(),
+            slots: vec![],
             enclosing: None,
         }
     }
@@ -316,6 +1083,20 @@ impl Env {
         e.enclosing = Some(enclosing);
         e
     }
+
+    pub fn enclosing(&self) -> Option<Rc<RefCell<Env>>> {
+        self.enclosing.clone()
+    }
+
+    /// A new environment with the same bindings, so closures keep the
+    /// values of the old one. Each iteration of a `for` loop runs in a copy.
+    pub fn copy(&self) -> Env {
+        Env {
+            symbols: self.symbols.clone(),
+            slots: self.slots.clone(),
+            enclosing: self.enclosing.clone(),
+        }
+    }
     pub fn get(&self, s: &String) -> Value {
         if self.symbols.contains_key(s) {
             let k = self.symbols.get(s).unwrap();
@@ -331,14 +1112,18 @@ impl Env {
         v
     }
 
-    pub fn set(&mut self, s: String, v: Value) {
+    pub fn set(&mut self, s: String, v: Value) -> Result<(), BeansError> {
         if self.symbols.contains_key(&s) {
             self.symbols.insert(s, v);
+            Ok(())
         } else if let Some(env) = &self.enclosing {
             let mut env_enclosing = env.as_ref().borrow_mut();
-            env_enclosing.set(s, v);
+            env_enclosing.set(s, v)
         } else {
-            println!("Undefined value! {}", s);
+            Err(BeansError::new(
+                ErrorKind::UndefinedVariable,
+                format!("Undefined value! {}", s),
+            ))
         }
     }
 
@@ -346,28 +1131,123 @@ impl Env {
         self.symbols.insert(s, v);
     }
 
+    /// Whether `s` is bound here or in an enclosing environment
+    pub fn contains(&self, s: &str) -> bool {
+        self.symbols.contains_key(s)
+            || match &self.enclosing {
+                Some(env) => env.borrow().contains(s),
+                None => false,
+            }
+    }
+
+    /// Reads a local `depth` function calls out. Unset slots are nil.
+    #[inline]
+    pub fn get_slot(&self, depth: usize, slot: usize) -> Value {
+        if depth == 0 {
+            return self.slots.get(slot).cloned().unwrap_or(Value::Nil);
+        }
+        match &self.enclosing {
+            Some(env) => env.borrow().get_slot(depth - 1, slot),
+            None => Value::Nil,
+        }
+    }
+
+    #[inline]
+    pub fn set_slot(&mut self, depth: usize, slot: usize, v: Value) {
+        if depth == 0 {
+            if slot >= self.slots.len() {
+                self.slots.resize(slot + 1, Value::Nil);
+            }
+            self.slots[slot] = v;
+        } else if let Some(env) = &self.enclosing {
+            env.borrow_mut().set_slot(depth - 1, slot, v);
+        }
+    }
+
+    pub fn get_variable(&self, var: &Variable) -> Value {
+        match var.slot.get() {
+            Slot::Global => self.get(&var.name),
+            Slot::Local { depth, slot } => self.get_slot(depth, slot),
+        }
+    }
+
+    /// Assigns an existing variable
+    pub fn set_variable(&mut self, var: &Variable, v: Value) -> Result<(), BeansError> {
+        match var.slot.get() {
+            Slot::Global => self.set(var.name.clone(), v),
+            Slot::Local { depth, slot } => {
+                self.set_slot(depth, slot, v);
+                Ok(())
+            }
+        }
+    }
+
+    /// Binds a variable in the environment its declaration runs in
+    pub fn define_variable(&mut self, var: &Variable, v: Value) {
+        match var.slot.get() {
+            Slot::Global => self.insert(var.name.clone(), v),
+            Slot::Local { slot, .. } => self.set_slot(0, slot, v),
+        }
+    }
+
     pub fn bind(&mut self, name: &str, val: Value) -> &Self {
         self.insert(String::from(name), val);
         self
     }
 
-    pub fn add_constant(&mut self, name: &str, val: Value) -> &Self {
-        self.set(String::from(name), val);
-        self
+    /// Binds a Rust function, converting its arguments and result
+    /// with `FromValue` and `IntoValue`
+    pub fn register_fn<Args, F: NativeFunction<Args>>(&mut self, name: &str, fun: F) -> &Self {
+        let native = NativeFn::new(fun.into_native(), F::arity());
+        self.bind(name, Value::Callable(Rc::new(Box::new(native))))
+    }
+
+    /// Binds the factory of a host struct under its own name
+    pub fn register_struct<T: BeansStruct>(&mut self) -> &Self {
+        let factory = T::factory();
+        let name = factory.get_name().clone();
+        self.bind(&name, Value::Callable(Rc::new(Box::new(factory))))
     }
 
-    pub fn make_callable(fun: fn(Vec<Value>) -> Value, arity: i8) -> Value {
-        Value::Callable(Rc::new(Box::new(NativeFn::new(fun, arity))))
+    pub fn add_constant(&mut self, name: &str, val: Value) -> Result<&Self, BeansError> {
+        self.set(String::from(name), val)?;
+        Ok(self)
+    }
+
+    pub fn make_callable<F>(fun: F, arity: i8) -> Value
+    where
+        F: Fn(&mut Evaluator, Vec<Value>) -> NativeResult + 'static,
+    {
+        Value::Callable(Rc::new(Box::new(NativeFn::new(Box::new(fun), arity))))
+    }
+
+    /// Like `make_callable`, for functions that mutate their captured state.
+    /// Calling such a function again while it is running is an error.
+    pub fn make_callable_mut<F>(fun: F, arity: i8) -> Value
+    where
+        F: FnMut(&mut Evaluator, Vec<Value>) -> NativeResult + 'static,
+    {
+        let fun = RefCell::new(fun);
+        Env::make_callable(
+            move |eval, args| match fun.try_borrow_mut() {
+                Ok(mut f) => (*f)(eval, args),
+                Err(_) => Err(BeansError::new(
+                    ErrorKind::InvalidControlFlow,
+                    String::from("Native function called recursively"),
+                )),
+            },
+            arity,
+        )
     }
 
     pub fn build_stdlib(&mut self) {
         let print = Env::make_callable(
-            |vals: Vec<Value>| {
+            |_, vals: Vec<Value>| {
                 for val in vals {
                     print!("{} ", val.string_repr());
                 }
                 print!("\n");
-                Value::Nil
+                Ok(Value::Nil)
             },
             -1,
         );
@@ -381,101 +1261,150 @@ impl Env {
 
         self.bind("print", print);
 
-        let mut math: HashMap<String, Value> = HashMap::new();
-        math.insert(String::from("PI"), Value::Num(std::f64::consts::PI));
+        // The iterator function of the integers from start to stop, stop excluded
+        let range = Env::make_callable(
+            |_, vals| {
+                arg_count(&vals, 1, 3)?;
+                let (start, stop) = match vals.len() {
+                    1 => (0, int_arg(&vals, 0)?),
+                    _ => (int_arg(&vals, 0)?, int_arg(&vals, 1)?),
+                };
+                let step = if vals.len() == 3 {
+                    int_arg(&vals, 2)?
+                } else {
+                    1
+                };
+                if step == 0 {
+                    return Err(BeansError::new(
+                        ErrorKind::ValueError,
+                        String::from("The step of a range can't be 0"),
+                    ));
+                }
+                let mut current = start;
+                Ok(Env::make_callable_mut(
+                    move |_, _| {
+                        if (step > 0 && current >= stop) || (step < 0 && current <= stop) {
+                            return Ok(Value::Nil);
+                        }
+                        let value = current;
+                        current = current.checked_add(step).unwrap_or(stop);
+                        Ok(Value::Int(value))
+                    },
+                    0,
+                ))
+            },
+            -1,
+        );
+        self.bind("range", range);
+
+        self.bind(
+            "error",
+            Env::make_callable(|_, mut vals| Err(raised(vals.remove(0))), 1),
+        );
+
+        let mut math = Dict::new();
+        math.insert(HashKey::from("PI"), Value::Num(std::f64::consts::PI));
         math.insert(
-            String::from("cos"),
+            HashKey::from("cos"),
             Env::make_callable(
-                |vals| {
-                    let n = vals.first().unwrap().as_numeric();
-                    Value::Num(n.cos())
+                |_, vals| {
+                    let n = numeric_arg(&vals, 0)?;
+                    Ok(Value::Num(n.cos()))
                 },
                 1,
             ),
         );
         math.insert(
-            String::from("sin"),
+            HashKey::from("sin"),
             Env::make_callable(
-                |vals| {
-                    let n = vals.first().unwrap().as_numeric();
-                    Value::Num(n.sin())
+                |_, vals| {
+                    let n = numeric_arg(&vals, 0)?;
+                    Ok(Value::Num(n.sin()))
                 },
                 1,
             ),
         );
         math.insert(
-            String::from("tan"),
+            HashKey::from("tan"),
             Env::make_callable(
-                |vals| {
-                    let n = vals.first().unwrap().as_numeric();
-                    Value::Num(n.tan())
+                |_, vals| {
+                    let n = numeric_arg(&vals, 0)?;
+                    Ok(Value::Num(n.tan()))
                 },
                 1,
             ),
         );
         math.insert(
-            String::from("atan"),
+            HashKey::from("atan"),
             Env::make_callable(
-                |vals| {
-                    let n = vals.first().unwrap().as_numeric();
-                    Value::Num(n.atan())
+                |_, vals| {
+                    let n = numeric_arg(&vals, 0)?;
+                    Ok(Value::Num(n.atan()))
                 },
                 1,
             ),
         );
         math.insert(
-            String::from("atan2"),
+            HashKey::from("atan2"),
             Env::make_callable(
-                |vals| {
-                    let n = vals.first().unwrap().as_numeric();
-                    let o = vals.get(1).unwrap().as_numeric();
-                    Value::Num(n.atan2(o))
+                |_, vals| {
+                    let n = numeric_arg(&vals, 0)?;
+                    let o = numeric_arg(&vals, 1)?;
+                    Ok(Value::Num(n.atan2(o)))
                 },
-                1,
+                2,
             ),
         );
         math.insert(
-            String::from("pow"),
+            HashKey::from("pow"),
             Env::make_callable(
-                |vals| {
-                    let n = vals.first().unwrap().as_numeric();
-                    let o = vals.get(1).unwrap().as_numeric();
-                    Value::Num(n.powf(o))
+                |_, vals| {
+                    let n = numeric_arg(&vals, 0)?;
+                    let o = numeric_arg(&vals, 1)?;
+                    Ok(Value::Num(n.powf(o)))
                 },
                 2,
             ),
         );
         math.insert(
-            String::from("pow2"),
+            HashKey::from("pow2"),
             Env::make_callable(
-                |vals| {
-                    let n = vals.first().unwrap().as_numeric();
-                    Value::Num(n * n)
+                |_, vals| {
+                    let n = numeric_arg(&vals, 0)?;
+                    Ok(Value::Num(n * n))
                 },
                 1,
             ),
         );
         math.insert(
-            String::from("sqrt"),
+            HashKey::from("sqrt"),
             Env::make_callable(
-                |vals| {
-                    let n = vals.first().unwrap().as_numeric();
-                    Value::Num(n.sqrt())
+                |_, vals| {
+                    let n = numeric_arg(&vals, 0)?;
+                    Ok(Value::Num(n.sqrt()))
                 },
                 1,
             ),
         );
         math.insert(
-            String::from("abs"),
+            HashKey::from("abs"),
             Env::make_callable(
-                |vals| {
-                    let n = vals.first().unwrap().as_numeric();
-                    Value::Num(n.abs())
+                |_, vals| {
+                    let n = numeric_arg(&vals, 0)?;
+                    Ok(Value::Num(n.abs()))
                 },
                 1,
             ),
         );
 
         self.bind("math", Value::Collection(Rc::new(RefCell::new(math))));
+        self.bind(
+            "string",
+            Value::Collection(Rc::new(RefCell::new(string_library()))),
+        );
+        self.bind(
+            "coroutine",
+            Value::Collection(Rc::new(RefCell::new(coroutine_library()))),
+        );
     }
 }
@@ -0,0 +1,284 @@
+use super::environments::Value;
+use super::span::*;
+use std::fmt;
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum ErrorKind {
+    TypeError,
+    IndexOutOfBounds,
+    UndefinedVariable,
+    ArityMismatch,
+    ImportFailed,
+    NotCallable,
+    InvalidAssignment,
+    IoError,
+    SyntaxError,
+    InvalidControlFlow,
+    Redeclaration,
+    ArithmeticError,
+    ValueError,
+    // Raised by scripts with `error()`
+    UserError,
+}
+
+const ERROR_KINDS: [ErrorKind; 14] = [
+    ErrorKind::TypeError,
+    ErrorKind::IndexOutOfBounds,
+    ErrorKind::UndefinedVariable,
+    ErrorKind::ArityMismatch,
+    ErrorKind::ImportFailed,
+    ErrorKind::NotCallable,
+    ErrorKind::InvalidAssignment,
+    ErrorKind::IoError,
+    ErrorKind::SyntaxError,
+    ErrorKind::InvalidControlFlow,
+    ErrorKind::Redeclaration,
+    ErrorKind::ArithmeticError,
+    ErrorKind::ValueError,
+    ErrorKind::UserError,
+];
+
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct Location {
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+    pub kind: ErrorKind,
+}
+
+/// A function call that was active when an error happened
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub function: String,
+    pub line: usize,
+    pub module: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct BeansError {
+    kind: ErrorKind,
+    // Boxed, so that results which may carry an error stay small
+    details: Box<Details>,
+}
+
+#[derive(Clone, Debug)]
+struct Details {
+    message: String,
+    location: Location,
+    diagnostics: Vec<Diagnostic>,
+    traceback: Vec<Frame>,
+    // What the script passed to `error()`
+    value: Option<Value>,
+}
+
+impl Diagnostic {
+    pub fn new(message: String, span: Span) -> Diagnostic {
+        Diagnostic {
+            message,
+            span,
+            kind: ErrorKind::SyntaxError,
+        }
+    }
+
+    /// A diagnostic found after parsing, such as by the resolver
+    pub fn with_kind(kind: ErrorKind, message: String, span: Span) -> Diagnostic {
+        Diagnostic {
+            message,
+            span,
+            kind,
+        }
+    }
+}
+
+impl Frame {
+    pub fn new(function: String, call_site: Span) -> Frame {
+        Frame {
+            function,
+            line: call_site.line,
+            module: file_name(call_site.file).unwrap_or_else(|| String::from("<string>")),
+        }
+    }
+}
+
+impl ErrorKind {
+    /// The kind displayed as `name`
+    pub fn from_name(name: &str) -> Option<ErrorKind> {
+        ERROR_KINDS
+            .iter()
+            .copied()
+            .find(|kind| kind.to_string() == name)
+    }
+}
+
+impl BeansError {
+    pub fn new(kind: ErrorKind, message: String) -> BeansError {
+        BeansError {
+            kind,
+            details: Box::new(Details {
+                message,
+                location: Location::default(),
+                diagnostics: vec![],
+                traceback: vec![],
+                value: None,
+            }),
+        }
+    }
+
+    /// An error raised by a script, carrying the value it was raised with
+    pub fn with_value(kind: ErrorKind, message: String, value: Value) -> BeansError {
+        let mut error = BeansError::new(kind, message);
+        error.details.value = Some(value);
+        error
+    }
+
+    /// Builds an error reporting every diagnostic found before running,
+    /// of the kind of the first one
+    pub fn from_diagnostics(diagnostics: Vec<Diagnostic>) -> BeansError {
+        let message = diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.to_string())
+            .collect::<Vec<String>>()
+            .join("\n");
+        let kind = diagnostics
+            .first()
+            .map(|first| first.kind)
+            .unwrap_or(ErrorKind::SyntaxError);
+        let mut error = BeansError::new(kind, message);
+        if let Some(first) = diagnostics.first() {
+            error = error.at(first.span);
+        }
+        error.details.diagnostics = diagnostics;
+        error
+    }
+
+    pub fn get_kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn get_message(&self) -> &String {
+        &self.details.message
+    }
+
+    pub fn get_location(&self) -> &Location {
+        &self.details.location
+    }
+
+    pub fn get_diagnostics(&self) -> &Vec<Diagnostic> {
+        &self.details.diagnostics
+    }
+
+    /// The value given to `error()`, if a script raised the error
+    pub fn get_value(&self) -> Option<&Value> {
+        self.details.value.as_ref()
+    }
+
+    /// The calls the error went through, outermost first
+    pub fn get_traceback(&self) -> &Vec<Frame> {
+        &self.details.traceback
+    }
+
+    /// Records a call the error is unwinding through.
+    /// Errors unwind from the innermost call, so each frame goes on the front.
+    pub fn push_frame(mut self, frame: Frame) -> BeansError {
+        self.details.traceback.insert(0, frame);
+        self
+    }
+
+    /// Formats the error like a Python traceback, most recent call last
+    pub fn format_traceback(&self) -> String {
+        let mut out = String::from("Traceback (most recent call last):\n");
+        let mut caller = String::from("<module>");
+        for frame in &self.details.traceback {
+            out.push_str(&format!(
+                "  File \"{}\", line {}, in {}\n",
+                frame.module, frame.line, caller
+            ));
+            caller = frame.function.clone();
+        }
+        let file = match &self.details.location.file {
+            Some(file) => file.as_str(),
+            None => "<string>",
+        };
+        out.push_str(&format!(
+            "  File \"{}\", line {}, in {}\n",
+            file, self.details.location.line, caller
+        ));
+        out.push_str(&format!("{}: {}", self.kind, self.details.message));
+        out
+    }
+
+    /// Records the file the error happened in, unless an inner
+    /// module already claimed it.
+    pub fn in_file(mut self, file: &str) -> BeansError {
+        if self.details.location.file.is_none() {
+            self.details.location.file = Some(String::from(file));
+        }
+        self
+    }
+
+    /// Records where the error happened; errors raised deeper in the tree
+    /// keep their own, more precise, location.
+    pub fn at(mut self, span: Span) -> BeansError {
+        if self.details.location.line == 0 {
+            self.details.location.line = span.line;
+            self.details.location.column = span.column;
+            if self.details.location.file.is_none() {
+                self.details.location.file = file_name(span.file);
+            }
+        }
+        self
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let file = match &self.file {
+            Some(file) => file.as_str(),
+            None => "<string>",
+        };
+        write!(f, "{}:{}:{}", file, self.line, self.column)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ErrorKind::SyntaxError => write!(
+                f,
+                "Syntax error at line {}, column {}: {}",
+                self.span.line, self.span.column, self.message
+            ),
+            kind => write!(
+                f,
+                "{} at line {}, column {}: {}",
+                kind, self.span.line, self.span.column, self.message
+            ),
+        }
+    }
+}
+
+impl fmt::Display for BeansError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.details.location.line == 0 && self.details.location.file.is_none() {
+            write!(f, "{}: {}", self.kind, self.details.message)
+        } else {
+            write!(
+                f,
+                "{}: {} (at {})",
+                self.kind, self.details.message, self.details.location
+            )
+        }
+    }
+}
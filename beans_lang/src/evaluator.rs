@@ -1,16 +1,20 @@
-use super::node::Expr::*;
+use super::node::ExprKind::*;
 use super::node::*;
 use super::tokens::TokenType::*;
-use float_cmp::*;
 use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::rc::Rc;
 
-use std::fs::File;
-use std::io::Read;
-
 use super::beans;
+use super::compiler::Compiler;
+use super::dict::{Dict, HashKey};
 use super::environments::*;
+use super::errors::*;
+use super::iteration::Iteration;
+use super::resolver::Resolver;
+use super::span::Span;
+use super::vm::Vm;
 
 #[cfg(test)]
 mod tests {
@@ -50,7 +54,8 @@ mod tests {
         let stmts = parser.parse();
 
         let mut evaluator = evaluator::Evaluator::new();
-        let mut value: StatementResult = StatementResult::Break;
+        resolver::Resolver::new(evaluator.current.clone()).resolve(&stmts);
+        let mut value: StatementResult = StatementResult::Break(None);
         for stmt in stmts {
             value = evaluator.execute_statement(&stmt);
         }
@@ -220,6 +225,287 @@ mod tests {
         }
     }
 
+    #[test]
+    fn equality() {
+        let prog = "var d = {};
+        struct P {x}
+        [\"a\" == \"a\", nil == nil, d.x == nil, 1 != \"1\", [1, [2]] == [1, [2]],
+            d == d, d != {}, P(1) == P(1), true != 1];";
+        match exec_prog(prog) {
+            StatementResult::Ok(environments::Value::List(results)) => {
+                assert!(results
+                    .borrow()
+                    .iter()
+                    .all(|r| r.equals(&environments::Value::Bool(true))))
+            }
+            StatementResult::Failure(why) => panic!("Failure! {}", why),
+            _ => panic!("Failure on equality"),
+        }
+    }
+
+    #[test]
+    fn list_aliases() {
+        let prog = "var a = [1, 2, 3];
+        var b = a;
+        function set(l) l[0] = 10; end
+        set(b);
+        a[2] = 30;
+        [a[0], b[2], a == b];";
+        match exec_prog(prog) {
+            StatementResult::Ok(environments::Value::List(results)) => {
+                let results = results.borrow();
+                assert!(results[0].as_numeric() == 10.0 && results[1].as_numeric() == 30.0);
+                assert!(results[2].equals(&environments::Value::Bool(true)));
+            }
+            StatementResult::Failure(why) => panic!("Failure! {}", why),
+            _ => panic!("Failure on list aliases"),
+        }
+    }
+
+    #[test]
+    fn dict_keys() {
+        let prog = "enum Colors {Red, Blue}
+        var k = \"computed\";
+        var d = {z: 0, 1: \"one\", \"two words\": 2, true: 3, [k]: 4};
+        d[Colors.Blue] = \"blue\";
+        d.z = 5;
+        [d[1], d[\"two words\"], d[true], d[\"computed\"], d[1.0], d.z, d[\"z\"]];";
+        // Enum members are numbers, so Colors.Blue is the key 1
+        match exec_prog(prog) {
+            StatementResult::Ok(environments::Value::List(results)) => {
+                let results: Vec<String> =
+                    results.borrow().iter().map(|r| r.string_repr()).collect();
+                assert!(
+                    results == ["blue", "2", "3", "4", "blue", "5", "5"],
+                    "{:?}",
+                    results
+                );
+            }
+            StatementResult::Failure(why) => panic!("Failure! {}", why),
+            _ => panic!("Failure on dict keys"),
+        }
+        // Printed in insertion order
+        match exec_prog("var d = {b: 1, a: 2, [0]: 3}; d.c = 4; d.b = 5; d;") {
+            StatementResult::Ok(d) => assert!(
+                d.stringify()
+                    == "Collection: {\n\tb : Int: 5\n\ta : Int: 2\n\t0 : Int: 3\n\tc : Int: 4\n\n}"
+            ),
+            _ => panic!("Failure on dict order"),
+        }
+    }
+
+    #[test]
+    fn floored_modulo() {
+        let prog = "var ok = true;
+        for a in [7, -7, 6, 0, 7.5, -7.5] do
+            for b in [3, -3, 2, 0.5, -2.5] do
+                if a != (a // b) * b + a % b then ok = false; end
+            end
+        end
+        [ok, -7 % 3, 7 % -3, -7 % -3, -7.5 % 2, 7.5 % -2];";
+        match exec_prog(prog) {
+            StatementResult::Ok(environments::Value::List(results)) => {
+                let results: Vec<String> = results.borrow().iter().map(|r| r.stringify()).collect();
+                let expected = [
+                    "Bool: true",
+                    "Int: 2",
+                    "Int: -2",
+                    "Int: -1",
+                    "Num: 0.5",
+                    "Num: -0.5",
+                ];
+                assert!(results == expected, "{:?}", results);
+            }
+            StatementResult::Failure(why) => panic!("Failure! {}", why),
+            _ => panic!("Failure on floored modulo"),
+        }
+    }
+
+    #[test]
+    fn integers() {
+        let prog =
+            "[7 // 2, -7 // 2, 7 % 3, -1 & 3, 5 | 8, -16 >> 2, 1 << 62, 3 + 0.5, 7 / 2, 7.0 // 2,
+        9007199254740993 > 9007199254740992, 2 == 2.0];";
+        match exec_prog(prog) {
+            StatementResult::Ok(environments::Value::List(results)) => {
+                let results: Vec<String> = results.borrow().iter().map(|r| r.stringify()).collect();
+                let expected = [
+                    "Int: 3",
+                    "Int: -4",
+                    "Int: 1",
+                    "Int: 3",
+                    "Int: 13",
+                    "Int: -4",
+                    "Int: 4611686018427387904",
+                    "Num: 3.5",
+                    "Num: 3.5",
+                    "Num: 3",
+                    "Bool: true",
+                    "Bool: true",
+                ];
+                assert!(results == expected, "{:?}", results);
+            }
+            StatementResult::Failure(why) => panic!("Failure! {}", why),
+            _ => panic!("Failure on integers"),
+        }
+        for bad in [
+            "9223372036854775807 + 1;",
+            "-9223372036854775807 - 2;",
+            "1 << 63;",
+            "1 >> -1;",
+            "1 // 0;",
+            "1.5 // 0;",
+            "1 % 0;",
+            "1 % 0.0;",
+            "1 / 0;",
+            "1.5 / -0.0;",
+            "1.5 & 1;",
+        ]
+        .iter()
+        {
+            match exec_prog(bad) {
+                StatementResult::Failure(_) => {}
+                _ => panic!("{} did not fail", bad),
+            }
+        }
+    }
+
+    #[test]
+    fn for_in() {
+        let prog = "var out = \"\";
+        function push(x) out = \"{out}{x} \"; end
+        for i in [1, 2] do push(i); end
+        for i, c in \"hé\" do push(\"{i}{c}\"); end
+        for k, v in {a: 1, [2]: \"b\"} do push(\"{k}={v}\"); end
+        var next = lambda() var n = 0; return lambda() n = n + 1; if n < 3 then return n * 10; end end; end;
+        for x in next() do push(x); end
+        struct Pairs {iter}
+        for k, v in Pairs(lambda() var done = false; return lambda()
+            if done then return nil; end done = true; return [\"k\", \"v\"]; end; end) do push(\"{k}{v}\"); end
+        out;";
+        match exec_prog(prog) {
+            StatementResult::Ok(out) => {
+                assert!(
+                    out.string_repr() == "1 2 0h 1é a=1 2=b 10 20 kv ",
+                    "{}",
+                    out.string_repr()
+                )
+            }
+            StatementResult::Failure(why) => panic!("Failure! {}", why),
+            _ => panic!("Failure on for in"),
+        }
+        for bad in [
+            "for x in 1 do end",
+            "for k, v in lambda() return 1; end do end",
+            "for x in lambda(a) return a; end do end",
+            "struct S {x} for x in S(1) do end",
+        ]
+        .iter()
+        {
+            match exec_prog(bad) {
+                StatementResult::Failure(_) => {}
+                _ => panic!("{} did not fail", bad),
+            }
+        }
+    }
+
+    #[test]
+    fn loop_control() {
+        let prog = "var out = \"\";
+        for var i = 0; i < 6; i = i + 1 do
+            if i == 1 then continue; end
+            if i == 4 then break; end
+            out = \"{out}{i}\";
+        end
+        outer: for x in [1, 2, 3] do
+            var j = 0;
+            while true do
+                j = j + 1;
+                if j == 2 then continue outer; end
+                if x == 3 then break outer; end
+                out = \"{out} {x}{j}\";
+            end
+        end
+        out;";
+        match exec_prog(prog) {
+            StatementResult::Ok(out) => {
+                assert!(out.string_repr() == "023 11 21", "{}", out.string_repr())
+            }
+            StatementResult::Failure(why) => panic!("Failure! {}", why),
+            _ => panic!("Failure on loop control"),
+        }
+    }
+
+    #[test]
+    fn block_scopes() {
+        let prog = "var x = \"outer\"; var seen = \"\";
+        if true then var x = \"inner\"; seen = x; end
+        var fs = [nil, nil, nil];
+        for var i = 0; i < 3; i = i + 1 do fs[i] = lambda() return i; end; end
+        var gs = [nil, nil];
+        for i, c in \"ab\" do var twice = \"{c}{c}\"; gs[i] = lambda() return twice; end; end
+        \"{seen} {x} {fs[0]()}{fs[1]()}{fs[2]()} {gs[0]()}{gs[1]()}\";";
+        match exec_prog(prog) {
+            StatementResult::Ok(out) => {
+                assert!(
+                    out.string_repr() == "inner outer 012 aabb",
+                    "{}",
+                    out.string_repr()
+                )
+            }
+            StatementResult::Failure(why) => panic!("Failure! {}", why),
+            _ => panic!("Failure on block scopes"),
+        }
+    }
+
+    #[test]
+    fn index() {
+        let prog = "var l = [1, 2, 3, 4];
+        var d = {};
+        var i = 1;
+        d[\"some key\"] = l[i + 1];
+        l[-1] = 40;
+        [d[\"some key\"], l[-1], l[1:-1], l[:2], l[3:1], \"héllo\"[1], \"héllo\"[-3:]];";
+        match exec_prog(prog) {
+            StatementResult::Ok(environments::Value::List(results)) => {
+                let results = results.borrow();
+                let expected = [
+                    "3",
+                    "40",
+                    "List, 2 elements",
+                    "List, 2 elements",
+                    "List, 0 elements",
+                    "é",
+                    "llo",
+                ];
+                for (result, expected) in results.iter().zip(expected.iter()) {
+                    assert!(
+                        result.string_repr() == *expected,
+                        "{}",
+                        result.string_repr()
+                    );
+                }
+            }
+            StatementResult::Failure(why) => panic!("Failure! {}", why),
+            _ => panic!("Failure on index"),
+        }
+        for bad in [
+            "[1][1];",
+            "[1][0.5];",
+            "{}[nil];",
+            "{[[]]: 1};",
+            "\"s\"[0] = \"t\";",
+            "[1][\"a\":];",
+        ]
+        .iter()
+        {
+            match exec_prog(bad) {
+                StatementResult::Failure(_) => {}
+                _ => panic!("{} did not fail", bad),
+            }
+        }
+    }
+
     // #[test]
     // fn list() {
     //     let prog = "var lis = [1, 2, 3, 4, 42];
@@ -253,22 +539,6 @@ mod tests {
     //     }
     // }
 }
-macro_rules! operation {
-    ($lr: expr, $op: tt, $rr: expr, $variant: ident) => {
-        match ($lr, $rr) {
-            (Ok(l), Ok(r)) => {
-                if l.is_numeric() && r.is_numeric() {
-                    Ok(Value::$variant(l.as_numeric() $op r.as_numeric()))
-                } else {
-                    Err(format!("Unsummable values! {}, {}", l.stringify(), r.stringify()))
-                }
-            },
-            (Err(lwhy), _) => Err(format!("In left side of operation: {}", lwhy)),
-            (_, Err(rwhy)) => Err(format!("In right side of operation: {}", rwhy)),
-            }
-        };
-}
-
 macro_rules! get_value {
     ($er: expr) => {
         match $er {
@@ -282,8 +552,8 @@ macro_rules! get_values_no_bs {
     ($lr: expr, $rr: expr) => {
         match ($lr, $rr) {
             (Ok(l), Ok(r)) => (l, r),
-            (Err(lwhy), _) => return Err(format!("In left side of operation: {}", lwhy)),
-            (_, Err(rwhy)) => return Err(format!("In right side of operation: {}", rwhy)),
+            (Err(lwhy), _) => return Err(lwhy),
+            (_, Err(rwhy)) => return Err(rwhy),
         }
     };
 }
@@ -291,9 +561,17 @@ macro_rules! get_values_no_bs {
 pub enum StatementResult {
     Ok(Value),
     Return(Value),
-    Break,
-    Continue,
-    Failure(String),
+    // With the label of the loop they leave, if any
+    Break(Option<String>),
+    Continue(Option<String>),
+    Failure(BeansError),
+}
+
+impl StatementResult {
+    /// Whether the statements after the one giving this result run
+    pub(crate) fn goes_on(&self) -> bool {
+        matches!(self, StatementResult::Ok(_) | StatementResult::Return(_))
+    }
 }
 
 pub trait Evaluate<S, E> {
@@ -301,9 +579,20 @@ pub trait Evaluate<S, E> {
     fn evaluate(&mut self, e: &Expr) -> E;
 }
 
+/// How an `Evaluator` runs statements
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Backend {
+    /// Walks the syntax tree directly
+    TreeWalker,
+    /// Compiles programs to bytecode and runs them on a stack VM
+    Bytecode,
+}
+
 pub struct Evaluator {
     global: Rc<RefCell<Env>>,
-    current: Rc<RefCell<Env>>,
+    pub(crate) current: Rc<RefCell<Env>>,
+    backend: Backend,
+    pub(crate) vm: Vm,
 }
 
 impl Evaluator {
@@ -312,10 +601,37 @@ impl Evaluator {
     }
 
     pub fn new_with_global(env: Rc<RefCell<Env>>) -> Evaluator {
+        Evaluator::new_with_backend(env, Backend::TreeWalker)
+    }
+
+    pub fn new_with_backend(env: Rc<RefCell<Env>>, backend: Backend) -> Evaluator {
         Evaluator {
             global: env.clone(),
             current: env.clone(),
+            backend,
+            vm: Vm::default(),
+        }
+    }
+
+    pub fn get_backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Runs the statements of a resolved program in order, stopping at the
+    /// first one that fails or breaks out of the top level. The bytecode
+    /// backend compiles the whole program once.
+    pub fn execute_program(&mut self, stmts: &[Stmt]) -> StatementResult {
+        if self.backend == Backend::Bytecode {
+            return self.run_program(Compiler::compile_program(stmts));
+        }
+        let mut result = StatementResult::Continue(None);
+        for stmt in stmts {
+            result = self.execute_statement(stmt);
+            if !result.goes_on() {
+                break;
+            }
         }
+        result
     }
 
     pub fn evaluate_in_env(&mut self, stmts: &Vec<Stmt>, env: Env) -> StatementResult {
@@ -326,9 +642,10 @@ impl Evaluator {
         result
     }
 
-    fn is_true(v: &Value) -> bool {
+    pub(crate) fn is_true(v: &Value) -> bool {
         match v {
             Value::Num(n) => *n != 0.0,
+            Value::Int(i) => *i != 0,
             Value::Str(s) => s.len() != 0,
             Value::Bool(b) => *b == true,
             Value::Callable(c) => c.arity() != 0,
@@ -336,7 +653,8 @@ impl Evaluator {
             Value::Enum(_, fields) => fields.len() != 0,
             Value::Nil => false,
             Value::Collection(map) => map.borrow().len() != 0,
-            Value::List(elts) => elts.len() != 0,
+            Value::List(elts) => elts.borrow().len() != 0,
+            Value::UserData(_) => true,
         }
     }
 
@@ -353,13 +671,35 @@ impl Evaluator {
                 };
                 Evaluator::is_true(&res)
             } {
-                return self.exec_block(&branch.1);
+                return self.exec_scope(&branch.1);
             }
         }
-        self.exec_block(else_block)
+        self.exec_scope(else_block)
     }
 
-    fn exec_while(&mut self, cond: &Expr, block: &Vec<Stmt>) -> StatementResult {
+    /// Tells a loop what to do once its body ran: `Ok(true)` to go on,
+    /// `Ok(false)` to stop, and `Err` with results that leave the loop for
+    /// an enclosing loop or function
+    fn after_body(
+        result: StatementResult,
+        label: &Option<String>,
+    ) -> Result<bool, StatementResult> {
+        match result {
+            StatementResult::Ok(_) | StatementResult::Continue(None) => Ok(true),
+            StatementResult::Break(None) => Ok(false),
+            StatementResult::Continue(Some(ref l)) if label.as_ref() == Some(l) => Ok(true),
+            StatementResult::Break(Some(ref l)) if label.as_ref() == Some(l) => Ok(false),
+            result => Err(result),
+        }
+    }
+
+    fn exec_while(
+        &mut self,
+        label: &Option<String>,
+        cond: &Expr,
+        block: &Vec<Stmt>,
+        updater: &Option<Expr>,
+    ) -> StatementResult {
         while {
             let res = match self.evaluate(&cond) {
                 Ok(v) => v,
@@ -367,31 +707,101 @@ impl Evaluator {
             };
             Evaluator::is_true(&res)
         } {
-            match self.exec_block(block) {
-                StatementResult::Ok(_) => {}
-                StatementResult::Return(v) => return StatementResult::Return(v),
-                StatementResult::Continue => continue,
-                StatementResult::Break => break,
-                StatementResult::Failure(why) => return StatementResult::Failure(why),
+            match Evaluator::after_body(self.exec_scope(block), label) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(result) => return result,
+            }
+            if let Some(updater) = updater {
+                // Closures created by this iteration keep its loop variable
+                let next = self.current.borrow().copy();
+                self.current = Rc::new(RefCell::new(next));
+                if let Err(why) = self.evaluate(updater) {
+                    return StatementResult::Failure(why);
+                }
             }
         }
 
         StatementResult::Ok(Value::Nil)
     }
+
+    fn exec_for_in(
+        &mut self,
+        label: &Option<String>,
+        vars: &[Variable],
+        iterable: &Expr,
+        block: &Vec<Stmt>,
+        span: Span,
+    ) -> StatementResult {
+        let iterable = match self.evaluate(iterable) {
+            Ok(v) => v,
+            Err(why) => return StatementResult::Failure(why),
+        };
+        let mut iteration = match Iteration::new(self, iterable, vars.len() == 2) {
+            Ok(iteration) => iteration,
+            Err(why) => return StatementResult::Failure(why.at(span)),
+        };
+        loop {
+            let (first, second) = match iteration.next(self, span) {
+                Ok(Some(values)) => values,
+                Ok(None) => break,
+                Err(why) => return StatementResult::Failure(why.at(span)),
+            };
+            let mut env = Env::new_enclosing(self.current.clone());
+            env.define_variable(&vars[0], first);
+            if let Some(var) = vars.get(1) {
+                env.define_variable(var, second);
+            }
+            match Evaluator::after_body(self.evaluate_in_env(block, env), label) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(result) => return result,
+            }
+        }
+
+        StatementResult::Ok(Value::Nil)
+    }
+    fn exec_try(
+        &mut self,
+        body: &Vec<Stmt>,
+        catch: &Option<(Variable, Vec<Stmt>)>,
+        finally: &Vec<Stmt>,
+    ) -> StatementResult {
+        let mut result = self.exec_scope(body);
+        if let (StatementResult::Failure(why), Some((var, handler))) = (&result, catch) {
+            let mut env = Env::new_enclosing(self.current.clone());
+            env.define_variable(var, error_object(why));
+            result = self.evaluate_in_env(handler, env);
+        }
+        // Leaving `finally` early overrides what the rest did
+        match self.exec_scope(finally) {
+            StatementResult::Ok(_) => result,
+            other => other,
+        }
+    }
+
+    /// Runs a block in an environment of its own if it declares names
+    fn exec_scope(&mut self, block: &Vec<Stmt>) -> StatementResult {
+        if !Resolver::declares(block) {
+            return self.exec_block(block);
+        }
+        let env = Env::new_enclosing(self.current.clone());
+        self.evaluate_in_env(block, env)
+    }
+
     fn exec_block(&mut self, block: &Vec<Stmt>) -> StatementResult {
         for stmt in block.iter() {
             let result = self.execute_statement(stmt);
             match result {
                 StatementResult::Ok(_) => {}
                 StatementResult::Return(v) => return StatementResult::Return(v),
-                StatementResult::Break => break,
-                StatementResult::Continue => return result,
                 StatementResult::Failure(why) => return StatementResult::Failure(why),
+                StatementResult::Break(_) | StatementResult::Continue(_) => return result,
             }
         }
         StatementResult::Ok(Value::Nil)
     }
-    fn exec_var(&mut self, id: &String, initializer: &Option<Expr>) -> StatementResult {
+    fn exec_var(&mut self, id: &Variable, initializer: &Option<Expr>) -> StatementResult {
         let mut value = Value::Nil;
         if let Some(expr) = initializer {
             let res = match self.evaluate(&expr) {
@@ -401,39 +811,40 @@ impl Evaluator {
             value = res;
         }
         let ret = value.clone();
-        self.current.borrow_mut().insert(id.clone(), value.clone());
+        self.current.borrow_mut().define_variable(id, value);
         StatementResult::Ok(ret)
     }
     fn exec_fundef(
         &mut self,
-        id: &String,
+        id: &Variable,
         params: &Vec<String>,
-        block: &Rc<Vec<Stmt>>,
+        block: &Rc<Body>,
     ) -> StatementResult {
         let closure = Value::Callable(Rc::new(Box::new(Closure::new(
+            id.name.clone(),
             block.clone(),
             self.current.clone(),
             params.to_vec(),
         ))));
         let ret = closure.clone();
-        self.current.borrow_mut().insert(id.clone(), closure);
+        self.current.borrow_mut().define_variable(id, closure);
         StatementResult::Ok(ret)
     }
 
-    fn exec_structdef(&mut self, name: &String, members: &Vec<String>) -> StatementResult {
-        let base_strukt = BaseStruct::new(members.to_vec(), name.clone());
+    fn exec_structdef(&mut self, name: &Variable, members: &Vec<String>) -> StatementResult {
+        let base_strukt = BaseStruct::new(members.to_vec(), name.name.clone());
         let factory = StructFactory::new(Rc::new(base_strukt));
 
         let strukt = Value::Callable(Rc::new(Box::new(factory)));
 
         let mut current_env = self.current.as_ref().borrow_mut();
-        current_env.insert(name.clone(), strukt.clone());
+        current_env.define_variable(name, strukt.clone());
         StatementResult::Ok(strukt)
     }
 
     fn exec_enumdef(
         &mut self,
-        name: &String,
+        name: &Variable,
         values: &Vec<(String, Option<Expr>)>,
     ) -> StatementResult {
         let mut i = 0.0;
@@ -446,9 +857,11 @@ impl Evaluator {
                 };
                 match res {
                     Value::Num(e) => e,
+                    Value::Int(e) => e as f64,
                     _ => {
-                        return StatementResult::Failure(format!(
-                            "Enum variants can only be associated to numbers!"
+                        return StatementResult::Failure(BeansError::new(
+                            ErrorKind::TypeError,
+                            String::from("Enum variants can only be associated to numbers!"),
                         ))
                     }
                 }
@@ -459,9 +872,9 @@ impl Evaluator {
             };
             variants.insert(value.0.clone(), assoc_value);
         }
-        let enumt = Value::Enum(name.clone(), variants);
+        let enumt = Value::Enum(name.name.clone(), variants);
         let ret = enumt.clone();
-        self.current.borrow_mut().insert(name.clone(), enumt);
+        self.current.borrow_mut().define_variable(name, enumt);
         StatementResult::Ok(ret)
     }
 
@@ -473,18 +886,42 @@ impl Evaluator {
         }
     }
 
-    fn exec_import(&mut self, module_path: &String) -> StatementResult {
-        use std::ops::Add;
-        let module_name = module_path.split('/').last().unwrap();
-        let env = Env::new_enclosing(self.current.clone());
-        let mut evaluator = Evaluator::new_with_global(Rc::new(RefCell::new(env)));
-        let result = beans::do_file(&module_path.clone().add(".bean"), &mut evaluator);
-        match result {
-            StatementResult::Return(value) => {
-                self.current.borrow_mut().bind(module_name, value.clone());
+    fn exec_import(&mut self, var: &Variable, module_path: &String) -> StatementResult {
+        let env = self.current.clone();
+        match self.import(&env, module_path) {
+            Ok(module) => {
+                self.current.borrow_mut().define_variable(var, module);
                 StatementResult::Ok(Value::Nil)
             }
-            _ => result,
+            Err(why) => StatementResult::Failure(why),
+        }
+    }
+
+    /// Runs a module in an environment enclosing `env`, giving what it
+    /// returns, or nil
+    pub(crate) fn import(
+        &self,
+        env: &Rc<RefCell<Env>>,
+        module_path: &String,
+    ) -> Result<Value, BeansError> {
+        use std::ops::Add;
+        let module_env = Env::new_enclosing(env.clone());
+        let mut evaluator =
+            Evaluator::new_with_backend(Rc::new(RefCell::new(module_env)), self.backend);
+        match beans::do_file(&module_path.clone().add(".bean"), &mut evaluator) {
+            StatementResult::Return(value) => Ok(value),
+            StatementResult::Failure(why) if why.get_kind() == ErrorKind::IoError => {
+                Err(BeansError::new(
+                    ErrorKind::ImportFailed,
+                    format!(
+                        "Could not import module {}: {}",
+                        module_path,
+                        why.get_message()
+                    ),
+                ))
+            }
+            StatementResult::Failure(why) => Err(why),
+            _ => Ok(Value::Nil),
         }
     }
 
@@ -493,76 +930,8 @@ impl Evaluator {
         le: &Expr,
         op: super::tokens::TokenType,
         re: &Expr,
-    ) -> Result<Value, String> {
+    ) -> Result<Value, BeansError> {
         match op {
-            Plus => {
-                let (l, r) = get_values_no_bs!(self.evaluate(le), self.evaluate(re));
-                if l.is_numeric() && r.is_numeric() {
-                    Ok(Value::Num(l.as_numeric() + r.as_numeric()))
-                } else if l.is_string() || r.is_string() {
-                    Ok(Value::Str(format!("{}{}", l.stringify(), r.stringify())))
-                } else {
-                    Err(format!(
-                        "Unsummable values! {}, {}",
-                        l.stringify(),
-                        r.stringify()
-                    ))
-                }
-            }
-            Minus => {
-                operation!(self.evaluate(le), -, self.evaluate(re), Num)
-                // let l = self.evaluate(le);
-                // let r = self.evaluate(re);
-                // Value::Num(l.as_numeric() - r.as_numeric())
-            }
-            Star => operation!(self.evaluate(le), *, self.evaluate(re), Num),
-            Slash => {
-                let (l, r) = get_values_no_bs!(self.evaluate(le), self.evaluate(re));
-                let divisor = r.as_numeric();
-                Ok(Value::Num(if divisor != 0.0 {
-                    l.as_numeric() / r.as_numeric()
-                } else {
-                    0.0
-                }))
-            }
-            Mod => operation!(self.evaluate(le), %, self.evaluate(re), Num),
-            Less => operation!(self.evaluate(le), <, self.evaluate(re), Bool),
-            LessEquals => operation!(self.evaluate(le), <=, self.evaluate(re), Bool),
-            More => operation!(self.evaluate(le), >, self.evaluate(re), Bool),
-            MoreEquals => operation!(self.evaluate(le), >=, self.evaluate(re), Bool),
-            EqualsEquals => {
-                let (l, r) = get_values_no_bs!(self.evaluate(le), self.evaluate(re));
-                Ok(Value::Bool(
-                    l.as_numeric()
-                        .approx_eq(r.as_numeric(), F64Margin::default()),
-                ))
-            }
-            BangEquals => operation!(self.evaluate(le), !=, self.evaluate(re), Bool),
-
-            LessLess => {
-                let (l, r) = get_values_no_bs!(self.evaluate(le), self.evaluate(re));
-                Ok(Value::Num(
-                    ((l.as_numeric() as u64) << (r.as_numeric() as u64)) as f64,
-                ))
-            }
-            MoreMore => {
-                let (l, r) = get_values_no_bs!(self.evaluate(le), self.evaluate(re));
-                Ok(Value::Num(
-                    ((l.as_numeric() as u64) >> (r.as_numeric() as u64)) as f64,
-                ))
-            }
-            Ampersand => {
-                let (l, r) = get_values_no_bs!(self.evaluate(le), self.evaluate(re));
-                Ok(Value::Num(
-                    ((l.as_numeric() as u64) & (r.as_numeric() as u64)) as f64,
-                ))
-            }
-            Pipe => {
-                let (l, r) = get_values_no_bs!(self.evaluate(le), self.evaluate(re));
-                Ok(Value::Num(
-                    ((l.as_numeric() as u64) | (r.as_numeric() as u64)) as f64,
-                ))
-            }
             And => {
                 let l = get_value!(self.evaluate(le));
                 if !Evaluator::is_true(&l) {
@@ -579,20 +948,196 @@ impl Evaluator {
                     self.evaluate(re)
                 }
             }
+            _ => {
+                let (l, r) = get_values_no_bs!(self.evaluate(le), self.evaluate(re));
+                Evaluator::binary_op(l, op, r)
+            }
+        }
+    }
+
+    /// Applies a non short-circuiting binary operator, shared by both backends
+    pub(crate) fn binary_op(
+        l: Value,
+        op: super::tokens::TokenType,
+        r: Value,
+    ) -> Result<Value, BeansError> {
+        match op {
+            Plus => {
+                if l.is_numeric() && r.is_numeric() {
+                    Evaluator::numeric(&l, &r, i64::checked_add, |a, b| a + b)
+                } else if l.is_string() || r.is_string() {
+                    Ok(Value::Str(format!("{}{}", l.stringify(), r.stringify())))
+                } else {
+                    Err(Evaluator::operand_error(&l, &r))
+                }
+            }
+            Minus => Evaluator::numeric(&l, &r, i64::checked_sub, |a, b| a - b),
+            Star => Evaluator::numeric(&l, &r, i64::checked_mul, |a, b| a * b),
+            Slash => {
+                if !(l.is_numeric() && r.is_numeric()) {
+                    return Err(Evaluator::operand_error(&l, &r));
+                }
+                if r.as_numeric() == 0.0 {
+                    return Err(Evaluator::division_by_zero());
+                }
+                Ok(Value::Num(l.as_numeric() / r.as_numeric()))
+            }
+            SlashSlash => {
+                if r.is_numeric() && r.as_numeric() == 0.0 {
+                    return Err(Evaluator::division_by_zero());
+                }
+                Evaluator::numeric(&l, &r, Evaluator::floor_div, |a, b| (a / b).floor())
+            }
+            Mod => {
+                if r.is_numeric() && r.as_numeric() == 0.0 {
+                    return Err(Evaluator::division_by_zero());
+                }
+                Evaluator::numeric(&l, &r, Evaluator::floor_mod, |a, b| {
+                    let m = a % b;
+                    if m != 0.0 && (m < 0.0) != (b < 0.0) {
+                        m + b
+                    } else {
+                        m
+                    }
+                })
+            }
+            Less => Evaluator::ordering(&l, &r, |o| o == Ordering::Less),
+            LessEquals => Evaluator::ordering(&l, &r, |o| o != Ordering::Greater),
+            More => Evaluator::ordering(&l, &r, |o| o == Ordering::Greater),
+            MoreEquals => Evaluator::ordering(&l, &r, |o| o != Ordering::Less),
+            EqualsEquals => Ok(Value::Bool(l.equals(&r))),
+            BangEquals => Ok(Value::Bool(!l.equals(&r))),
+            LessLess | MoreMore | Ampersand | Pipe => Evaluator::bit_op(&l, op, &r),
             _ => unreachable!(),
         }
     }
 
-    fn do_call(&mut self, fun: &Expr, args: &Vec<Expr>) -> Result<Value, String> {
+    /// Integer view of a value for arithmetic, bools counting as 0 and 1
+    fn as_int(v: &Value) -> Option<i64> {
+        match v {
+            Value::Int(i) => Some(*i),
+            Value::Bool(b) => Some(*b as i64),
+            _ => None,
+        }
+    }
+
+    fn operand_error(l: &Value, r: &Value) -> BeansError {
+        BeansError::new(
+            ErrorKind::TypeError,
+            format!("Unsummable values! {}, {}", l.stringify(), r.stringify()),
+        )
+    }
+
+    fn division_by_zero() -> BeansError {
+        BeansError::new(ErrorKind::ArithmeticError, String::from("Division by zero"))
+    }
+
+    fn overflow(l: i64, r: i64) -> BeansError {
+        BeansError::new(
+            ErrorKind::ArithmeticError,
+            format!("Integer overflow with operands {} and {}", l, r),
+        )
+    }
+
+    fn floor_div(a: i64, b: i64) -> Option<i64> {
+        let q = a.checked_div(b)?;
+        if a % b != 0 && (a < 0) != (b < 0) {
+            Some(q - 1)
+        } else {
+            Some(q)
+        }
+    }
+
+    /// Remainder of `floor_div`, taking the sign of the divisor
+    pub(crate) fn floor_mod(a: i64, b: i64) -> Option<i64> {
+        let m = a.checked_rem(b)?;
+        if m != 0 && (m < 0) != (b < 0) {
+            Some(m + b)
+        } else {
+            Some(m)
+        }
+    }
+
+    /// Applies an arithmetic operator. Two integers give a checked integer
+    /// result, anything else involving a float gives a float.
+    fn numeric(
+        l: &Value,
+        r: &Value,
+        int_op: fn(i64, i64) -> Option<i64>,
+        float_op: fn(f64, f64) -> f64,
+    ) -> Result<Value, BeansError> {
+        match (Evaluator::as_int(l), Evaluator::as_int(r)) {
+            (Some(a), Some(b)) => int_op(a, b)
+                .map(Value::Int)
+                .ok_or_else(|| Evaluator::overflow(a, b)),
+            _ if l.is_numeric() && r.is_numeric() => {
+                Ok(Value::Num(float_op(l.as_numeric(), r.as_numeric())))
+            }
+            _ => Err(Evaluator::operand_error(l, r)),
+        }
+    }
+
+    fn ordering(l: &Value, r: &Value, test: fn(Ordering) -> bool) -> Result<Value, BeansError> {
+        let ordering = match (Evaluator::as_int(l), Evaluator::as_int(r)) {
+            (Some(a), Some(b)) => Some(a.cmp(&b)),
+            _ if l.is_numeric() && r.is_numeric() => l.as_numeric().partial_cmp(&r.as_numeric()),
+            _ => return Err(Evaluator::operand_error(l, r)),
+        };
+        Ok(Value::Bool(ordering.is_some_and(test)))
+    }
+
+    /// Shifts and bitwise operators, on two's complement integers
+    fn bit_op(l: &Value, op: super::tokens::TokenType, r: &Value) -> Result<Value, BeansError> {
+        let (a, b) = match (Evaluator::as_int(l), Evaluator::as_int(r)) {
+            (Some(a), Some(b)) => (a, b),
+            _ => {
+                return Err(BeansError::new(
+                    ErrorKind::TypeError,
+                    format!(
+                        "Bit operations need integers, got {} and {}",
+                        l.type_name(),
+                        r.type_name()
+                    ),
+                ))
+            }
+        };
+        if (op == LessLess || op == MoreMore) && b < 0 {
+            return Err(BeansError::new(
+                ErrorKind::ArithmeticError,
+                format!("Negative shift count {}", b),
+            ));
+        }
+        let result = match op {
+            Ampersand => a & b,
+            Pipe => a | b,
+            // Shifting out bits other than copies of the sign is an overflow
+            LessLess if a == 0 => 0,
+            LessLess if b >= 64 || (a << b) >> b != a => return Err(Evaluator::overflow(a, b)),
+            LessLess => a << b,
+            MoreMore => a >> b.min(63),
+            _ => unreachable!(),
+        };
+        Ok(Value::Int(result))
+    }
+
+    fn do_call(
+        &mut self,
+        fun: &Expr,
+        args: &Vec<Expr>,
+        call_site: Span,
+    ) -> Result<Value, BeansError> {
         let callable_maybe = get_value!(self.evaluate(fun));
 
         match callable_maybe {
             Value::Callable(call) => {
                 if call.arity() != args.len() as i8 && call.arity() != -1 {
-                    return Err(format!(
-                        "Arguments differ in size! Expected {}, got {}",
-                        call.arity(),
-                        args.len()
+                    return Err(BeansError::new(
+                        ErrorKind::ArityMismatch,
+                        format!(
+                            "Arguments differ in size! Expected {}, got {}",
+                            call.arity(),
+                            args.len()
+                        ),
                     ));
                 }
 
@@ -602,38 +1147,64 @@ impl Evaluator {
                     args_evaluated.push(evaluated);
                 }
 
-                Ok(call.call(self, args_evaluated))
-            }
-            _ => {
-                println!("Can't call value of type {}!", callable_maybe.stringify());
-                Ok(Value::Nil)
+                call.call(self, args_evaluated)
+                    .map_err(|why| why.push_frame(Frame::new(call.name(), call_site)))
             }
+            _ => Err(BeansError::new(
+                ErrorKind::NotCallable,
+                format!("Can't call value of type {}!", callable_maybe.stringify()),
+            )),
         }
     }
 
-    fn get(&mut self, l: &Expr, e: &Expr) -> Result<Value, String> {
-        let index = e;
+    fn get(&mut self, l: &Expr, e: &Expr) -> Result<Value, BeansError> {
         let base = get_value!(self.evaluate(l));
+        Evaluator::get_key(base, e.as_key())
+    }
+
+    fn evaluate_bound(&mut self, bound: &Option<Box<Expr>>) -> Result<Value, BeansError> {
+        match bound {
+            Some(e) => self.evaluate(e),
+            None => Ok(Value::Nil),
+        }
+    }
+
+    /// Reads `base.key` or `base[key]`, shared by both backends
+    pub(crate) fn get_key(base: Value, key: Key) -> Result<Value, BeansError> {
         match base {
             Value::Collection(map) => {
-                let id = match index {
-                    Expr::Id(s) => s,
-                    _ => return Err(format!("Collections are only indexed by strings")),
+                let id = match key {
+                    Key::Name(s) => s,
+                    _ => {
+                        return Err(BeansError::new(
+                            ErrorKind::TypeError,
+                            String::from("Collections are only indexed by strings"),
+                        ))
+                    }
                 };
                 let borrowed_map = map.borrow();
-                let value = borrowed_map.get(id);
+                let value = borrowed_map.get(&HashKey::from(id));
                 Ok(match value {
                     Some(val) => val.clone(),
                     None => Value::Nil,
                 })
             }
             Value::List(lis) => {
-                let index = match index {
-                    Expr::Num(n) => *n as usize,
-                    _ => return Err(format!("Lists are only indexed by numbers")),
+                let index = match key {
+                    Key::Index(n) => n as usize,
+                    _ => {
+                        return Err(BeansError::new(
+                            ErrorKind::TypeError,
+                            String::from("Lists are only indexed by numbers"),
+                        ))
+                    }
                 };
+                let lis = lis.borrow();
                 if index >= lis.len() {
-                    return Err(format!("Index out of bounds"));
+                    return Err(BeansError::new(
+                        ErrorKind::IndexOutOfBounds,
+                        String::from("Index out of bounds"),
+                    ));
                 }
                 Ok(match lis.get(index) {
                     Some(val) => val.clone(),
@@ -641,142 +1212,355 @@ impl Evaluator {
                 })
             }
             Value::StructInstance(inst) => {
-                let id = match index {
-                    Expr::Id(s) => s,
-                    _ => return Err(format!("Structs are only indexed by strings")),
+                let id = match key {
+                    Key::Name(s) => s,
+                    _ => {
+                        return Err(BeansError::new(
+                            ErrorKind::TypeError,
+                            String::from("Structs are only indexed by strings"),
+                        ))
+                    }
                 };
-                return Ok(match inst.get(id) {
+                return Ok(match inst.get(&String::from(id)) {
                     Some(value) => value.clone(),
                     None => Value::Nil,
                 });
             }
             Value::Enum(_, fields) => {
-                let id = match index {
-                    Expr::Id(s) => s,
-                    _ => return Err(format!("Enums are only indexed by strings")),
+                let id = match key {
+                    Key::Name(s) => s,
+                    _ => {
+                        return Err(BeansError::new(
+                            ErrorKind::TypeError,
+                            String::from("Enums are only indexed by strings"),
+                        ))
+                    }
                 };
 
                 return Ok(match fields.get(id) {
+                    Some(n) if n.fract() == 0.0 => Value::Int(*n as i64),
                     Some(n) => Value::Num(*n),
                     None => Value::Nil,
                 });
             }
+            Value::UserData(data) => {
+                let id = match key {
+                    Key::Name(s) => s,
+                    _ => {
+                        return Err(BeansError::new(
+                            ErrorKind::TypeError,
+                            String::from("Userdata is only indexed by method names"),
+                        ))
+                    }
+                };
+                let method = data.get_methods().and_then(|m| m.get_method(id)).cloned();
+                match method {
+                    Some(Value::Callable(method)) => Ok(Value::Callable(Rc::new(Box::new(
+                        BoundMethod::new(Value::UserData(data.clone()), method),
+                    )))),
+                    _ => Err(BeansError::new(
+                        ErrorKind::TypeError,
+                        format!("{} has no method {}", data.get_type_name(), id),
+                    )),
+                }
+            }
             _ => {
-                return Err(format!("Invalid get target! {}", base.stringify()));
+                return Err(BeansError::new(
+                    ErrorKind::TypeError,
+                    format!("Invalid get target! {}", base.stringify()),
+                ));
             }
         }
     }
-    fn assign(&mut self, l: &Expr, r: &Expr) -> Result<Value, String> {
+    fn assign(&mut self, l: &Expr, r: &Expr) -> Result<Value, BeansError> {
         let value = get_value!(self.evaluate(r));
-        match l {
-            Expr::Id(name) => {
+        match &l.kind {
+            ExprKind::Id(var) => {
                 let mut current_env = self.current.as_ref().borrow_mut();
-                current_env.set(name.clone(), value.clone());
+                current_env.set_variable(var, value.clone())?;
             }
             Get(expr, id) => {
                 let base = get_value!(self.evaluate(expr));
-                match base {
-                    Value::Collection(map) => {
-                        let id = match id.as_ref() {
-                            Expr::Id(s) => s,
-                            _ => return Err(format!("Collections are only indexed by strings")),
-                        };
-
-                        map.borrow_mut().insert(id.clone(), value.clone());
-                        println!();
-                    }
-                    Value::List(mut lis) => {
-                        let index = match id.as_ref() {
-                            Expr::Num(n) => *n as usize,
-                            _ => return Err(format!("Lists are only indexed by numbers")),
-                        };
-                        if index >= lis.len() {
-                            return Err(format!("Index out of bounds"));
-                        }
-                        lis.push(value.clone());
-                        let _ = lis.swap_remove(index);
+                Evaluator::set_key(base, id.as_key(), &value)?;
+            }
+            Index(expr, key) => {
+                let base = get_value!(self.evaluate(expr));
+                let key = get_value!(self.evaluate(key));
+                Evaluator::set_index(base, key, &value)?;
+            }
+            _ => {
+                return Err(BeansError::new(
+                    ErrorKind::InvalidAssignment,
+                    String::from("Invalid assign target!"),
+                ));
+            }
+        }
+        Ok(value)
+    }
+
+    /// Writes `base.key` or `base[key]`, shared by both backends
+    pub(crate) fn set_key(base: Value, key: Key, value: &Value) -> Result<(), BeansError> {
+        match base {
+            Value::Collection(map) => {
+                let id = match key {
+                    Key::Name(s) => s,
+                    _ => {
+                        return Err(BeansError::new(
+                            ErrorKind::TypeError,
+                            String::from("Collections are only indexed by strings"),
+                        ))
                     }
-                    Value::StructInstance(mut inst) => {
-                        let id = match id.as_ref() {
-                            Expr::Id(s) => s,
-                            _ => return Err(format!("Structs are only indexed by strings")),
-                        };
-                        if let Err(_) = inst.set(id, value.clone()) {
-                            return Err(format!("{} is not a member of this struct", id));
-                        }
+                };
+
+                map.borrow_mut().insert(HashKey::from(id), value.clone());
+            }
+            Value::List(lis) => {
+                let index = match key {
+                    Key::Index(n) => n as usize,
+                    _ => {
+                        return Err(BeansError::new(
+                            ErrorKind::TypeError,
+                            String::from("Lists are only indexed by numbers"),
+                        ))
                     }
+                };
+                let mut lis = lis.borrow_mut();
+                if index >= lis.len() {
+                    return Err(BeansError::new(
+                        ErrorKind::IndexOutOfBounds,
+                        String::from("Index out of bounds"),
+                    ));
+                }
+                lis[index] = value.clone();
+            }
+            Value::StructInstance(mut inst) => {
+                let id = match key {
+                    Key::Name(s) => String::from(s),
                     _ => {
-                        return Err(format!("Invalid assign target!"));
+                        return Err(BeansError::new(
+                            ErrorKind::TypeError,
+                            String::from("Structs are only indexed by strings"),
+                        ))
                     }
+                };
+                if let Err(_) = inst.set(&id, value.clone()) {
+                    return Err(BeansError::new(
+                        ErrorKind::InvalidAssignment,
+                        format!("{} is not a member of this struct", id),
+                    ));
                 }
             }
             _ => {
-                return Err(format!("Invalid assign target!"));
+                return Err(BeansError::new(
+                    ErrorKind::InvalidAssignment,
+                    String::from("Invalid assign target!"),
+                ));
             }
         }
-        Ok(value)
+        Ok(())
     }
-    fn lambda(&mut self, params: &Vec<String>, prog: Rc<Vec<Stmt>>) -> Value {
+    fn lambda(&mut self, params: &Vec<String>, prog: Rc<Body>) -> Value {
         Value::Callable(Rc::new(Box::new(Closure::new(
+            String::from("<lambda>"),
             prog,
             self.current.clone(),
             params.clone(),
         ))))
     }
 
-    fn get_value(&self, id: &String) -> Value {
+    /// Turns a possibly negative index into a position in a sequence of `len` elements
+    pub(crate) fn position(key: &Value, len: usize) -> Result<usize, BeansError> {
+        let n = match key {
+            Value::Int(i) => *i as f64,
+            Value::Num(n) if n.fract() == 0.0 => *n,
+            _ => {
+                return Err(BeansError::new(
+                    ErrorKind::TypeError,
+                    format!("Indices must be integers, got {}", key.type_name()),
+                ))
+            }
+        };
+        let index = if n < 0.0 { n + len as f64 } else { n };
+        if index < 0.0 || index >= len as f64 {
+            return Err(BeansError::new(
+                ErrorKind::IndexOutOfBounds,
+                format!("Index {} out of bounds for length {}", n, len),
+            ));
+        }
+        Ok(index as usize)
+    }
+
+    fn index_error(base: &Value, key: &Value) -> BeansError {
+        BeansError::new(
+            ErrorKind::TypeError,
+            format!("Cannot index {} with {}", base.type_name(), key.type_name()),
+        )
+    }
+
+    /// Reads `base[key]`, shared by both backends
+    pub(crate) fn index(base: Value, key: Value) -> Result<Value, BeansError> {
+        match (&base, &key) {
+            (Value::List(lis), Value::Num(_)) | (Value::List(lis), Value::Int(_)) => {
+                let lis = lis.borrow();
+                let index = Evaluator::position(&key, lis.len())?;
+                Ok(lis[index].clone())
+            }
+            (Value::Str(s), Value::Num(_)) | (Value::Str(s), Value::Int(_)) => {
+                let index = Evaluator::position(&key, s.chars().count())?;
+                Ok(Value::Str(s.chars().nth(index).unwrap().to_string()))
+            }
+            (Value::Collection(map), _) => {
+                Ok(match map.borrow().get(&HashKey::from_value(&key)?) {
+                    Some(val) => val.clone(),
+                    None => Value::Nil,
+                })
+            }
+            _ => Err(Evaluator::index_error(&base, &key)),
+        }
+    }
+
+    /// Writes `base[key]`, shared by both backends
+    pub(crate) fn set_index(base: Value, key: Value, value: &Value) -> Result<(), BeansError> {
+        match (&base, &key) {
+            (Value::List(lis), Value::Num(_)) | (Value::List(lis), Value::Int(_)) => {
+                let mut lis = lis.borrow_mut();
+                let index = Evaluator::position(&key, lis.len())?;
+                lis[index] = value.clone();
+                Ok(())
+            }
+            (Value::Collection(map), _) => {
+                map.borrow_mut()
+                    .insert(HashKey::from_value(&key)?, value.clone());
+                Ok(())
+            }
+            _ => Err(Evaluator::index_error(&base, &key)),
+        }
+    }
+
+    /// Reads `base[lo:hi]` into a new list or string, shared by both backends.
+    /// Missing bounds are nil. Like indices, negative bounds count from the
+    /// end; bounds past either end are clamped.
+    pub(crate) fn slice(base: Value, lo: Value, hi: Value) -> Result<Value, BeansError> {
+        let bound = |b: &Value, default: usize, len: usize| match b {
+            Value::Nil => Ok(default),
+            Value::Int(i) => {
+                let i = if *i < 0 { i + len as i64 } else { *i };
+                Ok(i.max(0).min(len as i64) as usize)
+            }
+            Value::Num(n) if n.fract() == 0.0 => {
+                let n = if *n < 0.0 { n + len as f64 } else { *n };
+                Ok(n.max(0.0).min(len as f64) as usize)
+            }
+            _ => Err(BeansError::new(
+                ErrorKind::TypeError,
+                format!("Slice bounds must be integers, got {}", b.type_name()),
+            )),
+        };
+        match &base {
+            Value::List(lis) => {
+                let lis = lis.borrow();
+                let (lo, hi) = (bound(&lo, 0, lis.len())?, bound(&hi, lis.len(), lis.len())?);
+                let items = if lo < hi {
+                    lis[lo..hi].to_vec()
+                } else {
+                    vec![]
+                };
+                Ok(Value::List(Rc::new(RefCell::new(items))))
+            }
+            Value::Str(s) => {
+                let len = s.chars().count();
+                let (lo, hi) = (bound(&lo, 0, len)?, bound(&hi, len, len)?);
+                Ok(Value::Str(
+                    s.chars().skip(lo).take(hi.saturating_sub(lo)).collect(),
+                ))
+            }
+            _ => Err(BeansError::new(
+                ErrorKind::TypeError,
+                format!("Cannot slice {}", base.type_name()),
+            )),
+        }
+    }
+
+    fn get_value(&self, var: &Variable) -> Value {
         let current_env = self.current.as_ref().borrow();
-        current_env.get(id).clone()
+        current_env.get_variable(var)
     }
 
-    fn make_dict(&mut self, elts: &Vec<(String, Expr)>) -> Result<Value, String> {
-        let mut v: HashMap<String, Value> = HashMap::new();
+    fn make_dict(&mut self, elts: &Vec<(Expr, Expr)>) -> Result<Value, BeansError> {
+        let mut v = Dict::new();
         for el in elts {
+            let key = HashKey::from_value(&get_value!(self.evaluate(&el.0)))?;
             let evaluated = get_value!(self.evaluate(&el.1));
-            v.insert(el.0.clone(), evaluated);
+            v.insert(key, evaluated);
         }
         Ok(Value::Collection(Rc::new(RefCell::new(v))))
     }
 
-    fn make_list(&mut self, elts: &Vec<Expr>) -> Result<Value, String> {
+    /// Joins the parts of an interpolated string, shared by both backends
+    pub(crate) fn interpolate(parts: &[Value]) -> Value {
+        Value::Str(parts.iter().map(|part| part.string_repr()).collect())
+    }
+
+    fn make_list(&mut self, elts: &Vec<Expr>) -> Result<Value, BeansError> {
         let mut v: Vec<Value> = Vec::new();
         for el in elts {
             let evaluated = get_value!(self.evaluate(&el));
             v.push(evaluated);
         }
-        Ok(Value::List(v))
+        Ok(Value::List(Rc::new(RefCell::new(v))))
     }
 }
 
-impl Evaluate<StatementResult, Result<Value, String>> for Evaluator {
+impl Evaluate<StatementResult, Result<Value, BeansError>> for Evaluator {
     fn execute_statement(&mut self, s: &Stmt) -> StatementResult {
-        match s {
-            Stmt::ExprStmt(e) => {
+        if self.backend == Backend::Bytecode {
+            return self.run_statement(Compiler::compile_statement(s));
+        }
+        let result = match &s.kind {
+            StmtKind::ExprStmt(e) => {
                 let v = self.evaluate(&e);
-                return match v {
+                match v {
                     Ok(vs) => StatementResult::Ok(vs),
                     Err(why) => StatementResult::Failure(why),
-                };
+                }
+            }
+            StmtKind::If(branches, else_block) => self.exec_if(branches, else_block),
+            StmtKind::While(label, cond, block, updater) => {
+                self.exec_while(label, cond, block, updater)
+            }
+            StmtKind::ForIn(label, vars, iterable, block) => {
+                self.exec_for_in(label, vars, iterable, block, s.span)
             }
-            Stmt::If(branches, else_block) => self.exec_if(branches, else_block),
-            Stmt::While(cond, block) => self.exec_while(cond, block),
-            Stmt::Block(stmts) => self.exec_block(stmts),
-            Stmt::Var(id, expr) => self.exec_var(id, expr),
-            Stmt::FunDef(name, params, block) => self.exec_fundef(name, params, block),
-            Stmt::StructDef(name, members) => self.exec_structdef(name, members),
-            Stmt::EnumDef(name, values) => self.exec_enumdef(name, values),
-            Stmt::Return(expr) => self.exec_return(expr),
-            Stmt::Import(module) => self.exec_import(module),
-            Stmt::Break => StatementResult::Break,
-            Stmt::Continue => StatementResult::Continue,
+            StmtKind::Block(stmts) => self.exec_scope(stmts),
+            StmtKind::Var(id, expr) => self.exec_var(id, expr),
+            StmtKind::FunDef(name, params, block) => self.exec_fundef(name, params, block),
+            StmtKind::StructDef(name, members) => self.exec_structdef(name, members),
+            StmtKind::EnumDef(name, values) => self.exec_enumdef(name, values),
+            StmtKind::Return(expr) => self.exec_return(expr),
+            StmtKind::Import(var, module) => self.exec_import(var, module),
+            StmtKind::Break(label) => StatementResult::Break(label.clone()),
+            StmtKind::Continue(label) => StatementResult::Continue(label.clone()),
+            StmtKind::Try(body, catch, finally) => self.exec_try(body, catch, finally),
+        };
+        match result {
+            StatementResult::Failure(why) => StatementResult::Failure(why.at(s.span)),
+            _ => result,
         }
     }
 
-    fn evaluate(&mut self, e: &Expr) -> Result<Value, String> {
-        match e {
-            Expr::Num(n) => Ok(Value::Num(*n)),
-            Expr::Str(s) => Ok(Value::Str(s.clone())),
-            Expr::Bool(b) => Ok(Value::Bool(*b)),
+    fn evaluate(&mut self, e: &Expr) -> Result<Value, BeansError> {
+        if self.backend == Backend::Bytecode {
+            return match self.run_statement(Compiler::compile_expression(e)) {
+                StatementResult::Failure(why) => Err(why),
+                StatementResult::Ok(v) => Ok(v),
+                _ => Ok(Value::Nil),
+            };
+        }
+        let result = match &e.kind {
+            ExprKind::Num(n) => Ok(Value::Num(*n)),
+            ExprKind::Int(i) => Ok(Value::Int(*i)),
+            ExprKind::Str(s) => Ok(Value::Str(s.clone())),
+            ExprKind::Bool(b) => Ok(Value::Bool(*b)),
 
             Unary(op, e) => match op {
                 Plus => self.evaluate(e),
@@ -788,14 +1572,38 @@ impl Evaluate<StatementResult, Result<Value, String>> for Evaluator {
             },
             Binary(l, op, r) => self.arithmetic(l, *op, r),
             Grouping(e) => self.evaluate(e),
+            // Generators run on the VM, so this is never inside one
+            ExprKind::Yield(e) => {
+                self.evaluate(e)?;
+                Err(self.yield_error())
+            }
             Id(name) => Ok(self.get_value(name)),
-            Call(exp, args) => self.do_call(exp, args),
+            Call(exp, args) => self.do_call(exp, args, e.span),
             Get(l, r) => self.get(l, r),
+            Index(l, key) => {
+                let base = self.evaluate(l)?;
+                let key = self.evaluate(key)?;
+                Evaluator::index(base, key)
+            }
+            Slice(l, lo, hi) => {
+                let base = self.evaluate(l)?;
+                let lo = self.evaluate_bound(lo)?;
+                let hi = self.evaluate_bound(hi)?;
+                Evaluator::slice(base, lo, hi)
+            }
             Assign(l, r) => self.assign(l, r),
             LambdaDef(params, stmts) => Ok(self.lambda(params, stmts.clone())),
             DictDef(elts) => self.make_dict(elts),
             ListDef(elts) => self.make_list(elts),
-            Expr::Nil => Ok(Value::Nil),
-        }
+            Interpolation(parts) => {
+                let mut values = vec![];
+                for part in parts {
+                    values.push(self.evaluate(part)?);
+                }
+                Ok(Evaluator::interpolate(&values))
+            }
+            ExprKind::Nil => Ok(Value::Nil),
+        };
+        result.map_err(|why| why.at(e.span))
     }
 }
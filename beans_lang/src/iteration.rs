@@ -0,0 +1,168 @@
+use super::coroutine::{Coroutine, Resumed};
+use super::environments::*;
+use super::errors::*;
+use super::evaluator::Evaluator;
+use super::span::Span;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The state of a `for ... in` loop, shared by both backends.
+///
+/// Lists, strings and dicts are iterated directly. Anything else goes
+/// through the iterator protocol: a `next` function, called with no
+/// arguments before each iteration, returns the next item or nil once it is
+/// done. Callables are used as `next` functions themselves, and struct
+/// instances are iterable through an `iter` field returning one.
+/// Coroutines are resumed for each item until they return, and generator
+/// functions are called once to get the coroutine to iterate.
+pub(crate) struct Iteration {
+    source: Source,
+    // Loops with two variables get an index or key along with each item
+    pairs: bool,
+    position: usize,
+}
+
+enum Source {
+    // Read live, so elements appended by the loop body are visited
+    List(Rc<RefCell<Vec<Value>>>),
+    Chars(Vec<char>),
+    // Entries of the dict when the loop started
+    Entries(Vec<(Value, Value)>),
+    Next(Rc<Box<dyn Call>>),
+    Coroutine(UserData),
+}
+
+fn not_iterable(v: &Value) -> BeansError {
+    BeansError::new(
+        ErrorKind::TypeError,
+        format!("Values of type {} are not iterable", v.type_name()),
+    )
+}
+
+fn next_function(v: Value) -> Result<Rc<Box<dyn Call>>, BeansError> {
+    match v {
+        Value::Callable(next) if next.arity() == 0 || next.arity() == -1 => Ok(next),
+        Value::Callable(next) => Err(BeansError::new(
+            ErrorKind::ArityMismatch,
+            format!(
+                "Iterator functions take no arguments, {} takes {}",
+                next.name(),
+                next.arity()
+            ),
+        )),
+        v => Err(BeansError::new(
+            ErrorKind::TypeError,
+            format!("Expected an iterator function, got {}", v.type_name()),
+        )),
+    }
+}
+
+impl Iteration {
+    pub(crate) fn new(
+        eval: &mut Evaluator,
+        iterable: Value,
+        pairs: bool,
+    ) -> Result<Iteration, BeansError> {
+        let source = match iterable {
+            Value::List(items) => Source::List(items),
+            Value::Str(s) => Source::Chars(s.chars().collect()),
+            Value::Collection(dict) => Source::Entries(
+                dict.borrow()
+                    .iter()
+                    .map(|(k, v)| (k.to_value(), v.clone()))
+                    .collect(),
+            ),
+            Value::Callable(ref f) if f.as_closure().is_some_and(Closure::is_generator) => {
+                match next_function(iterable)?.call(eval, vec![])? {
+                    Value::UserData(data) => Source::Coroutine(data),
+                    v => return Err(not_iterable(&v)),
+                }
+            }
+            Value::Callable(_) => Source::Next(next_function(iterable)?),
+            Value::UserData(data) if data.is::<Coroutine>() => Source::Coroutine(data),
+            Value::StructInstance(ref inst) => match inst.get(&String::from("iter")) {
+                Some(Value::Callable(iter)) => {
+                    let iter = iter.clone();
+                    Source::Next(next_function(iter.call(eval, vec![])?)?)
+                }
+                _ => return Err(not_iterable(&iterable)),
+            },
+            _ => return Err(not_iterable(&iterable)),
+        };
+        Ok(Iteration {
+            source,
+            pairs,
+            position: 0,
+        })
+    }
+
+    pub(crate) fn is_pairs(&self) -> bool {
+        self.pairs
+    }
+
+    // Splits what an iterator produced into the loop variables
+    fn produced(&self, item: Value) -> Result<(Value, Value), BeansError> {
+        match item {
+            item if !self.pairs => Ok((item, Value::Nil)),
+            Value::List(ref pair) if pair.borrow().len() == 2 => {
+                let pair = pair.borrow();
+                Ok((pair[0].clone(), pair[1].clone()))
+            }
+            item => Err(BeansError::new(
+                ErrorKind::TypeError,
+                format!(
+                    "Loops with two variables need iterators returning [key, value] lists, got {}",
+                    item.type_name()
+                ),
+            )),
+        }
+    }
+
+    /// The values of the loop variables for the next iteration, the second
+    /// one being nil for loops with a single variable
+    pub(crate) fn next(
+        &mut self,
+        eval: &mut Evaluator,
+        span: Span,
+    ) -> Result<Option<(Value, Value)>, BeansError> {
+        let index = Value::Int(self.position as i64);
+        let item = match &self.source {
+            Source::List(items) => items.borrow().get(self.position).cloned(),
+            Source::Chars(chars) => chars.get(self.position).map(|c| Value::Str(c.to_string())),
+            Source::Entries(entries) => {
+                let entry = entries.get(self.position).cloned();
+                self.position += 1;
+                return Ok(entry.map(|(k, v)| if self.pairs { (k, v) } else { (k, Value::Nil) }));
+            }
+            Source::Next(next) => {
+                let item = next
+                    .call(eval, vec![])
+                    .map_err(|why| why.push_frame(Frame::new(next.name(), span)))?;
+                return match item {
+                    Value::Nil => Ok(None),
+                    item => self.produced(item).map(Some),
+                };
+            }
+            Source::Coroutine(data) => {
+                if Coroutine::is_dead(data) {
+                    return Ok(None);
+                }
+                let data = data.clone();
+                let resumed = Coroutine::resume(eval, &data, Value::Nil)
+                    .map_err(|why| why.push_frame(Frame::new(String::from("coroutine"), span)))?;
+                return match resumed {
+                    Resumed::Yielded(item) => self.produced(item).map(Some),
+                    Resumed::Returned(_) => Ok(None),
+                };
+            }
+        };
+        self.position += 1;
+        Ok(item.map(|item| {
+            if self.pairs {
+                (index, item)
+            } else {
+                (item, Value::Nil)
+            }
+        }))
+    }
+}
@@ -2,24 +2,18 @@ use super::tokens::TokenType::*;
 use super::tokens::TOKENS_MAP as TokenMap;
 use super::tokens::*;
 
+use super::errors::Diagnostic;
 use super::reader::Reader;
-
-macro_rules! peek_char {
-    ($c : expr) => {
-        if let Some(c) = $c.peek() {
-            c
-        } else {
-            panic!("No char to peek at!");
-        }
-    };
-}
+use super::span::*;
+use std::collections::HashSet;
+use std::rc::Rc;
 
 macro_rules! match_next {
     ( $c: expr, $f : expr, $ci: expr => $ti: expr, $( $cs: expr => $ts : expr ),*) => { {
-        match peek_char!($c) {
-                    $ci => {
+        match $c.peek() {
+                    Some($ci) => {
                         $c.next().unwrap(); $ti },
-                    $($cs => {$c.next().unwrap();$ts},)*
+                    $(Some($cs) => {$c.next().unwrap();$ts},)*
                     _ => { $f }
             }
     }};
@@ -55,94 +49,317 @@ mod tests {
             end",
         );
         let mut lexer = Lexer::new(test);
-        lexer.do_lex();
         assert!(!lexer.is_at_end());
         assert!(lexer.peek().unwrap().get_type() == Function);
-        assert!(lexer.next().unwrap().get_type() == Function);
-        assert!(lexer.next().unwrap().get_type() == Identifier);
-        assert!(lexer.next().unwrap().get_type() == LeftParen);
-        assert!(lexer.next().unwrap().get_type() == Identifier);
-        assert!(lexer.next().unwrap().get_type() == Comma);
-        assert!(lexer.next().unwrap().get_type() == Identifier);
-        assert!(lexer.next().unwrap().get_type() == Comma);
-        assert!(lexer.next().unwrap().get_type() == Identifier);
-        assert!(lexer.next().unwrap().get_type() == RightParen);
-        assert!(lexer.next().unwrap().get_type() == Return);
-        assert!(lexer.next().unwrap().get_type() == Identifier);
+        assert!(lexer.advance().unwrap().get_type() == Function);
+        assert!(lexer.advance().unwrap().get_type() == Identifier);
+        assert!(lexer.advance().unwrap().get_type() == LeftParen);
+        assert!(lexer.advance().unwrap().get_type() == Identifier);
+        assert!(lexer.advance().unwrap().get_type() == Comma);
+        assert!(lexer.advance().unwrap().get_type() == Identifier);
+        assert!(lexer.advance().unwrap().get_type() == Comma);
+        assert!(lexer.advance().unwrap().get_type() == Identifier);
+        assert!(lexer.advance().unwrap().get_type() == RightParen);
+        assert!(lexer.advance().unwrap().get_type() == Return);
+        assert!(lexer.advance().unwrap().get_type() == Identifier);
         assert!(lexer.prev().unwrap().get_type() == Identifier);
         assert!(lexer.peek().unwrap().get_type() == Plus);
-        assert!(lexer.next().unwrap().get_type() == Plus);
+        assert!(lexer.advance().unwrap().get_type() == Plus);
         assert!(!lexer.is_at_end());
-        assert!(lexer.next().unwrap().get_type() == Identifier);
-        assert!(lexer.next().unwrap().get_type() == Minus);
-        assert!(lexer.next().unwrap().get_type() == Identifier);
-        assert!(lexer.next().unwrap().get_type() == Star);
-        assert!(lexer.next().unwrap().get_type() == Num);
-        assert!(lexer.next().unwrap().get_type() == LessLess);
-        assert!(lexer.next().unwrap().get_type() == Num);
-        assert!(lexer.next().unwrap().get_type() == Semicolon);
+        assert!(lexer.advance().unwrap().get_type() == Identifier);
+        assert!(lexer.advance().unwrap().get_type() == Minus);
+        assert!(lexer.advance().unwrap().get_type() == Identifier);
+        assert!(lexer.advance().unwrap().get_type() == Star);
+        assert!(lexer.advance().unwrap().get_type() == Num);
+        assert!(lexer.advance().unwrap().get_type() == LessLess);
+        assert!(lexer.advance().unwrap().get_type() == Int);
+        assert!(lexer.advance().unwrap().get_type() == Semicolon);
         assert!(lexer.prev().unwrap().get_type() == Semicolon);
         assert!(lexer.peek().unwrap().get_type() == End);
-        assert!(lexer.next().unwrap().get_type() == End);
+        assert!(lexer.advance().unwrap().get_type() == End);
 
         assert!(lexer.is_at_end());
     }
+
+    #[test]
+    fn test_token_spans() {
+        let test = String::from("var x = 1;\n  x = \"è\" + x;");
+        let mut lexer = Lexer::new(test);
+        let span = lexer.advance().unwrap().get_span();
+        assert!(span.line == 1 && span.column == 1);
+        assert!(span.start == 0 && span.end == 3);
+        for _ in 0..4 {
+            lexer.advance();
+        }
+        let span = lexer.advance().unwrap().get_span();
+        assert!(span.line == 2 && span.column == 3);
+        assert!(span.start == 13 && span.end == 14);
+        lexer.advance();
+        let span = lexer.advance().unwrap().get_span();
+        assert!(span.column == 7);
+        assert!(span.start == 17 && span.end == 21);
+        let span = lexer.advance().unwrap().get_span();
+        assert!(span.column == 11 && span.start == 22);
+    }
+
+    #[test]
+    fn test_many_lines() {
+        // Lines past u16::MAX must keep their number, and lexing must stay linear
+        let test = "x;\n".repeat(70000) + "last";
+        let tokens: Vec<Token> = Scanner::new(test, 0).collect();
+        assert!(tokens.len() == 140001);
+        let last = tokens.last().unwrap();
+        assert!(last.get_line() == 70001 && last.as_Id() == "last");
+    }
+
+    #[test]
+    fn test_lazy_tokens() {
+        // Tokens are produced on demand, errors show up as they are reached
+        let mut scanner = Scanner::new(String::from("a b $"), 0);
+        assert!(scanner.next().unwrap().get_type() == Identifier);
+        assert!(!scanner.had_error());
+        assert!(scanner.next().unwrap().get_type() == Identifier);
+        assert!(scanner.next().is_none());
+        assert!(scanner.had_error());
+
+        let tokens: Vec<Token> = Scanner::new(String::from("x x"), 0).collect();
+        match (tokens[0].get_val(), tokens[1].get_val()) {
+            (Value::Id(a), Value::Id(b)) => assert!(Rc::ptr_eq(a, b)),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_strings() {
+        let source = r#"'a\tb' r"\n{x}" """say "hi"
+" """ "\u{1F600}\x41\{\}" """""""#;
+        let tokens: Vec<Token> = Scanner::new(String::from(source), 0).collect();
+        let strings: Vec<String> = tokens.iter().map(|t| t.as_String()).collect();
+        assert!(
+            strings == ["a\tb", "\\n{x}", "say \"hi\"\n\" ", "😀A{}", ""],
+            "{:?}",
+            strings
+        );
+
+        let tokens: Vec<Token> = Scanner::new(String::from(r#""a{x}b{ {c: 1}.c }""#), 0).collect();
+        let types: Vec<TokenType> = tokens.iter().map(|t| t.get_type()).collect();
+        assert!(
+            types
+                == [
+                    InterpolationStart,
+                    Identifier,
+                    InterpolationMid,
+                    LeftBrace,
+                    Identifier,
+                    Colon,
+                    Int,
+                    RightBrace,
+                    Dot,
+                    Identifier,
+                    InterpolationEnd
+                ]
+        );
+        assert!(tokens[0].as_String() == "a" && tokens[2].as_String() == "b");
+
+        for (source, message) in [
+            (r#""\q""#, "Unknown escape sequence \\q"),
+            (r#""\x4""#, "Expected two hex digits after \\x"),
+            (r#""\u{110000}""#, "Invalid unicode code point 110000"),
+            (
+                r#""\u41""#,
+                "Expected 1 to 6 hex digits in braces after \\u, like \\u{1F600}",
+            ),
+            (r#""{x"#, "Unterminated string interpolation"),
+        ]
+        .iter()
+        {
+            let mut scanner = Scanner::new(String::from(*source), 0);
+            while scanner.next().is_some() {}
+            let diagnostics = scanner.take_diagnostics();
+            assert!(
+                diagnostics.len() == 1 && diagnostics[0].message == *message,
+                "{:?}",
+                diagnostics
+            );
+        }
+    }
+
+    #[test]
+    fn test_numbers() {
+        let tokens: Vec<Token> =
+            Scanner::new(String::from("12 1.5 9223372036854775807 a//b"), 0).collect();
+        assert!(tokens[0].get_type() == Int && tokens[0].as_i64() == 12);
+        assert!(tokens[1].get_type() == Num && tokens[1].as_f64() == 1.5);
+        assert!(tokens[2].as_i64() == i64::MAX);
+        assert!(tokens[4].get_type() == SlashSlash);
+
+        let source = "1e-9 2.5E+3 1e3 0xFF 0b1010 0o755 1_000_000 0x_ff_ff 2.718_5 x-1";
+        let tokens: Vec<Token> = Scanner::new(String::from(source), 0).collect();
+        assert!(tokens[0].as_f64() == 1e-9 && tokens[1].as_f64() == 2500.0);
+        assert!(tokens[2].get_type() == Num && tokens[2].as_f64() == 1000.0);
+        let ints: Vec<i64> = tokens[3..7].iter().map(|t| t.as_i64()).collect();
+        assert!(ints == [255, 10, 493, 1_000_000]);
+        assert!(tokens[8].as_f64() == 2.718_5);
+        // A sign outside of an exponent is an operator
+        assert!(tokens[10].get_type() == Minus && tokens[11].as_i64() == 1);
+
+        // Keywords can follow a decimal literal without a space
+        let tokens: Vec<Token> = Scanner::new(String::from("0then 2else 3end 4ex"), 0).collect();
+        let types: Vec<TokenType> = tokens.iter().map(|t| t.get_type()).collect();
+        assert!(types == [Int, Then, Int, Else, Int, End, Int, Identifier]);
+    }
+
+    #[test]
+    fn test_number_errors() {
+        let cases = [
+            ("1.2.3", "Too many decimal points in number literal 1.2.3"),
+            ("0xfg", "Invalid digit 'g' in hexadecimal literal 0xfg"),
+            ("0b102", "Invalid digit '2' in binary literal 0b102"),
+            ("0o", "Missing digits in octal literal 0o"),
+            ("1__0", "Misplaced '_' in number literal 1__0"),
+            ("10_", "Misplaced '_' in number literal 10_"),
+            ("1e+", "Missing digits in the exponent of 1e+"),
+            ("1e", "Missing digits in the exponent of 1e"),
+            ("2.5E", "Missing digits in the exponent of 2.5E"),
+            ("3.", "Missing digits after the decimal point in 3."),
+            (
+                "9223372036854775808",
+                "Integer literal too large: 9223372036854775808",
+            ),
+            (
+                "0x1_0000_0000_0000_0000",
+                "Integer literal too large: 0x1_0000_0000_0000_0000",
+            ),
+        ];
+        for (source, message) in cases.iter() {
+            let mut scanner = Scanner::new(format!("x = {};", source), 0);
+            let tokens: Vec<Token> = (&mut scanner).collect();
+            // The bad literal is a single token followed by the rest of the line
+            assert!(tokens.len() == 4, "{}", source);
+            let diagnostics = scanner.take_diagnostics();
+            assert!(
+                diagnostics.len() == 1 && diagnostics[0].message == *message,
+                "{:?}",
+                diagnostics
+            );
+            let span = diagnostics[0].span;
+            assert!(span.start == 4 && span.end == 4 + source.len());
+        }
+    }
 }
 
-pub struct Lexer {
-    tokens: Vec<Token>,
+/// Produces the tokens of a source text one at a time
+pub struct Scanner {
     input_text: Reader,
-    line: u16,
+    file: FileId,
+    // Identifier text, shared between tokens naming the same thing
+    names: HashSet<Rc<str>>,
+    // Strings whose `{expression}` is being scanned, innermost last
+    interpolations: Vec<Interpolation>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// How a string literal is delimited
+#[derive(Clone, Copy)]
+struct Quote {
+    ch: char,
+    // Delimited by three quotes, so single quotes don't end it
+    triple: bool,
+    // No escapes or interpolation
+    raw: bool,
+}
+
+/// A string literal waiting for the end of one of its `{expression}` parts
+struct Interpolation {
+    quote: Quote,
+    // Braces opened by the expression and not closed yet
+    braces: usize,
+}
+
+/// Token cursor used by the parser, with one token of lookahead.
+/// Tokens are scanned as the parser consumes them.
+pub struct Lexer {
+    scanner: Scanner,
+    prev: Option<Token>,
+    peeked: Option<Token>,
     cur_tok: usize,
-    had_error: bool,
 }
 
 impl Lexer {
     pub fn new(input_text: String) -> Lexer {
+        Lexer::new_with_file(input_text, 0)
+    }
+
+    pub fn new_with_file(input_text: String, file: FileId) -> Lexer {
+        let mut scanner = Scanner::new(input_text, file);
+        let peeked = scanner.next();
         Lexer {
-            tokens: vec![],
-            input_text: Reader::new(input_text),
-            line: 1,
+            scanner,
+            prev: None,
+            peeked,
             cur_tok: 0,
-            had_error: false,
         }
     }
 
     pub fn had_error(&self) -> bool {
-        self.had_error
+        self.scanner.had_error()
     }
 
-    pub fn get_line(&self) -> u16 {
-        self.line
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        self.scanner.take_diagnostics()
     }
 
-    pub fn next(&mut self) -> Option<&Token> {
+    pub fn get_line(&self) -> usize {
+        self.scanner.input_text.line()
+    }
+
+    /// Index of the next token, useful to tell whether any got consumed
+    pub fn get_pos(&self) -> usize {
+        self.cur_tok
+    }
+
+    /// Moves to the next token, giving the one moved past
+    pub fn advance(&mut self) -> Option<&Token> {
+        if self.is_at_end() {
+            return None;
+        }
         self.cur_tok += 1;
-        return self.tokens.get(self.cur_tok - 1);
+        let next = self.scanner.next();
+        self.prev = std::mem::replace(&mut self.peeked, next);
+        self.prev.as_ref()
     }
 
     pub fn peek(&self) -> Option<&Token> {
-        return self.tokens.get(self.cur_tok);
+        self.peeked.as_ref()
     }
 
     pub fn prev(&self) -> Option<&Token> {
-        if self.cur_tok == 0 {
-            return None;
-        }
-        return self.tokens.get(self.cur_tok - 1);
+        self.prev.as_ref()
     }
 
-    pub fn do_lex(&mut self) {
-        while let Some(c) = self.input_text.next() {
-            if self.had_error {
-                break;
-            }
-            match c {
-                '\t' | ' ' => {
-                    continue;
+    pub fn is_at_end(&self) -> bool {
+        self.peeked.is_none()
+    }
+}
+
+impl Iterator for Scanner {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        loop {
+            let start = self.here();
+            let c = match self.input_text.next() {
+                Some(c) => c,
+                None => {
+                    if !self.interpolations.is_empty() {
+                        self.interpolations.clear();
+                        self.error(String::from("Unterminated string interpolation"), start);
+                    }
+                    return None;
                 }
-                '\n' => {
-                    self.line += 1;
+            };
+            match c {
+                '\t' | ' ' | '\r' | '\n' => {
                     continue;
                 }
                 _ => {}
@@ -151,7 +368,10 @@ impl Lexer {
                 '+' => Some(Plus),
                 '-' => Some(Minus),
                 '*' => Some(Star),
-                '/' => Some(Slash),
+                '/' => Some(match_next! { self.input_text,
+                    Slash,
+                    '/' => SlashSlash,
+                }),
                 '%' => Some(Mod),
                 '#' => {
                     self.comment();
@@ -159,8 +379,24 @@ impl Lexer {
                 }
                 '(' => Some(LeftParen),
                 ')' => Some(RightParen),
-                '{' => Some(LeftBrace),
-                '}' => Some(RightBrace),
+                '{' => {
+                    if let Some(interpolation) = self.interpolations.last_mut() {
+                        interpolation.braces += 1;
+                    }
+                    Some(LeftBrace)
+                }
+                '}' => match self.interpolations.last_mut() {
+                    // Back to the string after an interpolated expression
+                    Some(interpolation) if interpolation.braces == 0 => {
+                        let quote = interpolation.quote;
+                        return Some(self.string_token(quote, start, false));
+                    }
+                    Some(interpolation) => {
+                        interpolation.braces -= 1;
+                        Some(RightBrace)
+                    }
+                    None => Some(RightBrace),
+                },
                 '<' => Some(match_next! { self.input_text,
                     Less,
                     '<' => LessLess,
@@ -183,54 +419,91 @@ impl Lexer {
                 '.' => Some(Dot),
                 ';' => Some(Semicolon),
                 ':' => Some(Colon),
+                '&' => Some(Ampersand),
+                '|' => Some(Pipe),
                 '[' => Some(LeftSquare),
                 ']' => Some(RightSquare),
                 _ => None,
             };
 
             if let Some(tok) = token {
-                self.tokens.push(Token::new(tok, self.line, Value::Empty));
-                continue;
+                return Some(Token::new(tok, self.span_from(start), Value::Empty));
             }
 
-            let string = match c {
-                '"' | '\'' => Some(self.string(c)),
-                _ => None,
-            };
-            if let Some(s) = string {
-                self.tokens.push(Token::new(Str, self.line, Value::Str(s)));
-                continue;
+            if c == '"' || c == '\'' {
+                let quote = self.quote(c, false);
+                return Some(self.string_token(quote, start, true));
+            }
+
+            if c == 'r' {
+                if let Some(q) = self.input_text.peek().filter(|q| *q == '"' || *q == '\'') {
+                    self.input_text.next();
+                    let quote = self.quote(q, true);
+                    return Some(self.string_token(quote, start, true));
+                }
             }
 
             if c.is_digit(10) {
-                let n = self.num();
-                self.tokens.push(Token::new(Num, self.line, Value::Num(n)));
-                continue;
+                let (tok, n) = self.num(start);
+                return Some(Token::new(tok, self.span_from(start), n));
             }
 
-            let id = self.id();
+            let id = self.id(start);
             if c.is_alphabetic() {
-                if TokenMap.contains_key(&id) {
-                    self.tokens.push(Token::new(
-                        *TokenMap.get(&id).unwrap(),
-                        self.line,
-                        Value::Id(id),
-                    ));
-                } else {
-                    self.tokens
-                        .push(Token::new(Identifier, self.line, Value::Id(id)))
-                }
-                continue;
+                let tok = match TokenMap.get(&*id) {
+                    Some(keyword) => *keyword,
+                    None => Identifier,
+                };
+                return Some(Token::new(tok, self.span_from(start), Value::Id(id)));
             }
 
-            println!("Unrecognized token at line {}: {}", self.line, id);
-            self.had_error = true;
+            self.error(format!("Unrecognized token: {}", id), start);
         }
     }
+}
 
-    pub fn is_at_end(&self) -> bool {
-        self.cur_tok == self.tokens.len()
+impl Scanner {
+    pub fn new(input_text: String, file: FileId) -> Scanner {
+        Scanner {
+            input_text: Reader::new(input_text),
+            file,
+            names: HashSet::new(),
+            interpolations: vec![],
+            diagnostics: vec![],
+        }
+    }
+
+    pub fn had_error(&self) -> bool {
+        !self.diagnostics.is_empty()
+    }
+
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+
+    fn error(&mut self, message: String, start: Span) {
+        let span = self.span_from(start);
+        self.diagnostics.push(Diagnostic::new(message, span));
+    }
+
+    fn here(&self) -> Span {
+        let offset = self.input_text.offset();
+        Span::new(
+            self.file,
+            offset,
+            offset,
+            self.input_text.line(),
+            self.input_text.column(),
+        )
     }
+
+    fn span_from(&self, start: Span) -> Span {
+        Span {
+            end: self.input_text.offset(),
+            ..start
+        }
+    }
+
     fn comment(&mut self) {
         while let Some(c) = self.input_text.peek() {
             if c == '\n' {
@@ -241,93 +514,295 @@ impl Lexer {
         }
     }
 
-    fn string(&mut self, end: char) -> String {
+    /// Reads the rest of an opening quote, which may be tripled
+    fn quote(&mut self, ch: char, raw: bool) -> Quote {
+        let triple =
+            self.input_text.peek() == Some(ch) && self.input_text.peek_second() == Some(ch);
+        if triple {
+            self.input_text.next();
+            self.input_text.next();
+        }
+        Quote { ch, triple, raw }
+    }
+
+    /// Reads a piece of string literal. A piece that ends with the opening
+    /// brace of an interpolated expression starts or continues an
+    /// interpolation, one that ends with the closing quote is a whole string
+    /// or the end of an interpolation.
+    fn string_token(&mut self, quote: Quote, start: Span, first: bool) -> Token {
+        let (s, open) = self.string(quote, start);
+        let tok = match (first, open) {
+            (true, false) => Str,
+            (true, true) => {
+                self.interpolations.push(Interpolation { quote, braces: 0 });
+                InterpolationStart
+            }
+            (false, true) => InterpolationMid,
+            (false, false) => {
+                self.interpolations.pop();
+                InterpolationEnd
+            }
+        };
+        Token::new(tok, self.span_from(start), Value::Str(s))
+    }
+
+    /// Reads string contents up to the closing quote, or up to the brace
+    /// opening an interpolated expression in which case the flag is set
+    fn string(&mut self, quote: Quote, start: Span) -> (String, bool) {
         let mut s = String::new();
-        while let Some(c) = self.input_text.next() {
-            if c == end {
-                break;
+        loop {
+            let here = self.here();
+            let c = match self.input_text.next() {
+                Some(c) => c,
+                None => {
+                    self.error(String::from("Unterminated string"), start);
+                    break;
+                }
+            };
+            if c == quote.ch {
+                if !quote.triple {
+                    break;
+                }
+                if self.input_text.peek() == Some(c) && self.input_text.peek_second() == Some(c) {
+                    self.input_text.next();
+                    self.input_text.next();
+                    break;
+                }
+                s.push(c);
+            } else if quote.raw {
+                s.push(c);
+            } else if c == '{' {
+                return (s, true);
+            } else if c == '\\' {
+                if let Some(escaped) = self.escape(here) {
+                    s.push(escaped);
+                }
+            } else {
+                s.push(c);
             }
-            if c == '\n' {
-                self.line += 1;
+        }
+        (s, false)
+    }
+
+    /// Reads an escape sequence after its backslash
+    fn escape(&mut self, start: Span) -> Option<char> {
+        let c = match self.input_text.next() {
+            Some(c) => c,
+            None => {
+                self.error(String::from("Unterminated escape sequence"), start);
+                return None;
             }
-            if c == '\\' {
-                let next = self.input_text.next();
-                let next = match next {
+        };
+        let escaped = match c {
+            'a' => '\x07',
+            'b' => '\x08',
+            'e' => '\x1B',
+            'f' => '\x0C',
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'v' => '\x0B',
+            '0' => '\0',
+            '\\' | '\'' | '"' | '?' | '{' | '}' => c,
+            'x' => {
+                let digits = self.hex_digits(2);
+                if digits.len() != 2 {
+                    self.error(String::from("Expected two hex digits after \\x"), start);
+                    return None;
+                }
+                char::from(u8::from_str_radix(&digits, 16).unwrap())
+            }
+            'u' => {
+                let digits = if self.input_text.peek() == Some('{') {
+                    self.input_text.next();
+                    self.hex_digits(6)
+                } else {
+                    String::new()
+                };
+                if digits.is_empty() || self.input_text.peek() != Some('}') {
+                    let message = String::from(
+                        "Expected 1 to 6 hex digits in braces after \\u, like \\u{1F600}",
+                    );
+                    self.error(message, start);
+                    return None;
+                }
+                self.input_text.next();
+                match std::char::from_u32(u32::from_str_radix(&digits, 16).unwrap()) {
                     Some(c) => c,
                     None => {
-                        println!(
-                            "Got EOF while parsing escape sequence at line {}!",
-                            self.line
-                        );
-                        ' '
-                    }
-                };
-
-                let escape_code: u8 = match next {
-                    'a' => 0x07,  // \a
-                    'b' => 0x08,  // \b
-                    'e' => 0x1B,  // \e
-                    'f' => 0x0C,  // \f
-                    'n' => 0x0A,  // \n
-                    'r' => 0x0D,  // \r
-                    't' => 0x09,  // \t
-                    'v' => 0x0B,  // \v
-                    '\\' => 0x5C, // \
-                    '\'' => 0x27, // '
-                    '"' => 0x22,  // "
-                    '?' => 0x3F,  // ?,
-                    _ => {
-                        println!(
-                            "Unrecognised escape sequence at line {}: {}",
-                            self.line,
-                            format!("{}{}", c, next)
-                        );
-                        0x00
+                        let message = format!("Invalid unicode code point {}", digits);
+                        self.error(message, start);
+                        return None;
                     }
-                };
-                if escape_code != 0x00 {
-                    s.push(escape_code as char)
                 }
-            } else {
-                s.push(c);
             }
+            _ => {
+                self.error(format!("Unknown escape sequence \\{}", c), start);
+                return None;
+            }
+        };
+        Some(escaped)
+    }
+
+    fn hex_digits(&mut self, max: usize) -> String {
+        let mut digits = String::new();
+        while let Some(c) = self.input_text.peek().filter(|c| c.is_ascii_hexdigit()) {
+            if digits.len() == max {
+                break;
+            }
+            digits.push(c);
+            self.input_text.next();
         }
-        s
+        digits
     }
 
-    fn num(&mut self) -> f64 {
-        let prev = self.input_text.prev().unwrap();
-        let mut s = String::new();
-        s.push(prev);
+    /// Reads a numeric literal. Radix literals take every letter glued to
+    /// them so that mistakes like `0xfg` are reported as one bad literal,
+    /// while decimal ones stop at letters that can't be an exponent so that
+    /// `0then` still lexes as `0 then`. An `e` ending the literal is taken as
+    /// an exponent missing its digits.
+    fn num(&mut self, start: Span) -> (TokenType, Value) {
+        let first = self.input_text.slice(start.start, self.input_text.offset());
+        let radix = first == "0" && self.input_text.peek().is_some_and(|c| "xXbBoO".contains(c));
+        let mut prev = ' ';
         while let Some(c) = self.input_text.peek() {
-            if !(c.is_numeric() || c == '.') {
+            let exponent = (c == 'e' || c == 'E')
+                && self
+                    .input_text
+                    .peek_second()
+                    .is_none_or(|n| !(n.is_alphabetic() || n == '_'));
+            let exponent_sign = (c == '+' || c == '-') && (prev == 'e' || prev == 'E');
+            let accepted = if radix {
+                c.is_alphanumeric() || c == '_'
+            } else {
+                c.is_ascii_digit() || c == '_' || c == '.' || exponent || exponent_sign
+            };
+            if !accepted {
                 break;
             }
+            prev = c;
             self.input_text.next();
-            s.push(c);
         }
-        if let Ok(n) = str::parse::<f64>(s.as_ref()) {
-            return n;
-        } else {
-            println!(
-                "Faliure parsing number, at line {}! Got {}",
-                self.line, s
-            );
-            self.had_error = true;
-            0.0
+        let text = self.input_text.slice(start.start, self.input_text.offset());
+        match parse_number(text) {
+            Ok(number) => number,
+            Err(message) => {
+                self.error(message, start);
+                (Num, Value::Num(0.0))
+            }
         }
     }
 
-    fn id(&mut self) -> String {
-        let prev = self.input_text.prev().unwrap();
-        let mut s = char::to_string(&prev);
+    fn id(&mut self, start: Span) -> Rc<str> {
         while let Some(c) = self.input_text.peek() {
             if !(c.is_alphanumeric() || c == '_') {
                 break;
             }
             self.input_text.next();
-            s.push(c);
         }
-        s
+        let text = self.input_text.slice(start.start, self.input_text.offset());
+        match self.names.get(text) {
+            Some(name) => name.clone(),
+            None => {
+                let name: Rc<str> = Rc::from(text);
+                self.names.insert(name.clone());
+                name
+            }
+        }
+    }
+}
+
+/// Radix and name of a literal starting with `0x`, `0b` or `0o`
+fn radix_prefix(text: &str) -> Option<(u32, &'static str)> {
+    match text.get(..2) {
+        Some("0x") | Some("0X") => Some((16, "hexadecimal")),
+        Some("0b") | Some("0B") => Some((2, "binary")),
+        Some("0o") | Some("0O") => Some((8, "octal")),
+        _ => None,
+    }
+}
+
+/// Parses the text of a numeric literal, or explains what is wrong with it.
+/// Literals without a decimal point or an exponent are integers.
+pub(crate) fn parse_number(text: &str) -> Result<(TokenType, Value), String> {
+    let (radix, digits) = match radix_prefix(text) {
+        Some((radix, _)) => (radix, &text[2..]),
+        None => (10, text),
+    };
+    // Separators go between two digits
+    let chars: Vec<char> = digits.chars().collect();
+    for (i, c) in chars.iter().enumerate() {
+        if *c == '_'
+            && (i == 0
+                || i + 1 == chars.len()
+                || !chars[i - 1].is_digit(radix)
+                || !chars[i + 1].is_digit(radix))
+        {
+            return Err(format!("Misplaced '_' in number literal {}", text));
+        }
+    }
+    let digits: String = digits.chars().filter(|c| *c != '_').collect();
+    let too_large = || format!("Integer literal too large: {}", text);
+
+    if let Some((radix, name)) = radix_prefix(text) {
+        if digits.is_empty() {
+            return Err(format!("Missing digits in {} literal {}", name, text));
+        }
+        if let Some(c) = digits.chars().find(|c| !c.is_digit(radix)) {
+            return Err(format!(
+                "Invalid digit '{}' in {} literal {}",
+                c, name, text
+            ));
+        }
+        return i64::from_str_radix(&digits, radix)
+            .map(|n| (Int, Value::Int(n)))
+            .map_err(|_| too_large());
+    }
+
+    let (mantissa, exponent) = match digits.find(|c| c == 'e' || c == 'E') {
+        Some(i) => (&digits[..i], Some(&digits[i + 1..])),
+        None => (&digits[..], None),
+    };
+    if let Some(c) = mantissa
+        .chars()
+        .find(|c| !(c.is_ascii_digit() || *c == '.'))
+    {
+        return Err(format!(
+            "Invalid character '{}' in number literal {}",
+            c, text
+        ));
+    }
+    if mantissa.matches('.').count() > 1 {
+        return Err(format!(
+            "Too many decimal points in number literal {}",
+            text
+        ));
+    }
+    if mantissa.ends_with('.') {
+        return Err(format!(
+            "Missing digits after the decimal point in {}",
+            text
+        ));
+    }
+    if let Some(exponent) = exponent {
+        let exponent = exponent
+            .strip_prefix(|c| c == '+' || c == '-')
+            .unwrap_or(exponent);
+        if exponent.is_empty() {
+            return Err(format!("Missing digits in the exponent of {}", text));
+        }
+        if let Some(c) = exponent.chars().find(|c| !c.is_ascii_digit()) {
+            return Err(format!(
+                "Invalid character '{}' in the exponent of {}",
+                c, text
+            ));
+        }
+    } else if !mantissa.contains('.') {
+        return str::parse::<i64>(&digits)
+            .map(|n| (Int, Value::Int(n)))
+            .map_err(|_| too_large());
     }
+    str::parse::<f64>(&digits)
+        .map(|n| (Num, Value::Num(n)))
+        .map_err(|_| format!("Faliure parsing number! Got {}", text))
 }
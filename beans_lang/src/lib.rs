@@ -1,25 +1,38 @@
 #[macro_use]
 extern crate lazy_static;
 extern crate float_cmp;
+// Lets the code generated by beans_derive name this crate from inside it
+extern crate self as beans_lang;
 
+pub mod compiler;
+pub mod convert;
+mod coroutine;
+pub mod dict;
 pub mod environments;
+pub mod errors;
 pub mod evaluator;
+mod iteration;
 pub mod lexer;
 mod node;
 pub mod parser;
 mod reader;
+pub mod resolver;
+pub mod span;
+#[rustfmt::skip]
 pub mod tokens;
+pub mod vm;
 
 #[cfg(test)]
 mod tests {
     use super::beans;
     use super::environments::*;
+    use super::errors::*;
     use super::evaluator::*;
     #[test]
     fn bind_sin() {
-        let sin_v = |vs: Vec<Value>| {
-            let d = vs.first().unwrap().as_numeric();
-            Value::Num(d.sin())
+        let sin_v = |_: &mut Evaluator, vs: Vec<Value>| {
+            let d = numeric_arg(&vs, 0)?;
+            Ok(Value::Num(d.sin()))
         };
 
         let glob = beans::create_global();
@@ -35,6 +48,335 @@ mod tests {
         }
     }
 
+    #[test]
+    fn stateful_natives() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let log = Rc::new(RefCell::new(vec![]));
+        let sink = log.clone();
+        let mut count = 0.0;
+
+        let glob = beans::create_global();
+        glob.borrow_mut().bind(
+            "log",
+            Env::make_callable(
+                move |_, vs| {
+                    sink.borrow_mut().push(vs[0].string_repr());
+                    Ok(Value::Nil)
+                },
+                1,
+            ),
+        );
+        glob.borrow_mut().bind(
+            "tick",
+            Env::make_callable_mut(
+                move |_, _| {
+                    count += 1.0;
+                    Ok(Value::Num(count))
+                },
+                0,
+            ),
+        );
+
+        let program = String::from("log(\"a\"); log(\"b\"); tick(); tick();");
+        let mut evaluator = beans::create_evaluator(glob);
+        match beans::do_string(program, &mut evaluator) {
+            StatementResult::Ok(v) => assert!(v.as_numeric() == 2.0),
+            _ => panic!("Failed calling stateful natives"),
+        }
+        assert!(*log.borrow() == vec![String::from("a"), String::from("b")]);
+    }
+
+    #[test]
+    fn register_fn() {
+        let glob = beans::create_global();
+        glob.borrow_mut()
+            .register_fn("repeat", |s: String, n: i64| s.repeat(n as usize));
+        glob.borrow_mut()
+            .register_fn("sum", |xs: Vec<f64>| -> f64 { xs.iter().sum() });
+        glob.borrow_mut()
+            .register_fn("first", |xs: Vec<Value>| xs.into_iter().next());
+
+        let mut evaluator = beans::create_evaluator(glob);
+        match beans::do_string(String::from("first([]);"), &mut evaluator) {
+            StatementResult::Ok(Value::Nil) => {}
+            _ => panic!("Expected None to become nil"),
+        }
+        match beans::do_string(String::from("repeat(\"ab\", sum([1, 2]));"), &mut evaluator) {
+            StatementResult::Ok(v) => assert!(v.string_repr() == "ababab"),
+            _ => panic!("Failed calling registered functions"),
+        }
+        match beans::do_string(String::from("repeat(1, 2);"), &mut evaluator) {
+            StatementResult::Failure(why) => {
+                assert!(why.get_kind() == ErrorKind::TypeError);
+                assert!(why.get_message() == "Argument 1: Expected string, got int");
+            }
+            _ => panic!("Expected a conversion error"),
+        }
+    }
+
+    #[test]
+    fn derive_struct() {
+        use super::convert::BeansStruct;
+        use beans_derive::BeansStruct;
+
+        #[derive(BeansStruct, Clone)]
+        struct Point {
+            x: f64,
+            y: f64,
+        }
+
+        let glob = beans::create_global();
+        glob.borrow_mut().register_struct::<Point>();
+        glob.borrow_mut()
+            .register_fn("norm", |p: Point| (p.x * p.x + p.y * p.y).sqrt());
+        glob.borrow_mut()
+            .register_fn("origin", || Point { x: 0.0, y: 0.0 });
+
+        let mut evaluator = beans::create_evaluator(glob);
+        let program = String::from("var p = Point(3, 4); var o = origin(); norm(p) + o.x;");
+        match beans::do_string(program, &mut evaluator) {
+            StatementResult::Ok(v) => assert!(v.as_numeric() == 5.0),
+            _ => panic!("Failed round-tripping a derived struct"),
+        }
+
+        let mut p = Point { x: 1.0, y: 2.0 };
+        assert!(p.get_field("y").unwrap().as_numeric() == 2.0);
+        assert!(p.set_field("x", Value::Num(5.0)).is_ok() && p.x == 5.0);
+        assert!(p.set_field("z", Value::Nil).is_err());
+        assert!(p.set_field("x", Value::Nil).is_err());
+    }
+
+    #[test]
+    fn user_data() {
+        use std::rc::Rc;
+
+        struct Counter(f64);
+
+        let mut methods = MethodTable::new("Counter");
+        methods
+            .add_method("add", 1, |c: &mut Counter, args| {
+                c.0 += numeric_arg(&args, 0)?;
+                Ok(Value::Nil)
+            })
+            .add_method("get", 0, |c: &mut Counter, _| Ok(Value::Num(c.0)));
+        let methods = Rc::new(methods);
+
+        let glob = beans::create_global();
+        glob.borrow_mut().register_fn("counter", move || {
+            UserData::new(Counter(0.0)).with_methods(methods.clone())
+        });
+        glob.borrow_mut().register_fn("peek", |data: UserData| {
+            data.borrow::<Counter>().map(|c| c.0)
+        });
+
+        let mut evaluator = beans::create_evaluator(glob);
+        let program = String::from("var c = counter(); c.add(2); c.add(3); c.get() + peek(c);");
+        match beans::do_string(program, &mut evaluator) {
+            StatementResult::Ok(v) => assert!(v.as_numeric() == 10.0),
+            _ => panic!("Failed calling userdata methods"),
+        }
+        match beans::do_string(String::from("c.reset();"), &mut evaluator) {
+            StatementResult::Failure(why) => assert!(why.get_kind() == ErrorKind::TypeError),
+            _ => panic!("Expected a missing method error"),
+        }
+    }
+
+    #[test]
+    fn error_kinds() {
+        let programs = [
+            ("var l = [1, 2]; l[3];", ErrorKind::IndexOutOfBounds),
+            ("1 + nil;", ErrorKind::TypeError),
+            ("undefined = 3;", ErrorKind::UndefinedVariable),
+            (
+                "var f = lambda(x) return x; end; f(1, 2);",
+                ErrorKind::ArityMismatch,
+            ),
+            ("var n = 3; n();", ErrorKind::NotCallable),
+            ("import \"does/not/exist\";", ErrorKind::ImportFailed),
+            ("var = 3; print(1 +);", ErrorKind::SyntaxError),
+            ("math.sin(\"x\");", ErrorKind::TypeError),
+            ("math.atan2(1);", ErrorKind::ArityMismatch),
+            ("string.sub(\"abc\");", ErrorKind::ArityMismatch),
+            ("string.upper(1);", ErrorKind::TypeError),
+            ("string.ord(\"\");", ErrorKind::IndexOutOfBounds),
+            ("string.chr(-1);", ErrorKind::ValueError),
+            (
+                "string.repeat(\"ab\", 4611686018427387904);",
+                ErrorKind::ValueError,
+            ),
+            ("error(\"x\");", ErrorKind::UserError),
+            ("try error(1); catch e error(e); end", ErrorKind::UserError),
+            (
+                "try 1 // 0; catch e error(e); end",
+                ErrorKind::ArithmeticError,
+            ),
+            (
+                "try [][0]; finally print(1); end",
+                ErrorKind::IndexOutOfBounds,
+            ),
+        ];
+
+        for (program, kind) in programs.iter() {
+            let glob = beans::create_global();
+            glob.borrow_mut().build_stdlib();
+            let mut evaluator = beans::create_evaluator(glob);
+            match beans::do_string(String::from(*program), &mut evaluator) {
+                StatementResult::Failure(why) => assert!(why.get_kind() == *kind),
+                _ => panic!("Expected {:?} from {}", kind, program),
+            }
+        }
+    }
+
+    #[test]
+    fn string_library() {
+        let cases = [
+            ("string.len(\"héllo\");", "5"),
+            ("string.sub(\"héllo\", 1, -1);", "éll"),
+            ("string.sub(\"héllo\", -2);", "lo"),
+            (
+                "string.join(string.split(\"a,b,,c\", \",\"), \"|\");",
+                "a|b||c",
+            ),
+            ("string.join(string.split(\"  a b\\tc \"), \"-\");", "a-b-c"),
+            ("string.join(string.split(\"añb\", \"\"), \" \");", "a ñ b"),
+            ("string.find(\"ñaña\", \"a\");", "1"),
+            ("string.find(\"ñaña\", \"a\", 2);", "3"),
+            ("string.find(\"abc\", \"z\");", "Nil"),
+            ("string.replace(\"a-b-c\", \"-\", \"+\");", "a+b+c"),
+            (
+                "\"{string.upper(\"straße\")}{string.lower(\"ÀB\")}\";",
+                "STRASSEàb",
+            ),
+            ("string.trim(\"  x \\n\");", "x"),
+            (
+                "string.starts_with(\"beans\", \"be\") and string.ends_with(\"beans\", \"ns\");",
+                "true",
+            ),
+            ("string.repeat(\"ab\", 3);", "ababab"),
+            (
+                "string.ord(\"€\") == 8364 and string.ord(\"a€\", -1) == 8364;",
+                "true",
+            ),
+            ("string.chr(128512);", "😀"),
+            (
+                "string.tonumber(\" 42 \") + string.tonumber(\"-0x10\");",
+                "26",
+            ),
+            ("string.tonumber(\"2.5e1\");", "25"),
+            (
+                "string.tonumber(\"abc\") == nil and string.tonumber(\"\") == nil;",
+                "true",
+            ),
+            (
+                "string.tostring(1.5) == \"1.5\" and string.tostring(2) == \"2\";",
+                "true",
+            ),
+        ];
+        for (program, expected) in cases.iter() {
+            let glob = beans::create_global();
+            glob.borrow_mut().build_stdlib();
+            let mut evaluator = beans::create_evaluator(glob);
+            match beans::do_string(String::from(*program), &mut evaluator) {
+                StatementResult::Ok(v) => {
+                    assert!(
+                        v.string_repr() == *expected,
+                        "{} gave {}",
+                        program,
+                        v.string_repr()
+                    )
+                }
+                StatementResult::Failure(why) => panic!("{} failed: {}", program, why),
+                _ => panic!("Unexpected result from {}", program),
+            }
+        }
+    }
+
+    #[test]
+    fn error_objects() {
+        let program = String::from(
+            "function f(x) error([x]); end\nvar e; try f(1); catch caught e = caught; end e;",
+        );
+        let glob = beans::create_global();
+        glob.borrow_mut().build_stdlib();
+        let mut evaluator = beans::create_evaluator(glob);
+        let e = match beans::do_string(program, &mut evaluator) {
+            StatementResult::Ok(Value::StructInstance(e)) => e,
+            _ => panic!("Expected an error object"),
+        };
+        let field = |name: &str| e.get(&String::from(name)).unwrap().clone();
+        assert!(e.get_base().get_name() == "Error");
+        assert!(field("kind").string_repr() == "UserError");
+        match field("value") {
+            Value::List(l) => assert!(l.borrow()[0].as_numeric() == 1.0),
+            _ => panic!("Expected the raised list"),
+        }
+        let traceback = field("traceback").string_repr();
+        assert!(traceback.contains("line 2, in <module>"), "{}", traceback);
+        assert!(traceback.contains("line 1, in f"), "{}", traceback);
+    }
+
+    #[test]
+    fn coroutine_status() {
+        let program = String::from(
+            "var seen = [];
+            var outer = nil;
+            outer = coroutine.create(lambda()
+                var inner = coroutine.create(lambda() seen = [seen, coroutine.status(outer)]; end);
+                seen = [seen, coroutine.status(outer)];
+                coroutine.resume(inner);
+                return coroutine.status(inner);
+            end);
+            var before = coroutine.status(outer);
+            var after = coroutine.resume(outer);
+            \"{before} {seen[0][1]} {seen[1]} {after} {coroutine.status(outer)}\";",
+        );
+        let glob = beans::create_global();
+        glob.borrow_mut().build_stdlib();
+        let mut evaluator = beans::create_evaluator(glob);
+        match beans::do_string(program, &mut evaluator) {
+            StatementResult::Ok(Value::Str(s)) => {
+                assert!(s == "suspended running normal dead dead", "{}", s)
+            }
+            _ => panic!("Expected the statuses"),
+        }
+    }
+
+    #[test]
+    fn error_location() {
+        let program = String::from("var l = [1, 2];\nvar x = 1 +\n    l[3];");
+        let mut evaluator = beans::create_evaluator(beans::create_global());
+        match beans::do_string(program, &mut evaluator) {
+            StatementResult::Failure(why) => {
+                assert!(why.get_location().line == 3);
+                assert!(why.get_location().column == 5);
+            }
+            _ => panic!("Expected an index error"),
+        }
+    }
+
+    #[test]
+    fn traceback() {
+        let program = String::from(
+            "function inner(x)\n  return x[5];\nend\nfunction outer()\n  return inner([]);\nend\nouter();",
+        );
+        let mut evaluator = beans::create_evaluator(beans::create_global());
+        match beans::do_string(program, &mut evaluator) {
+            StatementResult::Failure(why) => {
+                let frames = why.get_traceback();
+                assert!(frames.len() == 2);
+                assert!(frames[0].function == "outer" && frames[0].line == 7);
+                assert!(frames[1].function == "inner" && frames[1].line == 5);
+                assert!(why.get_location().line == 2);
+                let text = why.format_traceback();
+                assert!(text.contains("File \"<string>\", line 5, in outer"));
+                assert!(text.contains("line 2, in inner"));
+            }
+            _ => panic!("Expected an index error"),
+        }
+    }
+
     #[test]
     fn two_envs() {
         let glob = beans::create_global();
@@ -42,22 +384,20 @@ mod tests {
         let first = beans::create_enclosing(glob.clone());
         let sec = beans::create_enclosing(glob.clone());
 
-        let cos_fun = |vs: Vec<Value>| {
-            let d = vs.first().unwrap().as_numeric();
-            Value::Num(d.cos())
+        let cos_fun = |_: &mut Evaluator, vs: Vec<Value>| {
+            let d = numeric_arg(&vs, 0)?;
+            Ok(Value::Num(d.cos()))
         };
 
-        let sin_fun = |vs: Vec<Value>| {
-            let d = vs.first().unwrap().as_numeric();
-            Value::Num(d.sin())
+        let sin_fun = |_: &mut Evaluator, vs: Vec<Value>| {
+            let d = numeric_arg(&vs, 0)?;
+            Ok(Value::Num(d.sin()))
         };
 
         first
             .borrow_mut()
             .bind("cos", Env::make_callable(cos_fun, 1));
-        sec
-            .borrow_mut()
-            .bind("sin", Env::make_callable(sin_fun, 1));
+        sec.borrow_mut().bind("sin", Env::make_callable(sin_fun, 1));
 
         let sin_expr = String::from("sin(0.0);");
         let cos_expr = String::from("cos(0.0);");
@@ -94,15 +434,18 @@ mod tests {
 pub mod beans {
 
     use super::environments::*;
-    use super::evaluator::Evaluate;
+    use super::errors::*;
+    use super::evaluator::Backend;
     use super::evaluator::Evaluator;
     use super::evaluator::StatementResult;
     use super::lexer::Lexer;
     use super::parser::Parser;
-    use std::io::Read;
+    use super::resolver::Resolver;
+    use super::span::register_file;
     use std::cell::RefCell;
-    use std::rc::Rc;
     use std::fs::File;
+    use std::io::Read;
+    use std::rc::Rc;
 
     pub fn create_global() -> Rc<RefCell<Env>> {
         Rc::new(RefCell::new(Env::new()))
@@ -116,29 +459,54 @@ pub mod beans {
         Evaluator::new_with_global(env)
     }
 
+    pub fn create_evaluator_with(env: Rc<RefCell<Env>>, backend: Backend) -> Evaluator {
+        Evaluator::new_with_backend(env, backend)
+    }
+
     pub fn do_string(program: String, evaluator: &mut Evaluator) -> StatementResult {
+        run(Lexer::new(program), evaluator)
+    }
 
-        let lexer = Lexer::new(program);
+    fn run(lexer: Lexer, evaluator: &mut Evaluator) -> StatementResult {
         let mut parser = Parser::new(lexer);
         let stmts = parser.parse();
-        let mut result: StatementResult = StatementResult::Continue;
-        for stmt in stmts {
-            result = evaluator.execute_statement(&stmt);
+        if parser.error() {
+            return StatementResult::Failure(BeansError::from_diagnostics(
+                parser.diagnostics().clone(),
+            ));
         }
-        result
+        let mut resolver = Resolver::new(evaluator.current.clone());
+        resolver.resolve(&stmts);
+        if resolver.error() {
+            return StatementResult::Failure(BeansError::from_diagnostics(
+                resolver.diagnostics().clone(),
+            ));
+        }
+        evaluator.execute_program(&stmts)
     }
 
     pub fn do_file(file_path: &String, evaluator: &mut Evaluator) -> StatementResult {
         let mut file = match File::open(file_path) {
             Ok(file) => file,
-            Err(why) => return StatementResult::Failure(format!("Error opening file {}: {}", file_path, why))
+            Err(why) => {
+                return StatementResult::Failure(BeansError::new(
+                    ErrorKind::IoError,
+                    format!("Error opening file {}: {}", file_path, why),
+                ))
+            }
         };
 
         let mut file_content = String::new();
-        file.read_to_string(&mut file_content);
-        do_string(file_content, evaluator)
-
+        if let Err(why) = file.read_to_string(&mut file_content) {
+            return StatementResult::Failure(BeansError::new(
+                ErrorKind::IoError,
+                format!("Error reading file {}: {}", file_path, why),
+            ));
+        }
+        let lexer = Lexer::new_with_file(file_content, register_file(file_path));
+        match run(lexer, evaluator) {
+            StatementResult::Failure(why) => StatementResult::Failure(why.in_file(file_path)),
+            result => result,
+        }
     }
-   
-
 }
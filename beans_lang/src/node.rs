@@ -1,50 +1,222 @@
+use super::span::Span;
 use super::tokens::*;
+use std::cell::Cell;
+use std::ops::Deref;
 use std::rc::Rc;
 use String as Id;
 
 #[derive(Debug)]
-pub enum Stmt {
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub enum StmtKind {
     ExprStmt(Expr),
     If(Vec<(Expr, Vec<Stmt>)>, Vec<Stmt>),
-    While(Expr, Vec<Stmt>),
+    // Label, condition, body, and the updater of C-style `for` loops,
+    // which runs after the body even when it continues
+    While(Option<Id>, Expr, Vec<Stmt>, Option<Expr>),
+    // `for x in xs` or `for k, v in xs`
+    ForIn(Option<Id>, Vec<Variable>, Expr, Vec<Stmt>),
     Block(Vec<Stmt>),
-    Var(Id, Option<Expr>),
-    FunDef(Id, Vec<Id>, Rc<Vec<Stmt>>),
-    StructDef(Id, Vec<Id>),
-    EnumDef(Id, Vec<(Id, Option<Expr>)>),
+    Var(Variable, Option<Expr>),
+    FunDef(Variable, Vec<Id>, Rc<Body>),
+    StructDef(Variable, Vec<Id>),
+    EnumDef(Variable, Vec<(Id, Option<Expr>)>),
     Return(Expr),
-    Import(Id),
-    Break,
-    Continue,
+    // The variable the module is bound to, and the path of the module
+    Import(Variable, Id),
+    // With the label of the loop to leave, the innermost one otherwise
+    Break(Option<Id>),
+    Continue(Option<Id>),
+    // Body, the variable and body of the `catch` clause, and the `finally`
+    // body, shared so the compiler can emit it on every way out of the body
+    Try(Vec<Stmt>, Option<(Variable, Vec<Stmt>)>, Rc<Vec<Stmt>>),
+}
+
+#[derive(Debug)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
 }
 
 #[derive(Debug)]
-pub enum Expr {
+pub enum ExprKind {
     Unary(TokenType, Box<Expr>),
     Binary(Box<Expr>, TokenType, Box<Expr>),
-    Id(String),
+    Id(Variable),
     Call(Box<Expr>, Vec<Expr>),
     Get(Box<Expr>, Box<Expr>),
+    Index(Box<Expr>, Box<Expr>),
+    Slice(Box<Expr>, Option<Box<Expr>>, Option<Box<Expr>>),
     Num(f64),
+    Int(i64),
     Str(String),
     Bool(bool),
-    DictDef(Vec<(String, Expr)>),
+    DictDef(Vec<(Expr, Expr)>),
     ListDef(Vec<Expr>),
+    // Literal pieces of a string and the expressions written between them
+    Interpolation(Vec<Expr>),
     Grouping(Box<Expr>),
     Assign(Box<Expr>, Box<Expr>),
-    LambdaDef(Vec<String>, Rc<Vec<Stmt>>),
+    LambdaDef(Vec<String>, Rc<Body>),
+    // Makes the function it is written in a generator
+    Yield(Box<Expr>),
     Nil,
 }
 
+/// Where a variable lives, filled in by the resolver
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Slot {
+    /// Looked up by name, starting from the current environment
+    Global,
+    /// Slot of the environment of a function call or block, `depth`
+    /// environments out
+    Local { depth: usize, slot: usize },
+}
+
+/// A use or declaration of a variable
+#[derive(Debug)]
+pub struct Variable {
+    pub name: Id,
+    pub slot: Cell<Slot>,
+}
+
+impl Variable {
+    pub fn new(name: Id) -> Variable {
+        Variable {
+            name,
+            slot: Cell::new(Slot::Global),
+        }
+    }
+}
+
+/// What a `Get` indexes by: an identifier or a number literal
+#[derive(Clone, Copy)]
+pub enum Key<'a> {
+    Name(&'a str),
+    Index(f64),
+    Invalid,
+}
+
+/// The statements of a function, shared by the closures made from it
+#[derive(Debug)]
+pub struct Body {
+    stmts: Vec<Stmt>,
+    // Whether the body yields, making calls return coroutines
+    pub generator: bool,
+}
+
+impl Body {
+    pub fn new(stmts: Vec<Stmt>) -> Body {
+        Body {
+            generator: yields(&stmts),
+            stmts,
+        }
+    }
+}
+
+impl Deref for Body {
+    type Target = Vec<Stmt>;
+
+    fn deref(&self) -> &Vec<Stmt> {
+        &self.stmts
+    }
+}
+
+/// Whether a `yield` is written in `stmts`, outside of nested functions
+pub fn yields(stmts: &[Stmt]) -> bool {
+    stmts.iter().any(Stmt::yields)
+}
+
+fn yields_in(e: &Option<Expr>) -> bool {
+    e.as_ref().is_some_and(Expr::yields)
+}
+
+impl Stmt {
+    pub fn new(kind: StmtKind, span: Span) -> Stmt {
+        Stmt { kind, span }
+    }
+
+    fn yields(&self) -> bool {
+        match &self.kind {
+            StmtKind::ExprStmt(e) | StmtKind::Return(e) => e.yields(),
+            StmtKind::If(branches, else_block) => {
+                branches
+                    .iter()
+                    .any(|(cond, body)| cond.yields() || yields(body))
+                    || yields(else_block)
+            }
+            StmtKind::While(_, cond, body, updater) => {
+                cond.yields() || yields(body) || yields_in(updater)
+            }
+            StmtKind::ForIn(_, _, iterable, body) => iterable.yields() || yields(body),
+            StmtKind::Block(body) => yields(body),
+            StmtKind::Var(_, init) => yields_in(init),
+            StmtKind::EnumDef(_, values) => values.iter().any(|(_, value)| yields_in(value)),
+            StmtKind::Try(body, catch, finally) => {
+                yields(body) || catch.as_ref().is_some_and(|(_, c)| yields(c)) || yields(finally)
+            }
+            StmtKind::FunDef(..)
+            | StmtKind::StructDef(..)
+            | StmtKind::Import(..)
+            | StmtKind::Break(_)
+            | StmtKind::Continue(_) => false,
+        }
+    }
+}
+
 impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Expr {
+        Expr { kind, span }
+    }
+
+    fn yields(&self) -> bool {
+        match &self.kind {
+            ExprKind::Yield(_) => true,
+            ExprKind::Unary(_, e) | ExprKind::Grouping(e) | ExprKind::Get(e, _) => e.yields(),
+            ExprKind::Binary(l, _, r) | ExprKind::Assign(l, r) | ExprKind::Index(l, r) => {
+                l.yields() || r.yields()
+            }
+            ExprKind::Call(callee, args) => callee.yields() || args.iter().any(Expr::yields),
+            ExprKind::Slice(base, lo, hi) => {
+                base.yields() || lo.iter().chain(hi).any(|bound| bound.yields())
+            }
+            ExprKind::DictDef(elts) => elts.iter().any(|(k, v)| k.yields() || v.yields()),
+            ExprKind::ListDef(elts) | ExprKind::Interpolation(elts) => {
+                elts.iter().any(Expr::yields)
+            }
+            ExprKind::Id(_)
+            | ExprKind::Num(_)
+            | ExprKind::Int(_)
+            | ExprKind::Str(_)
+            | ExprKind::Bool(_)
+            | ExprKind::LambdaDef(..)
+            | ExprKind::Nil => false,
+        }
+    }
+
+    pub fn as_key(&self) -> Key<'_> {
+        match &self.kind {
+            ExprKind::Id(v) => Key::Name(&v.name),
+            ExprKind::Num(n) => Key::Index(*n),
+            ExprKind::Int(i) => Key::Index(*i as f64),
+            _ => Key::Invalid,
+        }
+    }
+
     pub fn new_from_tok(t: &Token) -> Expr {
-        match t.get_type() {
-            TokenType::Num => Expr::Num(t.as_f64()),
-            TokenType::Str => Expr::Str(t.as_String()),
-            TokenType::True => Expr::Bool(true),
-            TokenType::False => Expr::Bool(false),
-            TokenType::Identifier => Expr::Id(t.as_Id()),
+        let kind = match t.get_type() {
+            TokenType::Num => ExprKind::Num(t.as_f64()),
+            TokenType::Int => ExprKind::Int(t.as_i64()),
+            TokenType::Str => ExprKind::Str(t.as_String()),
+            TokenType::True => ExprKind::Bool(true),
+            TokenType::False => ExprKind::Bool(false),
+            TokenType::Identifier => ExprKind::Id(Variable::new(t.as_Id())),
             _ => panic!("Can't convert Token to Expr!"),
-        }
+        };
+        Expr::new(kind, t.get_span())
     }
 }
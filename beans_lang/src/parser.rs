@@ -1,5 +1,7 @@
+use super::errors::Diagnostic;
 use super::lexer::*;
 use super::node::*;
+use super::span::Span;
 use super::tokens::Token;
 use super::tokens::TokenType;
 use super::tokens::TokenType::*;
@@ -32,7 +34,11 @@ mod tests {
                     2 + 3;
                     end;",
         );
-        run_str("call(3, 4, 5);")
+        run_str("call(3, 4, 5);");
+        run_str("l[i + 1] = d[\"some key\"][-1];");
+        run_str("s[1:]; s[:-1]; s[:]; s[lo:hi];");
+        run_str("f(1)(2).x[0](3);");
+        run_str("\"{a} and {f(\"{b}\")} { {k: 1}.k }\";")
     }
 
     #[test]
@@ -78,6 +84,142 @@ mod tests {
                 print(i);
             end",
         );
+        run_str(
+            "try
+                risky();
+            catch e
+                print(e.message);
+            finally
+                done();
+            end",
+        );
+        run_str(
+            "function gen() var x = yield; yield [x, yield]; yield; end
+            coroutine.yield(1);",
+        );
+        run_str(
+            "rows: for row in grid do
+                for x in row do
+                    if x then continue rows; end
+                end
+            end",
+        );
+    }
+
+    #[test]
+    fn test_spans() {
+        let source = String::from("var x = 1;\nprint(x + 2,\n  x);");
+        let mut parser = Parser::new(Lexer::new(source));
+        let stmts = parser.parse();
+
+        let call = &stmts[1];
+        assert!(call.span.line == 2 && call.span.column == 1);
+        assert!(call.span.start == 11 && call.span.end == 29);
+        match &call.kind {
+            StmtKind::ExprStmt(e) => match &e.kind {
+                ExprKind::Call(_, args) => {
+                    assert!(args[0].span.line == 2 && args[0].span.column == 7);
+                    assert!(args[0].span.end - args[0].span.start == 5);
+                    assert!(args[1].span.line == 3 && args[1].span.column == 3);
+                }
+                _ => panic!("Expected a call"),
+            },
+            _ => panic!("Expected an expression statement"),
+        }
+    }
+
+    fn parse_errors(s: &str) -> Vec<Diagnostic> {
+        let mut parser = Parser::new(Lexer::new(String::from(s)));
+        parser.parse();
+        parser.diagnostics().clone()
+    }
+
+    #[test]
+    fn test_recovery() {
+        let errors = parse_errors(
+            "var = 3;
+            print(1 +);
+            function f(a, 2)
+                return a;
+            end
+            var ok = 1;
+            x = [1, 2;",
+        );
+        assert!(errors.len() == 4);
+        assert!(errors[0].span.line == 1);
+        assert!(errors[1].span.line == 2);
+        assert!(errors[2].span.line == 3);
+        assert!(errors[3].span.line == 7);
+
+        let errors = parse_errors(
+            "while true do
+                x = ;
+                if x then
+                    y = );
+                end
+            end
+            end",
+        );
+        assert!(errors.len() == 3);
+    }
+
+    #[test]
+    fn test_no_crash() {
+        let malformed = [
+            "x <",
+            "struct",
+            "struct P {}",
+            "enum E {",
+            "import;",
+            "a[];",
+            "a[1:2:3];",
+            "{nil: 2};",
+            "{[1: 2};",
+            "function (",
+            "lambda",
+            "if",
+            "if x then",
+            "for var",
+            "))))",
+            "end end",
+            "elif else",
+            "\"unterminated",
+            "1.2.3;",
+            "x = $;",
+            "\"{}\";",
+            "\"{a b}\";",
+            "\"{\";",
+            "\"{(}\";",
+            "try x(); end",
+            "try catch end",
+            "try catch e",
+            "yield yield",
+            "var yield = 1;",
+        ];
+        for program in malformed.iter() {
+            assert!(!parse_errors(program).is_empty(), "{} parsed", program);
+        }
+        assert!(!parse_errors(&"(".repeat(3000)).is_empty());
+        assert!(!parse_errors(&"-".repeat(3000)).is_empty());
+        assert!(!parse_errors(&"x[".repeat(3000)).is_empty());
+
+        let vocabulary = [
+            "var", "x", "=", "1", ";", "function", "(", ")", ",", "end", "if", "then", "elif",
+            "else", "while", "do", "for", "lambda", "[", "]", "{", "}", ":", ".", "+", "struct",
+            "enum", "return", "break", "\"s\"", "import", "and", "not", "\"{", "}\"",
+        ];
+        let mut seed: u64 = 42;
+        for _ in 0..500 {
+            let mut program = String::new();
+            for _ in 0..30 {
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                program.push_str(vocabulary[(seed >> 33) as usize % vocabulary.len()]);
+                program.push(' ');
+            }
+            parse_errors(&program);
+        }
     }
 
     #[test]
@@ -120,37 +262,47 @@ mod tests {
     }
 }
 
+// Nodes nested deeper than this are reported instead of risking a stack overflow
+const MAX_NESTING: usize = 100;
+
 pub struct Parser {
     lexer: Lexer,
-    had_error: bool,
+    diagnostics: Vec<Diagnostic>,
+    panic_mode: bool,
+    block_depth: usize,
+    nesting: usize,
 }
 
 impl Parser {
     pub fn new(lex: Lexer) -> Parser {
         Parser {
             lexer: lex,
-            had_error: false,
+            diagnostics: vec![],
+            panic_mode: false,
+            block_depth: 0,
+            nesting: 0,
         }
     }
 
     pub fn error(&self) -> bool {
-        self.had_error
+        !self.diagnostics.is_empty()
     }
 
-    pub fn parse(&mut self) -> Vec<Stmt> {
-        self.lexer.do_lex();
-
-        if self.lexer.had_error() {
-            self.had_error = true;
-            return vec![];
-        }
+    /// Every syntax error found by the last call to parse
+    pub fn diagnostics(&self) -> &Vec<Diagnostic> {
+        &self.diagnostics
+    }
 
+    pub fn parse(&mut self) -> Vec<Stmt> {
         let mut vec = vec![];
         while !self.lexer.is_at_end() {
             vec.push(self.statement());
-            if self.had_error {
-                break;
-            }
+        }
+
+        // Syntax errors after a bad token are most likely caused by it
+        if self.lexer.had_error() {
+            self.diagnostics = self.lexer.take_diagnostics();
+            return vec![];
         }
         vec
     }
@@ -160,38 +312,140 @@ impl Parser {
         if let Some(peek_tok) = peek_result {
             let peek = peek_tok.get_type();
             if toks.contains(&peek) {
-                self.lexer.next();
+                self.lexer.advance();
                 return true;
             }
         }
         false
     }
 
-    fn syntax_error(&self, t: &Token, msg: String) {
-        println!("Syntax error at line {}: {}", t.get_line(), msg);
+    fn check(&self, toks: &[TokenType]) -> bool {
+        match self.lexer.peek() {
+            Some(tok) => toks.contains(&tok.get_type()),
+            None => false,
+        }
+    }
+
+    /// Records a syntax error. While recovering from an error the following
+    /// ones are most likely caused by the first, so they are not reported.
+    fn syntax_error(&mut self, span: Span, msg: String) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        self.diagnostics.push(Diagnostic::new(msg, span));
+    }
+
+    /// Reports an error about the next token, or about the end of the file
+    fn error_at_peek(&mut self, msg: String) {
+        match self.lexer.peek() {
+            Some(tok) => {
+                let (span, tok_type) = (tok.get_span(), tok.get_type());
+                self.syntax_error(span, format!("{}, got {:?}", msg, tok_type));
+            }
+            None => {
+                let span = self.prev_span();
+                self.syntax_error(span, format!("{}, got EOF", msg));
+            }
+        }
     }
 
     fn expect(&mut self, t: TokenType) -> Option<&Token> {
         if !self.match_next(vec![t]) {
-            if let Some(token_type) = self.lexer.peek() {
-                if self.lexer.is_at_end() {
-                    self.syntax_error(token_type, format!("Expected {:?}, got EOF", t));
-                } else {
-                    self.syntax_error(
-                        token_type,
-                        format!("Expected {:?}, got {:?}", t, token_type.get_type()),
-                    );
-                }
-            } else {
-                println!("Expected {:?}, got EOF", t);
-            }
-            self.had_error = true;
+            self.error_at_peek(format!("Expected {:?}", t));
             return None;
         }
         self.lexer.prev()
     }
 
+    /// Skips tokens until the start of the next statement, or until the end
+    /// of the enclosing block, which is left for the block to consume.
+    fn synchronize(&mut self, progressed: bool) {
+        self.panic_mode = false;
+        if progressed && [Semicolon, End].contains(&self.prev_type()) {
+            return;
+        }
+        let mut advanced = false;
+        while let Some(tok) = self.lexer.peek() {
+            let tok_type = tok.get_type();
+            if self.block_depth > 0 && [End, Elif, Else, Catch, Finally].contains(&tok_type) {
+                return;
+            }
+            if advanced
+                && [
+                    Var, Function, Struct, Enum, Import, If, While, For, Try, Return, Break,
+                    Continue,
+                ]
+                .contains(&tok_type)
+            {
+                return;
+            }
+            self.lexer.advance();
+            advanced = true;
+            if tok_type == Semicolon {
+                return;
+            }
+        }
+    }
+
+    /// Span of the token about to be consumed, used to mark where a node begins
+    fn peek_span(&self) -> Span {
+        match self.lexer.peek() {
+            Some(tok) => tok.get_span(),
+            None => self.prev_span(),
+        }
+    }
+
+    fn prev_span(&self) -> Span {
+        match self.lexer.prev() {
+            Some(tok) => tok.get_span(),
+            None => Span::default(),
+        }
+    }
+
+    fn prev_type(&self) -> TokenType {
+        match self.lexer.prev() {
+            Some(tok) => tok.get_type(),
+            None => Eof,
+        }
+    }
+
+    /// Span going from start to the last consumed token
+    fn span_from(&self, start: Span) -> Span {
+        start.to(self.prev_span())
+    }
+
+    fn binary(l: Expr, op: TokenType, r: Expr) -> Expr {
+        let span = l.span.to(r.span);
+        Expr::new(ExprKind::Binary(Box::new(l), op, Box::new(r)), span)
+    }
+
+    fn enter(&mut self) -> bool {
+        self.nesting += 1;
+        if self.nesting > MAX_NESTING {
+            let span = self.peek_span();
+            self.syntax_error(span, String::from("Code is nested too deeply!"));
+            return false;
+        }
+        true
+    }
+
+    fn leave(&mut self) {
+        self.nesting -= 1;
+    }
+
     fn statement(&mut self) -> Stmt {
+        let start = self.peek_span();
+        let pos = self.lexer.get_pos();
+        let kind = self.statement_kind();
+        if self.panic_mode {
+            let progressed = pos != self.lexer.get_pos();
+            self.synchronize(progressed);
+        }
+        Stmt::new(kind, self.span_from(start))
+    }
+
+    fn statement_kind(&mut self) -> StmtKind {
         if self.match_next(vec![Var]) {
             return self.parse_var();
         }
@@ -211,84 +465,109 @@ impl Parser {
             return self.parse_if();
         }
         if self.match_next(vec![While]) {
-            return self.parse_while();
+            return self.parse_while(None);
         }
         if self.match_next(vec![For]) {
-            return self.parse_for();
+            return self.parse_for(None);
+        }
+        if self.match_next(vec![Try]) {
+            return self.parse_try();
         }
         if self.match_next(vec![Return]) {
             let expr = self.expr();
             self.expect(Semicolon);
-            return Stmt::Return(expr);
+            return StmtKind::Return(expr);
         }
         if self.match_next(vec![Break]) {
+            let label = self.label();
             self.expect(Semicolon);
-            return Stmt::Break;
+            return StmtKind::Break(label);
         }
         if self.match_next(vec![Continue]) {
+            let label = self.label();
             self.expect(Semicolon);
-            return Stmt::Continue;
+            return StmtKind::Continue(label);
         }
 
-        let ex = Stmt::ExprStmt(self.expr());
+        let expr = self.expr();
+        // `name:` labels the loop that follows
+        if let ExprKind::Id(var) = &expr.kind {
+            if self.match_next(vec![Colon]) {
+                let label = Some(var.name.clone());
+                if self.match_next(vec![While]) {
+                    return self.parse_while(label);
+                }
+                if self.match_next(vec![For]) {
+                    return self.parse_for(label);
+                }
+                self.error_at_peek(String::from("Expected a loop after label"));
+            }
+        }
+        let ex = StmtKind::ExprStmt(expr);
         self.expect(Semicolon);
         ex
     }
 
-    fn parse_import(&mut self) -> Stmt {
-        let module_name = self.expect(Str).unwrap().as_String();
+    fn label(&mut self) -> Option<String> {
+        if self.match_next(vec![Identifier]) {
+            Some(self.lexer.prev().unwrap().as_Id())
+        } else {
+            None
+        }
+    }
+
+    fn parse_import(&mut self) -> StmtKind {
+        let module_name = match self.expect(Str) {
+            Some(tok) => tok.as_String(),
+            None => String::new(),
+        };
         self.expect(Semicolon);
-        Stmt::Import(module_name)
+        let var = Variable::new(String::from(module_name.split('/').last().unwrap()));
+        StmtKind::Import(var, module_name)
     }
 
-    fn parse_var(&mut self) -> Stmt {
-        let id = self.name();
-        let def = if self.lexer.peek().unwrap().get_type() == Equals {
-            self.lexer.next().unwrap();
+    fn parse_var(&mut self) -> StmtKind {
+        let id = Variable::new(self.name());
+        let def = if self.match_next(vec![Equals]) {
             let exp = self.expr();
-            Stmt::Var(id, Some(exp))
+            StmtKind::Var(id, Some(exp))
         } else {
-            Stmt::Var(id, None)
+            StmtKind::Var(id, None)
         };
         self.expect(Semicolon);
         def
     }
 
-    fn parse_struct(&mut self) -> Stmt {
-        let name = self.name();
+    fn parse_struct(&mut self) -> StmtKind {
+        let name = Variable::new(self.name());
         self.expect(LeftBrace);
         let mut members: Vec<String> = vec![];
         if self.match_next(vec![RightBrace]) {
-            self.syntax_error(
-                self.lexer.peek().unwrap(),
-                String::from("Structs can't be empty!"),
-            );
-            self.had_error = true;
+            let span = self.prev_span();
+            self.syntax_error(span, String::from("Structs can't be empty!"));
+            return StmtKind::StructDef(name, members);
         }
         while {
             let next = self.name();
             members.push(next);
             self.match_next(vec![Comma])
         } {}
-        self.match_next(vec![RightBrace]);
-        Stmt::StructDef(name, members)
+        self.expect(RightBrace);
+        StmtKind::StructDef(name, members)
     }
 
-    fn parse_enum(&mut self) -> Stmt {
-        let name = self.name();
+    fn parse_enum(&mut self) -> StmtKind {
+        let name = Variable::new(self.name());
         self.expect(LeftBrace);
         let mut members: Vec<(String, Option<Expr>)> = vec![];
         if self.match_next(vec![RightBrace]) {
-            self.syntax_error(
-                self.lexer.peek().unwrap(),
-                String::from("Enums can't be empty!"),
-            );
-            self.had_error = true;
+            let span = self.prev_span();
+            self.syntax_error(span, String::from("Enums can't be empty!"));
+            return StmtKind::EnumDef(name, members);
         }
         while {
             let next = self.name();
-            let expr = if self.lexer.peek().unwrap().get_type() == Equals {
-                self.lexer.next().unwrap();
+            let expr = if self.match_next(vec![Equals]) {
                 Some(self.expr())
             } else {
                 None
@@ -297,55 +576,50 @@ impl Parser {
             members.push((next, expr));
             self.match_next(vec![Comma])
         } {}
-        self.match_next(vec![RightBrace]);
-        Stmt::EnumDef(name, members)
+        self.expect(RightBrace);
+        StmtKind::EnumDef(name, members)
     }
 
-    fn parse_if(&mut self) -> Stmt {
+    fn parse_if(&mut self) -> StmtKind {
         let mut branches: Vec<(Expr, Vec<Stmt>)> = vec![];
-        let if_then = self.if_cond_and_exprs();
-        branches.push(if_then);
-        while self.lexer.prev().unwrap().get_type() == Elif {
-            let elif = self.if_cond_and_exprs();
-            branches.push(elif);
-        }
+        while {
+            let if_then = self.if_cond_and_exprs();
+            branches.push(if_then);
+            self.match_next(vec![Elif])
+        } {}
 
         let mut else_block: Vec<Stmt> = vec![];
 
-        if self.lexer.prev().unwrap().get_type() == Else {
-            while {
-                let stmt = self.statement();
-                else_block.push(stmt);
-                !self.match_next(vec![End])
-            } {}
+        if self.match_next(vec![Else]) {
+            else_block = self.block();
         }
+        self.expect(End);
 
-        Stmt::If(branches, else_block)
+        StmtKind::If(branches, else_block)
     }
 
     fn if_cond_and_exprs(&mut self) -> (Expr, Vec<Stmt>) {
         let cond = self.expr();
         self.expect(Then);
-
-        let mut body: Vec<Stmt> = vec![];
-        while {
-            let stmt = self.statement();
-            body.push(stmt);
-            !self.match_next(vec![End, Elif, Else])
-        } {}
+        let body = self.block();
         (cond, body)
     }
 
-    fn parse_while(&mut self) -> Stmt {
+    fn parse_while(&mut self, label: Option<String>) -> StmtKind {
         let cond = self.expr();
         self.expect(Do);
         let body = self.body();
-        Stmt::While(cond, body)
+        StmtKind::While(label, cond, body, None)
     }
 
-    fn parse_for(&mut self) -> Stmt {
+    fn parse_for(&mut self, label: Option<String>) -> StmtKind {
+        let for_start = self.prev_span();
+        if !self.check(&[Var]) {
+            return self.parse_for_in(label);
+        }
         self.expect(Var);
-        let initializer = self.parse_var();
+        let var_start = self.prev_span();
+        let initializer = Stmt::new(self.parse_var(), self.span_from(var_start));
 
         let condition = self.expr();
         self.expect(Semicolon);
@@ -353,46 +627,103 @@ impl Parser {
         let updater = self.expr();
 
         self.expect(Do);
-        let mut body = self.body();
-        body.push(Stmt::ExprStmt(updater));
-        let while_body = Stmt::While(condition, body);
+        let body = self.body();
+        let while_body = Stmt::new(
+            StmtKind::While(label, condition, body, Some(updater)),
+            self.span_from(for_start),
+        );
+
+        StmtKind::Block(vec![initializer, while_body])
+    }
 
-        Stmt::Block(vec![initializer, while_body])
+    fn parse_for_in(&mut self, label: Option<String>) -> StmtKind {
+        let mut vars = vec![Variable::new(self.name())];
+        if self.match_next(vec![Comma]) {
+            vars.push(Variable::new(self.name()));
+        }
+        self.expect(In);
+        let iterable = self.expr();
+        self.expect(Do);
+        let body = self.body();
+        StmtKind::ForIn(label, vars, iterable, body)
+    }
+
+    fn parse_try(&mut self) -> StmtKind {
+        let body = self.block();
+        let mut catch = None;
+        if self.match_next(vec![Catch]) {
+            let var = Variable::new(self.name());
+            catch = Some((var, self.block()));
+        }
+        let mut finally = vec![];
+        if self.match_next(vec![Finally]) {
+            finally = self.block();
+        } else if catch.is_none() {
+            self.error_at_peek(String::from("Expected catch or finally"));
+        }
+        self.expect(End);
+        StmtKind::Try(body, catch, Rc::new(finally))
     }
 
-    fn parse_function(&mut self) -> Stmt {
-        let id = self.name();
+    fn parse_function(&mut self) -> StmtKind {
+        let id = Variable::new(self.name());
         self.expect(LeftParen);
         let params = self.args();
         let body = self.body();
-        Stmt::FunDef(id, params, Rc::new(body))
+        StmtKind::FunDef(id, params, Rc::new(Body::new(body)))
     }
 
     fn expr(&mut self) -> Expr {
+        if !self.enter() {
+            self.leave();
+            return Expr::new(ExprKind::Nil, self.peek_span());
+        }
+        let expr = self.assignment();
+        self.leave();
+        expr
+    }
+
+    fn assignment(&mut self) -> Expr {
         if self.match_next(vec![Lambda]) {
             return self.lambda();
         }
+        if self.match_next(vec![Yield]) {
+            let start = self.prev_span();
+            // A bare `yield` yields nil
+            let value = if self.check(&[Semicolon, RightParen, RightSquare, RightBrace, Comma, End])
+            {
+                Expr::new(ExprKind::Nil, start)
+            } else {
+                self.expr()
+            };
+            return Expr::new(ExprKind::Yield(Box::new(value)), self.span_from(start));
+        }
         let mut eq = self.or();
         while self.match_next(vec![Equals]) {
             let r = self.expr();
-            eq = Expr::Assign(Box::new(eq), Box::new(r));
+            let span = eq.span.to(r.span);
+            eq = Expr::new(ExprKind::Assign(Box::new(eq), Box::new(r)), span);
         }
         eq
     }
 
     fn lambda(&mut self) -> Expr {
+        let start = self.prev_span();
         self.expect(LeftParen);
         let args = self.args();
         let body = self.body();
-        Expr::LambdaDef(args, Rc::new(body))
+        Expr::new(
+            ExprKind::LambdaDef(args, Rc::new(Body::new(body))),
+            self.span_from(start),
+        )
     }
 
     fn equality(&mut self) -> Expr {
         let mut or = self.comparison();
         while self.match_next(vec![EqualsEquals, BangEquals]) {
-            let op = self.lexer.prev().unwrap().get_type();
+            let op = self.prev_type();
             let right = self.comparison();
-            or = Expr::Binary(Box::new(or), op, Box::new(right))
+            or = Parser::binary(or, op, right)
         }
         or
     }
@@ -400,9 +731,9 @@ impl Parser {
     fn or(&mut self) -> Expr {
         let mut and = self.and();
         while self.match_next(vec![Or]) {
-            let op = self.lexer.prev().unwrap().get_type();
+            let op = self.prev_type();
             let right = self.and();
-            and = Expr::Binary(Box::new(and), op, Box::new(right))
+            and = Parser::binary(and, op, right)
         }
         and
     }
@@ -410,187 +741,261 @@ impl Parser {
     fn and(&mut self) -> Expr {
         let mut comparison = self.equality();
         while self.match_next(vec![And]) {
-            let op = self.lexer.prev().unwrap().get_type();
+            let op = self.prev_type();
             let right = self.equality();
-            comparison = Expr::Binary(Box::new(comparison), op, Box::new(right))
+            comparison = Parser::binary(comparison, op, right)
         }
         comparison
     }
     fn comparison(&mut self) -> Expr {
         let mut shift = self.shift();
         while self.match_next(vec![Less, LessEquals, More, MoreEquals]) {
-            let op = self.lexer.prev().unwrap().get_type();
+            let op = self.prev_type();
             let right = self.shift();
-            shift = Expr::Binary(Box::new(shift), op, Box::new(right))
+            shift = Parser::binary(shift, op, right)
         }
         shift
     }
     fn shift(&mut self) -> Expr {
         let mut bit_or = self.bit_or();
         while self.match_next(vec![LessLess, MoreMore]) {
-            let op = self.lexer.prev().unwrap().get_type();
+            let op = self.prev_type();
             let right = self.bit_or();
-            bit_or = Expr::Binary(Box::new(bit_or), op, Box::new(right))
+            bit_or = Parser::binary(bit_or, op, right)
         }
         bit_or
     }
     fn bit_or(&mut self) -> Expr {
         let mut bit_and = self.bit_and();
         while self.match_next(vec![Pipe]) {
-            let op = self.lexer.prev().unwrap().get_type();
+            let op = self.prev_type();
             let right = self.bit_and();
-            bit_and = Expr::Binary(Box::new(bit_and), op, Box::new(right))
+            bit_and = Parser::binary(bit_and, op, right)
         }
         bit_and
     }
     fn bit_and(&mut self) -> Expr {
         let mut sum = self.sum();
         while self.match_next(vec![Ampersand]) {
-            let op = self.lexer.prev().unwrap().get_type();
+            let op = self.prev_type();
             let right = self.sum();
-            sum = Expr::Binary(Box::new(sum), op, Box::new(right))
+            sum = Parser::binary(sum, op, right)
         }
         sum
     }
     fn sum(&mut self) -> Expr {
         let mut product = self.product();
         while self.match_next(vec![Plus, Minus]) {
-            let op = self.lexer.prev().unwrap().get_type();
+            let op = self.prev_type();
             let right = self.product();
-            product = Expr::Binary(Box::new(product), op, Box::new(right))
+            product = Parser::binary(product, op, right)
         }
         product
     }
     fn product(&mut self) -> Expr {
         let mut unary = self.unary();
-        while self.match_next(vec![Star, Slash, Mod]) {
-            let op = self.lexer.prev().unwrap().get_type();
+        while self.match_next(vec![Star, Slash, SlashSlash, Mod]) {
+            let op = self.prev_type();
             let right = self.unary();
-            unary = Expr::Binary(Box::new(unary), op, Box::new(right))
+            unary = Parser::binary(unary, op, right)
         }
         unary
     }
     fn unary(&mut self) -> Expr {
-        while self.match_next(vec![Plus, Minus, Not]) {
-            let tok = self.lexer.prev().unwrap();
-            return Expr::Unary(tok.get_type(), Box::new(self.unary()));
+        if self.match_next(vec![Plus, Minus, Not]) {
+            let (op, start) = (self.prev_type(), self.prev_span());
+            if !self.enter() {
+                self.leave();
+                return Expr::new(ExprKind::Nil, start);
+            }
+            let operand = self.unary();
+            self.leave();
+            let span = start.to(operand.span);
+            return Expr::new(ExprKind::Unary(op, Box::new(operand)), span);
         }
 
         self.call()
     }
 
+    /// Member accesses, subscripts and calls, applied left to right
     fn call(&mut self) -> Expr {
-        let mut l = self.index();
-        while self.match_next(vec![Dot]) {
-            let r = self.name();
-            l = Expr::Get(Box::new(l), Box::new(Expr::Id(r)));
-        }
-        if self.match_next(vec![LeftParen]) {
-            let params = self.params();
-            l = Expr::Call(Box::new(l), params);
+        let mut l = self.literal();
+        loop {
+            if self.match_next(vec![Dot]) {
+                let name_span = self.peek_span();
+                let r = Expr::new(ExprKind::Id(Variable::new(self.member())), name_span);
+                let span = l.span.to(r.span);
+                l = Expr::new(ExprKind::Get(Box::new(l), Box::new(r)), span);
+            } else if self.match_next(vec![LeftSquare]) {
+                l = self.index(l);
+            } else if self.match_next(vec![LeftParen]) {
+                let params = self.params();
+                let span = self.span_from(l.span);
+                l = Expr::new(ExprKind::Call(Box::new(l), params), span);
+            } else {
+                return l;
+            }
         }
-        l
     }
 
-    fn index(&mut self) -> Expr {
-        let mut e = self.literal();
-        while self.match_next(vec![LeftSquare]) {
-            let ind = self.lexer.next().unwrap();
-            match ind.get_type() {
-                Identifier | Num => e = Expr::Get(Box::new(e), Box::new(Expr::new_from_tok(ind))),
-                _ => panic!("Indexes can only be identifiers or numbers!"),
+    /// `target[key]` or `target[lo:hi]`, where both bounds are optional
+    fn index(&mut self, target: Expr) -> Expr {
+        let start = target.span;
+        let lo = if self.check(&[Colon]) {
+            None
+        } else {
+            Some(Box::new(self.expr()))
+        };
+        let kind = match lo {
+            Some(key) if !self.match_next(vec![Colon]) => ExprKind::Index(Box::new(target), key),
+            lo => {
+                if lo.is_none() {
+                    self.expect(Colon);
+                }
+                let hi = if self.check(&[RightSquare]) {
+                    None
+                } else {
+                    Some(Box::new(self.expr()))
+                };
+                ExprKind::Slice(Box::new(target), lo, hi)
             }
-            self.expect(RightSquare);
-        }
-        e
+        };
+        self.expect(RightSquare);
+        Expr::new(kind, self.span_from(start))
     }
 
     fn literal(&mut self) -> Expr {
-        if self.match_next(vec![Num, Str, Identifier, True, False]) {
+        let start = self.peek_span();
+        if self.match_next(vec![Int, Num, Str, Identifier, True, False]) {
             return Expr::new_from_tok(self.lexer.prev().unwrap());
         }
         if self.match_next(vec![LeftParen]) {
             let e = self.expr();
             self.expect(RightParen);
-            return Expr::Grouping(Box::new(e));
+            return Expr::new(ExprKind::Grouping(Box::new(e)), self.span_from(start));
         }
         if self.match_next(vec![Nil]) {
-            return Expr::Nil;
+            return Expr::new(ExprKind::Nil, start);
         }
         if self.match_next(vec![LeftBrace]) {
-            return self.dictionary();
+            let dict = self.dictionary();
+            return Expr::new(dict, self.span_from(start));
         }
         if self.match_next(vec![LeftSquare]) {
-            return self.list();
+            let list = self.list();
+            return Expr::new(list, self.span_from(start));
+        }
+        if self.match_next(vec![InterpolationStart]) {
+            let interpolation = self.interpolation();
+            return Expr::new(interpolation, self.span_from(start));
         }
 
         if let Some(token) = self.lexer.peek() {
             let tok_type = token.get_type();
             self.syntax_error(
-                self.lexer.peek().unwrap(),
+                start,
                 format!("{:?} can't be parsed as an expression!", tok_type),
             );
-            self.had_error = true;
+        } else {
+            let previous_type = self.prev_type();
+            self.syntax_error(start, format!("Premature EOF after {:?}!", previous_type));
         }
 
-        let previous_type = self.lexer.prev().unwrap().get_type();
-        self.syntax_error(
-            self.lexer.prev().unwrap(),
-            format!("Premature EOF after {:?}!", previous_type),
-        );
-        self.had_error = true;
+        Expr::new(ExprKind::Nil, start)
+    }
 
-        Expr::Nil
+    /// Reads the expressions of a string and the pieces around them, the
+    /// first piece having been consumed
+    fn interpolation(&mut self) -> ExprKind {
+        let mut parts = vec![];
+        loop {
+            let piece = self.lexer.prev().unwrap();
+            let (text, span, tok_type) = (piece.as_String(), piece.get_span(), piece.get_type());
+            if !text.is_empty() {
+                parts.push(Expr::new(ExprKind::Str(text), span));
+            }
+            if tok_type == InterpolationEnd {
+                break;
+            }
+            if self.check(&[InterpolationMid, InterpolationEnd]) {
+                self.error_at_peek(String::from("Expected an expression between braces"));
+                break;
+            }
+            parts.push(self.expr());
+            if !self.match_next(vec![InterpolationMid, InterpolationEnd]) {
+                self.error_at_peek(String::from("Expected '}' after interpolated expression"));
+                break;
+            }
+        }
+        ExprKind::Interpolation(parts)
     }
 
-    fn dictionary(&mut self) -> Expr {
+    fn dictionary(&mut self) -> ExprKind {
         let mut v = vec![];
         if !self.match_next(vec![RightBrace]) {
             while {
-                let id = self.lexer.next().unwrap();
-                if id.get_type() != Identifier {
-                    panic!("Dictionary keys can only be identifiers!");
-                }
-                let id = id.as_Id();
+                let key = self.dict_key();
                 self.expect(Colon);
 
                 let expr = self.expr();
 
-                v.push((id, expr));
-                self.match_next(vec![Comma])
+                v.push((key, expr));
+                !self.panic_mode && self.match_next(vec![Comma])
             } {}
             self.expect(RightBrace);
         }
-        Expr::DictDef(v)
+        ExprKind::DictDef(v)
+    }
+
+    /// A bare name, a literal, or `[expr]` for a computed key
+    fn dict_key(&mut self) -> Expr {
+        let start = self.peek_span();
+        if self.match_next(vec![Identifier]) {
+            let name = self.lexer.prev().unwrap().as_Id();
+            return Expr::new(ExprKind::Str(name), start);
+        }
+        if self.match_next(vec![Int, Num, Str, True, False]) {
+            return Expr::new_from_tok(self.lexer.prev().unwrap());
+        }
+        if self.match_next(vec![LeftSquare]) {
+            let key = self.expr();
+            self.expect(RightSquare);
+            return key;
+        }
+        self.error_at_peek(String::from(
+            "Dictionary keys can only be names, literals or [expressions]",
+        ));
+        Expr::new(ExprKind::Nil, start)
     }
 
-    fn list(&mut self) -> Expr {
+    fn list(&mut self) -> ExprKind {
         let mut v = vec![];
         if !self.match_next(vec![RightSquare]) {
             while {
                 let expr = self.expr();
 
                 v.push(expr);
-                self.match_next(vec![Comma])
+                !self.panic_mode && self.match_next(vec![Comma])
             } {}
             self.expect(RightSquare);
         }
-        Expr::ListDef(v)
+        ExprKind::ListDef(v)
     }
 
     fn name(&mut self) -> String {
-        let tok = self.lexer.next().unwrap().get_type();
-        match tok {
-            Identifier => self.lexer.prev().unwrap().as_Id(), // OK
-            _ => {
-                self.syntax_error(
-                    self.lexer.prev().unwrap(),
-                    String::from("Expected identifier!"),
-                );
-                self.had_error = true;
-                String::from("none duh")
-            }
+        if self.match_next(vec![Identifier]) {
+            return self.lexer.prev().unwrap().as_Id();
+        }
+        self.error_at_peek(String::from("Expected identifier"));
+        String::from("none duh")
+    }
+
+    // `yield` is a keyword, but still names coroutine.yield
+    fn member(&mut self) -> String {
+        if self.match_next(vec![Yield]) {
+            return String::from("yield");
         }
+        self.name()
     }
 
     fn params(&mut self) -> Vec<Expr> {
@@ -599,7 +1004,7 @@ impl Parser {
             while {
                 let e = self.expr();
                 v.push(e);
-                self.match_next(vec![Comma])
+                !self.panic_mode && self.match_next(vec![Comma])
             } {}
             self.expect(RightParen);
         }
@@ -609,24 +1014,39 @@ impl Parser {
         let mut v = vec![];
         if !self.match_next(vec![RightParen]) {
             while {
-                let e = self.lexer.next().unwrap();
-                if e.get_type() != Identifier {
-                    panic!("Params can only be identifiers!");
+                if self.match_next(vec![Identifier]) {
+                    v.push(self.lexer.prev().unwrap().as_Id());
+                } else {
+                    self.error_at_peek(String::from("Params can only be identifiers"));
                 }
-                v.push(e.as_Id());
-                self.match_next(vec![Comma])
+                !self.panic_mode && self.match_next(vec![Comma])
             } {}
             self.expect(RightParen);
         }
         v
     }
-    fn body(&mut self) -> Vec<Stmt> {
+
+    /// Parses statements until the end of the block, without consuming
+    /// the token that closes it
+    fn block(&mut self) -> Vec<Stmt> {
         let mut v = vec![];
-        while {
+        if !self.enter() {
+            self.leave();
+            return v;
+        }
+        self.block_depth += 1;
+        while !self.lexer.is_at_end() && !self.check(&[End, Elif, Else, Catch, Finally]) {
             let s = self.statement();
             v.push(s);
-            !self.match_next(vec![End])
-        } {}
+        }
+        self.block_depth -= 1;
+        self.leave();
+        v
+    }
+
+    fn body(&mut self) -> Vec<Stmt> {
+        let v = self.block();
+        self.expect(End);
         v
     }
 }
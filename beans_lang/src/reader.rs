@@ -6,55 +6,93 @@ mod tests {
         let test = String::from("abcd");
         let mut r = Reader::new(test);
 
-        assert!(r.prev() == None);
         assert!(r.peek().unwrap() == 'a');
         assert!(r.next().unwrap() == 'a');
 
-        assert!(r.prev().unwrap() == 'a');
         assert!(r.peek().unwrap() == 'b');
         assert!(r.next().unwrap() == 'b');
 
-        assert!(r.prev().unwrap() == 'b');
         assert!(r.peek().unwrap() == 'c');
         assert!(r.next().unwrap() == 'c');
 
-        assert!(r.prev().unwrap() == 'c');
         assert!(r.peek().unwrap() == 'd');
         assert!(r.next().unwrap() == 'd');
 
-        assert!(r.prev().unwrap() == 'd');
         assert!(r.peek() == None);
         assert!(r.next() == None);
     }
+
+    #[test]
+    fn test_reader_position() {
+        let test = String::from("aè\nb");
+        let mut r = Reader::new(test);
+
+        r.next();
+        r.next();
+        assert!(r.offset() == 3);
+        assert!(r.line() == 1 && r.column() == 3);
+        r.next();
+        assert!(r.line() == 2 && r.column() == 1);
+        assert!(r.slice(1, 3) == "è");
+    }
 }
 
+/// Cursor over the chars of a string, tracking byte offset, line and column
 pub struct Reader {
     content: String,
-    pos: usize,
+    offset: usize,
+    line: usize,
+    column: usize,
 }
 
 impl Reader {
     pub fn new(input: String) -> Reader {
         Reader {
             content: input,
-            pos: 0,
+            offset: 0,
+            line: 1,
+            column: 1,
         }
     }
 
     pub fn peek(&self) -> Option<char> {
-        self.content.chars().nth(self.pos)
+        self.content[self.offset..].chars().next()
+    }
+
+    /// The char after the one `peek` returns
+    pub fn peek_second(&self) -> Option<char> {
+        self.content[self.offset..].chars().nth(1)
     }
 
     pub fn next(&mut self) -> Option<char> {
-        let ch = self.content.chars().nth(self.pos);
-        self.pos += 1;
+        let ch = self.peek();
+        if let Some(c) = ch {
+            self.offset += c.len_utf8();
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
         ch
     }
 
-    pub fn prev(&self) -> Option<char> {
-        if self.pos == 0 {
-            return None;
-        }
-        self.content.chars().nth(self.pos - 1)
+    /// Byte offset of the next char
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// The text between two byte offsets
+    pub fn slice(&self, start: usize, end: usize) -> &str {
+        &self.content[start..end]
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn column(&self) -> usize {
+        self.column
     }
 }
@@ -0,0 +1,499 @@
+use super::environments::Env;
+use super::errors::{Diagnostic, ErrorKind};
+use super::node::*;
+use super::span::Span;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn resolve(program: &str) -> (Vec<Stmt>, Vec<Diagnostic>) {
+        let mut parser = Parser::new(Lexer::new(String::from(program)));
+        let stmts = parser.parse();
+        assert!(!parser.error());
+        let mut env = Env::new();
+        env.insert(String::from("print"), crate::environments::Value::Nil);
+        let mut resolver = Resolver::new(Rc::new(RefCell::new(env)));
+        resolver.resolve(&stmts);
+        (stmts, resolver.diagnostics().clone())
+    }
+
+    #[test]
+    fn test_slots() {
+        let (stmts, diagnostics) =
+            resolve("var g = 1; function f(a, b) var c = a; lambda() return c + b + g; end; end");
+        assert!(diagnostics.is_empty());
+        let body = match &stmts[1].kind {
+            StmtKind::FunDef(_, _, body) => body.clone(),
+            _ => unreachable!(),
+        };
+        match &body[0].kind {
+            StmtKind::Var(c, Some(init)) => {
+                assert!(c.slot.get() == Slot::Local { depth: 0, slot: 2 });
+                match &init.kind {
+                    ExprKind::Id(a) => assert!(a.slot.get() == Slot::Local { depth: 0, slot: 0 }),
+                    _ => unreachable!(),
+                }
+            }
+            _ => unreachable!(),
+        }
+        let lambda = match &body[1].kind {
+            StmtKind::ExprStmt(Expr {
+                kind: ExprKind::LambdaDef(_, body),
+                ..
+            }) => body.clone(),
+            _ => unreachable!(),
+        };
+        let slots = match &lambda[0].kind {
+            StmtKind::Return(Expr {
+                kind: ExprKind::Binary(l, _, g),
+                ..
+            }) => match (&l.kind, &g.kind) {
+                (ExprKind::Binary(c, _, b), ExprKind::Id(g)) => match (&c.kind, &b.kind) {
+                    (ExprKind::Id(c), ExprKind::Id(b)) => {
+                        (c.slot.get(), b.slot.get(), g.slot.get())
+                    }
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        assert!(slots.0 == Slot::Local { depth: 1, slot: 2 });
+        assert!(slots.1 == Slot::Local { depth: 1, slot: 1 });
+        assert!(slots.2 == Slot::Global);
+
+        // Blocks declaring names and loop bodies are environments of their own
+        let (stmts, diagnostics) = resolve("for x in [1] do if x then var y = x; end end");
+        assert!(diagnostics.is_empty());
+        let body = match &stmts[0].kind {
+            StmtKind::ForIn(_, vars, _, body) => {
+                assert!(vars[0].slot.get() == Slot::Local { depth: 0, slot: 0 });
+                body.clone()
+            }
+            _ => unreachable!(),
+        };
+        match &body[0].kind {
+            StmtKind::If(branches, _) => match &branches[0].1[0].kind {
+                StmtKind::Var(y, Some(init)) => {
+                    assert!(y.slot.get() == Slot::Local { depth: 0, slot: 0 });
+                    match &init.kind {
+                        ExprKind::Id(x) => {
+                            assert!(x.slot.get() == Slot::Local { depth: 1, slot: 0 })
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_resolves_in_order() {
+        let (stmts, diagnostics) =
+            resolve("var x = 5; function f() print(x); var x = 1; if x then print(x); end end");
+        assert!(diagnostics.is_empty());
+        let body = match &stmts[1].kind {
+            StmtKind::FunDef(_, _, body) => body.clone(),
+            _ => unreachable!(),
+        };
+        let arg = |stmt: &Stmt| match &stmt.kind {
+            StmtKind::ExprStmt(Expr {
+                kind: ExprKind::Call(_, args),
+                ..
+            }) => match &args[0].kind {
+                ExprKind::Id(x) => x.slot.get(),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        assert!(arg(&body[0]) == Slot::Global);
+        match &body[2].kind {
+            StmtKind::If(branches, _) => {
+                assert!(arg(&branches[0].1[0]) == Slot::Local { depth: 0, slot: 0 })
+            }
+            _ => unreachable!(),
+        }
+
+        // Functions run after the names of the enclosing one are declared
+        let (_, diagnostics) = resolve(
+            "function f() function a() return b(); end function b() return 1; end return a(); end",
+        );
+        assert!(diagnostics.is_empty());
+        let (_, diagnostics) = resolve("function f() print(y); var y = 1; end");
+        assert!(diagnostics.len() == 1);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let (_, diagnostics) =
+            resolve("print(x); function f() return later + y; end var later = 1;");
+        assert!(diagnostics.len() == 2);
+        assert!(diagnostics
+            .iter()
+            .all(|d| d.kind == ErrorKind::UndefinedVariable));
+        assert!(diagnostics[0].message.contains("'x'"));
+        assert!(diagnostics[1].message.contains("'y'"));
+
+        let (_, diagnostics) = resolve("var a = 1; var a = 2; function f(b) var b = 3; end");
+        assert!(diagnostics.len() == 2);
+        assert!(diagnostics
+            .iter()
+            .all(|d| d.kind == ErrorKind::Redeclaration));
+
+        // Each statement list declares its own names
+        let (_, diagnostics) =
+            resolve("var i = 0; while i < 2 do var i = 3; end if true then var i; end");
+        assert!(diagnostics.is_empty());
+
+        // Names declared in blocks are gone after them
+        let (_, diagnostics) = resolve(
+            "if true then var a = 1; end for var i = 0; i < 2; i = i + 1 do end print(a + i);",
+        );
+        assert!(diagnostics.len() == 2);
+
+        let (_, diagnostics) =
+            resolve("a: while true do break a; end while true do continue a; end b: while true do lambda() break b; end; end");
+        assert!(diagnostics.len() == 2);
+        assert!(diagnostics
+            .iter()
+            .all(|d| d.kind == ErrorKind::InvalidControlFlow));
+    }
+}
+
+/// Binds every variable to where it lives before the program runs.
+///
+/// Top-level names are globals, looked up by name. Names declared in a
+/// function, including its parameters, get a slot of the function call's
+/// environment. Blocks declaring names run in an environment of their own,
+/// where those names get their slots, and so do the variables of `for`
+/// loops, with a new environment for every iteration.
+///
+/// Names are resolved in order: until its declaration, a name of the
+/// running function or of the top level refers to whatever it named
+/// outside. Functions nested in it run later, and see all of its names.
+pub struct Resolver {
+    env: Rc<RefCell<Env>>,
+    // Names declared at the top level of the program
+    globals: HashSet<String>,
+    // Globals not declared yet by the top-level statements
+    pending_globals: HashSet<String>,
+    // Slots of every enclosing environment, innermost last
+    scopes: Vec<HashMap<String, usize>>,
+    // Names of each environment not declared yet
+    pending: Vec<HashSet<String>>,
+    // Index in `scopes` of the outermost environment of the current function
+    function_base: usize,
+    // Names declared in every open statement list
+    lists: Vec<HashSet<String>>,
+    // Labels of the loops enclosing the current statement in its function
+    labels: Vec<Option<String>>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Resolver {
+    /// `env` holds the names defined before the program runs
+    pub fn new(env: Rc<RefCell<Env>>) -> Resolver {
+        Resolver {
+            env,
+            globals: HashSet::new(),
+            pending_globals: HashSet::new(),
+            scopes: vec![],
+            pending: vec![],
+            function_base: 0,
+            lists: vec![],
+            labels: vec![],
+            diagnostics: vec![],
+        }
+    }
+
+    pub fn error(&self) -> bool {
+        !self.diagnostics.is_empty()
+    }
+
+    /// Every undeclared variable and redeclaration found by the last call to resolve
+    pub fn diagnostics(&self) -> &Vec<Diagnostic> {
+        &self.diagnostics
+    }
+
+    pub fn resolve(&mut self, stmts: &[Stmt]) {
+        self.diagnostics.clear();
+        let mut globals = vec![];
+        Resolver::declarations(stmts, &mut globals);
+        self.globals = globals.into_iter().collect();
+        self.pending_globals = self.globals.clone();
+        self.block(stmts, HashSet::new());
+    }
+
+    /// Collects the names declared in `stmts`, outside of nested blocks
+    fn declarations(stmts: &[Stmt], names: &mut Vec<String>) {
+        for stmt in stmts {
+            match &stmt.kind {
+                StmtKind::Var(var, _)
+                | StmtKind::FunDef(var, _, _)
+                | StmtKind::StructDef(var, _)
+                | StmtKind::EnumDef(var, _)
+                | StmtKind::Import(var, _) => names.push(var.name.clone()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Whether a block declares names, and so runs in an environment of its own
+    pub(crate) fn declares(stmts: &[Stmt]) -> bool {
+        stmts.iter().any(|stmt| {
+            matches!(
+                stmt.kind,
+                StmtKind::Var(..)
+                    | StmtKind::FunDef(..)
+                    | StmtKind::StructDef(..)
+                    | StmtKind::EnumDef(..)
+                    | StmtKind::Import(..)
+            )
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Option<Slot> {
+        for (depth, slots) in self.scopes.iter().rev().enumerate() {
+            let index = self.scopes.len() - 1 - depth;
+            if index >= self.function_base && self.pending[index].contains(name) {
+                continue;
+            }
+            if let Some(slot) = slots.get(name) {
+                return Some(Slot::Local { depth, slot: *slot });
+            }
+        }
+        let global = self.globals.contains(name) && !self.pending_globals.contains(name);
+        if global || self.env.borrow().contains(name) {
+            Some(Slot::Global)
+        } else {
+            None
+        }
+    }
+
+    fn variable(&mut self, var: &Variable, span: Span) {
+        match self.lookup(&var.name) {
+            Some(slot) => var.slot.set(slot),
+            None => self.diagnostics.push(Diagnostic::with_kind(
+                ErrorKind::UndefinedVariable,
+                format!("Undefined variable '{}'", var.name),
+                span,
+            )),
+        }
+    }
+
+    /// Records a declaration in the current statement list.
+    /// Only `var` may not reuse a name declared there.
+    fn declare(&mut self, var: &Variable, is_var: bool, span: Span) {
+        let list = self.lists.last_mut().unwrap();
+        if !list.insert(var.name.clone()) && is_var {
+            self.diagnostics.push(Diagnostic::with_kind(
+                ErrorKind::Redeclaration,
+                format!("Variable '{}' is already declared", var.name),
+                span,
+            ));
+        }
+        match self.pending.last_mut() {
+            Some(pending) => pending.remove(&var.name),
+            None => self.pending_globals.remove(&var.name),
+        };
+        self.variable(var, span);
+    }
+
+    fn block(&mut self, stmts: &[Stmt], declared: HashSet<String>) {
+        self.lists.push(declared);
+        for stmt in stmts {
+            self.statement(stmt);
+        }
+        self.lists.pop();
+    }
+
+    /// Enters an environment giving the first slots to `first` and the
+    /// next ones to the names declared in `stmts`, which are pending until
+    /// their declaration
+    fn push_scope(&mut self, first: &[String], stmts: &[Stmt]) {
+        let mut declared = vec![];
+        Resolver::declarations(stmts, &mut declared);
+        let mut slots = HashMap::new();
+        for name in first.iter().chain(&declared) {
+            let next = slots.len();
+            slots.entry(name.clone()).or_insert(next);
+        }
+        self.scopes.push(slots);
+        self.pending.push(
+            declared
+                .into_iter()
+                .filter(|name| !first.contains(name))
+                .collect(),
+        );
+    }
+
+    fn pop_scope(&mut self) {
+        self.scopes.pop();
+        self.pending.pop();
+    }
+
+    /// Resolves the body of an `if`, a loop, a block or a `catch` clause,
+    /// binding the loop or error variables `vars` in its environment
+    fn scope(&mut self, stmts: &[Stmt], vars: &[Variable], span: Span) {
+        let scoped = !vars.is_empty() || Resolver::declares(stmts);
+        if scoped {
+            let names: Vec<String> = vars.iter().map(|var| var.name.clone()).collect();
+            self.push_scope(&names, stmts);
+        }
+        // Loop variables belong to the loop, not to the statement list
+        for var in vars {
+            self.variable(var, span);
+        }
+        self.block(stmts, HashSet::new());
+        if scoped {
+            self.pop_scope();
+        }
+    }
+
+    fn function(&mut self, params: &[String], body: &[Stmt], span: Span) {
+        let mut declared = HashSet::new();
+        for param in params {
+            if !declared.insert(param.clone()) {
+                self.diagnostics.push(Diagnostic::with_kind(
+                    ErrorKind::Redeclaration,
+                    format!("Parameter '{}' is already declared", param),
+                    span,
+                ));
+            }
+        }
+        let base = std::mem::replace(&mut self.function_base, self.scopes.len());
+        self.push_scope(params, body);
+        let pending_globals = std::mem::take(&mut self.pending_globals);
+        // Loops outside of the function can't be left from inside it
+        let labels = std::mem::take(&mut self.labels);
+        // Parameters belong to the outermost statement list of the body
+        self.block(body, declared);
+        self.labels = labels;
+        self.pending_globals = pending_globals;
+        self.pop_scope();
+        self.function_base = base;
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        let span = stmt.span;
+        match &stmt.kind {
+            StmtKind::ExprStmt(e) | StmtKind::Return(e) => self.expr(e),
+            StmtKind::If(branches, else_block) => {
+                for (cond, body) in branches {
+                    self.expr(cond);
+                    self.scope(body, &[], span);
+                }
+                self.scope(else_block, &[], span);
+            }
+            StmtKind::While(label, cond, body, updater) => {
+                self.expr(cond);
+                if let Some(e) = updater {
+                    self.expr(e);
+                }
+                self.labels.push(label.clone());
+                self.scope(body, &[], span);
+                self.labels.pop();
+            }
+            StmtKind::ForIn(label, vars, iterable, body) => {
+                self.expr(iterable);
+                self.labels.push(label.clone());
+                self.scope(body, vars, span);
+                self.labels.pop();
+            }
+            StmtKind::Block(stmts) => self.scope(stmts, &[], span),
+            StmtKind::Try(body, catch, finally) => {
+                self.scope(body, &[], span);
+                if let Some((var, handler)) = catch {
+                    self.scope(handler, std::slice::from_ref(var), span);
+                }
+                self.scope(finally, &[], span);
+            }
+            StmtKind::Var(var, init) => {
+                if let Some(e) = init {
+                    self.expr(e);
+                }
+                self.declare(var, true, span);
+            }
+            StmtKind::FunDef(var, params, body) => {
+                self.declare(var, false, span);
+                self.function(params, body, span);
+            }
+            StmtKind::StructDef(var, _) => self.declare(var, false, span),
+            StmtKind::EnumDef(var, values) => {
+                for (_, value) in values {
+                    if let Some(e) = value {
+                        self.expr(e);
+                    }
+                }
+                self.declare(var, false, span);
+            }
+            StmtKind::Break(Some(label)) | StmtKind::Continue(Some(label)) => {
+                if !self.labels.contains(&Some(label.clone())) {
+                    self.diagnostics.push(Diagnostic::with_kind(
+                        ErrorKind::InvalidControlFlow,
+                        format!("No enclosing loop is labeled '{}'", label),
+                        span,
+                    ));
+                }
+            }
+            StmtKind::Import(var, _) => self.declare(var, false, span),
+            StmtKind::Break(None) | StmtKind::Continue(None) => {}
+        }
+    }
+
+    fn expr(&mut self, e: &Expr) {
+        match &e.kind {
+            ExprKind::Id(var) => self.variable(var, e.span),
+            ExprKind::Unary(_, operand)
+            | ExprKind::Grouping(operand)
+            | ExprKind::Yield(operand) => self.expr(operand),
+            ExprKind::Binary(l, _, r) | ExprKind::Assign(l, r) => {
+                self.expr(l);
+                self.expr(r);
+            }
+            ExprKind::Call(callee, args) => {
+                self.expr(callee);
+                for arg in args {
+                    self.expr(arg);
+                }
+            }
+            // Keys name members, not variables
+            ExprKind::Get(base, _) => self.expr(base),
+            ExprKind::Index(base, key) => {
+                self.expr(base);
+                self.expr(key);
+            }
+            ExprKind::Slice(base, lo, hi) => {
+                self.expr(base);
+                for bound in lo.iter().chain(hi) {
+                    self.expr(bound);
+                }
+            }
+            ExprKind::DictDef(elts) => {
+                for (key, value) in elts {
+                    self.expr(key);
+                    self.expr(value);
+                }
+            }
+            ExprKind::ListDef(elts) | ExprKind::Interpolation(elts) => {
+                for elt in elts {
+                    self.expr(elt);
+                }
+            }
+            ExprKind::LambdaDef(params, body) => self.function(params, body, e.span),
+            ExprKind::Int(_)
+            | ExprKind::Num(_)
+            | ExprKind::Str(_)
+            | ExprKind::Bool(_)
+            | ExprKind::Nil => {}
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::cell::RefCell;
+
+pub type FileId = usize;
+
+thread_local! {
+    static FILES: RefCell<Vec<String>> = RefCell::new(vec![String::from("<string>")]);
+}
+
+/// Registers a source file name, returning the id its spans will carry.
+/// Id 0 is reserved for code that doesn't come from a file.
+pub fn register_file(name: &str) -> FileId {
+    FILES.with(|files| {
+        let mut files = files.borrow_mut();
+        if let Some(id) = files.iter().position(|file| file == name) {
+            return id;
+        }
+        files.push(String::from(name));
+        files.len() - 1
+    })
+}
+
+pub fn file_name(id: FileId) -> Option<String> {
+    if id == 0 {
+        return None;
+    }
+    FILES.with(|files| files.borrow().get(id).cloned())
+}
+
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub struct Span {
+    pub file: FileId,
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn new(file: FileId, start: usize, end: usize, line: usize, column: usize) -> Span {
+        Span {
+            file,
+            start,
+            end,
+            line,
+            column,
+        }
+    }
+
+    /// Returns a span going from the start of self to the end of other
+    pub fn to(&self, other: Span) -> Span {
+        Span {
+            end: other.end,
+            ..*self
+        }
+    }
+}
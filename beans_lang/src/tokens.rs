@@ -1,92 +1,109 @@
 
 use std::string::String;
 use std::collections::HashMap;
+use std::rc::Rc;
+use super::span::Span;
 #[derive(PartialEq, PartialOrd, Copy, Clone, Debug)]
 pub enum TokenType { 
-End,
-Pass,
-False,
-While,
-Do,
-Nil,
-Import,
-Str,
-Num,
 Ampersand,
+And,
+BangEquals,
 Break,
+Catch,
 Colon,
-Slash,
+Comma,
 Continue,
-Not,
-RightSquare,
-Pipe,
-Equals,
-Star,
-Elif,
-Return,
-RightBrace,
-EqualsEquals,
-BangEquals,
-LessEquals,
-Lambda,
+Do,
 Dot,
+Elif,
+Else,
+End,
 Enum,
-Less,
-Var,
-LeftSquare,
 Eof,
-If,
-Identifier,
-RightParen,
+Equals,
+EqualsEquals,
+False,
+Finally,
+For,
 Function,
-LeftParen,
+Identifier,
+If,
+Import,
+In,
+Int,
+InterpolationEnd,
+InterpolationMid,
+InterpolationStart,
+Lambda,
 LeftBrace,
-MoreMore,
-Plus,
-For,
-Comma,
-True,
-Or,
+LeftParen,
+LeftSquare,
+Less,
+LessEquals,
 LessLess,
-Else,
-More,
 Minus,
+Mod,
+More,
+MoreEquals,
+MoreMore,
+Nil,
+Not,
+Num,
+Or,
+Pass,
+Pipe,
+Plus,
+Return,
+RightBrace,
+RightParen,
+RightSquare,
 Semicolon,
+Slash,
+SlashSlash,
+Star,
+Str,
 Struct,
-Mod,
-And,
 Then,
-MoreEquals,
+True,
+Try,
+Var,
+While,
+Yield,
 
 }
 
 lazy_static! {
         pub static ref TOKENS_MAP : HashMap<String, TokenType> = {
         let mut m = HashMap::new();
-        m.insert(String::from("end"), TokenType::End);
-        m.insert(String::from("pass"), TokenType::Pass);
-        m.insert(String::from("false"), TokenType::False);
-        m.insert(String::from("while"), TokenType::While);
-        m.insert(String::from("do"), TokenType::Do);
-        m.insert(String::from("nil"), TokenType::Nil);
-        m.insert(String::from("import"), TokenType::Import);
+        m.insert(String::from("and"), TokenType::And);
         m.insert(String::from("break"), TokenType::Break);
+        m.insert(String::from("catch"), TokenType::Catch);
         m.insert(String::from("continue"), TokenType::Continue);
-        m.insert(String::from("not"), TokenType::Not);
+        m.insert(String::from("do"), TokenType::Do);
         m.insert(String::from("elif"), TokenType::Elif);
-        m.insert(String::from("return"), TokenType::Return);
-        m.insert(String::from("lambda"), TokenType::Lambda);
+        m.insert(String::from("else"), TokenType::Else);
+        m.insert(String::from("end"), TokenType::End);
         m.insert(String::from("enum"), TokenType::Enum);
-        m.insert(String::from("var"), TokenType::Var);
-        m.insert(String::from("if"), TokenType::If);
-        m.insert(String::from("function"), TokenType::Function);
+        m.insert(String::from("false"), TokenType::False);
+        m.insert(String::from("finally"), TokenType::Finally);
         m.insert(String::from("for"), TokenType::For);
-        m.insert(String::from("true"), TokenType::True);
+        m.insert(String::from("function"), TokenType::Function);
+        m.insert(String::from("if"), TokenType::If);
+        m.insert(String::from("import"), TokenType::Import);
+        m.insert(String::from("in"), TokenType::In);
+        m.insert(String::from("lambda"), TokenType::Lambda);
+        m.insert(String::from("nil"), TokenType::Nil);
+        m.insert(String::from("not"), TokenType::Not);
         m.insert(String::from("or"), TokenType::Or);
-        m.insert(String::from("else"), TokenType::Else);
+        m.insert(String::from("pass"), TokenType::Pass);
+        m.insert(String::from("return"), TokenType::Return);
         m.insert(String::from("struct"), TokenType::Struct);
-        m.insert(String::from("and"), TokenType::And);
         m.insert(String::from("then"), TokenType::Then);
+        m.insert(String::from("true"), TokenType::True);
+        m.insert(String::from("try"), TokenType::Try);
+        m.insert(String::from("var"), TokenType::Var);
+        m.insert(String::from("while"), TokenType::While);
+        m.insert(String::from("yield"), TokenType::Yield);
 
         m
     };
@@ -96,22 +113,24 @@ lazy_static! {
 pub enum Value {
     Empty,
     Num(f64),
+    Int(i64),
     Str(String),
-    Id(String),
+    // Interned by the lexer, so repeated names share their text
+    Id(Rc<str>),
 }
 
 #[derive(Debug)]
 pub struct Token {
     token_type : TokenType,
-    line: u16,
+    span: Span,
     val: Value,
 }
 
 impl Token{
-    pub fn new(token_type : TokenType, line : u16, val : Value) -> Token {
+    pub fn new(token_type : TokenType, span : Span, val : Value) -> Token {
         Token {
             token_type : token_type,
-            line : line,
+            span : span,
             val : val,
         }
     }
@@ -124,8 +143,12 @@ impl Token{
         self.token_type
     }
 
-    pub fn get_line(&self) -> u16 {
-        self.line
+    pub fn get_line(&self) -> usize {
+        self.span.line
+    }
+
+    pub fn get_span(&self) -> Span {
+        self.span
     }
 
     pub fn as_f64(&self) -> f64 {
@@ -135,6 +158,13 @@ impl Token{
         }
     }
 
+    pub fn as_i64(&self) -> i64 {
+        match self.val {
+            Value::Int(n) => n,
+            _ => panic!("Falied converting Value to i64!")
+        }
+    }
+
     pub fn as_String(&self) -> String {
         match &self.val {
             Value::Str(s) => s.clone(),
@@ -144,7 +174,7 @@ impl Token{
 
     pub fn as_Id(&self) -> String {
         match &self.val {
-            Value::Id(s) => s.clone(),
+            Value::Id(s) => s.to_string(),
             _ => panic!("Falied converting Value to Id!")
         }
     }
@@ -0,0 +1,818 @@
+use super::compiler::*;
+use super::coroutine::{Resumed, Status};
+use super::dict::{Dict, HashKey};
+use super::environments::*;
+use super::errors::*;
+use super::evaluator::*;
+use super::iteration::Iteration;
+use super::span::Span;
+use super::tokens::TokenType;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+#[cfg(test)]
+mod tests {
+    use super::super::beans;
+    use super::*;
+    use std::time::Instant;
+
+    fn run(program: &str, backend: Backend) -> String {
+        let glob = beans::create_global();
+        glob.borrow_mut().build_stdlib();
+        let mut evaluator = beans::create_evaluator_with(glob, backend);
+        show(beans::do_string(String::from(program), &mut evaluator))
+    }
+
+    fn show(result: StatementResult) -> String {
+        match result {
+            StatementResult::Ok(v) => format!("Ok {}", v.stringify()),
+            StatementResult::Return(v) => format!("Return {}", v.stringify()),
+            StatementResult::Break(_) => String::from("Break"),
+            StatementResult::Continue(_) => String::from("Continue"),
+            StatementResult::Failure(why) => format!("{}\n{}", why, why.format_traceback()),
+        }
+    }
+
+    #[test]
+    fn test_same_results() {
+        let programs = [
+            "var a = 0; var i = 10; while i > 0 do a = a + i; i = i - 1; end a;",
+            "function f(n) if n < 2 then return n; end return f(n - 1) + f(n - 2); end f(15);",
+            "var x = 1; function g() x = x + 1; return x; end g(); g();",
+            "var d = {a: 1, b: 2}; d.c = d.a + d.b; d.c;",
+            "var l = [1, 2, 3]; l[2] = 5; l[2];",
+            "var l = [1, 2, 3]; l[5];",
+            "var s = 0; var i = 0; while i < 10 do i = i + 1; if i == 3 then continue; end s = s + i; end s;",
+            "var s = 0; for var i = 0; i < 10; i = i + 1 do s = s + i; end s;",
+            "var s = 0; while s < 3 do s = s + 1; break; s = 100; end s;",
+            "if true then return 3; end",
+            "break;",
+            "if true then continue; end",
+            "function h() continue; end h();",
+            "1 and 2; nil or \"x\";",
+            "enum Colors {Red, Blue = 20, Green} Colors.Green;",
+            "enum E {A = \"x\"}",
+            "struct P {x, y} var p = P(1, 2); p.y;",
+            "var f = lambda(x) return x * 2; end; f(1, 2);",
+            "var n = 3; n(print(\"not printed\"));",
+            "function inner(x) return x[5]; end\nfunction outer() return inner([]); end\nouter();",
+            "math.atan2(1, \"a\");",
+            "undefined = 3;",
+            "-nil;",
+            "\"a\" + 1;",
+            "[1, \"a\", [nil]] == [1, \"a\", [nil]];",
+            "var d = {}; d == {} or d != d;",
+            "var l = [1, 2, 3]; var i = 0; l[i - 1] = l[i] + 10; l[-1];",
+            "\"hello\"[1:-1];",
+            "var d = {a: 1, 2: \"b\", [true]: 3}; d[2] = d.a + d[true]; d;",
+            "var d = {[nil]: 1};",
+            "[1, 2][5];",
+            "var a = [1, 2]; var b = a; b[1] = 5; function f(l) l[0] = 7; end f(a); [a[0], a[1]];",
+            "[7 // -2, 2 * 3.5, 1 << 10, -5 % 3, 255 & 15 | 256, 1 / 4, 3 < 2.5];",
+            "var a = -7; var b = 3; [a % b, a % 3, 7 % -b, a % -3, a % 2.5, 7.5 % -b];",
+            "9223372036854775807 * 2;",
+            "1 // 0;",
+            "1 / 0;",
+            "var z = 0.0; [2.5 / 2, 2.5 / z];",
+            "var z = 0.0; 7 % z;",
+            "var s = 0; for i in range(10) do s = s + i; end for i in range(5, -5, -2) do s = s + i; end s;",
+            "var s = \"\"; for k, v in {a: 1, b: [2]} do s = \"{s}{k}{v[0]}\"; end s;",
+            "function f(l) for i, x in l do if x > 1 then return i; end end end f([1, 5, 2]);",
+            "var l = [1, 2, 3]; var s = 0; for x in l do l[2] = 10; s = s + x; end s;",
+            "for x in range(1, 2, 0) do end",
+            "var x = 1; if x then var x = 2; x = x + 1; end x;",
+            "var s = \"\"; try [][1]; catch e s = \"{e.kind} {e.value}\"; finally s = \"{s}!\"; end s;",
+            "function f(n) try if n then error({n: n}); end return 0; catch e return e.value.n; finally n = 5; end end [f(nil), f(3)];",
+            "var s = 0; for i in range(6) do try if i == 4 then break; end if i % 2 == 0 then continue; end s = s + i; finally s = s + 10; end end s;",
+            "var s = \"\"; a: for x in [1, 2] do for y in [1, 2] do try if y == 2 then continue a; end s = \"{s}{x}\"; finally s = \"{s}f\"; end end end s;",
+            "try try error(\"in\"); finally var x = 1; end catch e error(e); end",
+            "function g() error(\"deep\"); end function h() try g(); catch e return e.traceback; end end h();",
+            "try error(\"a\"); catch e error(\"b\"); finally print(\"cleanup\"); end",
+
+            "var fs = [nil, nil]; for var i = 0; i < 2; i = i + 1 do var j = i * 10; fs[i] = lambda() i = i + j; return i; end; end [fs[0](), fs[1](), fs[1]()];",
+            "var fs = [nil, nil]; var n = 0; while n < 2 do var m = n; fs[n] = lambda() return m; end; n = n + 1; end [fs[0](), fs[1]()];",
+            "function f() var s = 0; a: for x in [1, 2, 3] do var y = x; for z in [y] do var w = z; if w == 2 then continue a; end s = s + w; end end return s; end f();",
+            "var s = 0; for var i = 0; i < 10; i = i + 1 do if i % 2 == 0 then continue; end s = s + i; end s;",
+            "var s = \"\"; a: for x in [1, 2, 3] do for y in \"xyz\" do if y == \"y\" then continue a; end if x == 3 then break a; end s = \"{s}{x}{y}\"; end end s;",
+            "function f() var n = 0; w: while true do for i in range(5) do n = n + i; if n > 7 then break w; end end end return n; end [f(), f()];",
+            "var words = string.split(\"to be or not\"); string.join(words, string.upper(\"_\")) + string.len(words[0]);",
+            "string.sub(\"abc\", \"x\");",
+            "var n = 2; \"{n} + {n * 1.5} = {[n][0] + 3}{\"!\"}\";",
+            "var maker = lambda() var c = 0; return lambda() c = c + 1; return c; end; end; var c = maker(); c(); c();",
+            "function count(n) for i in range(n) do yield i * i; end end var s = []; for x in count(3) do s = [s, x]; end s;",
+            "function kv() yield [\"a\", 1]; yield [\"b\", 2]; end var s = \"\"; for k, v in kv() do s = \"{s}{k}{v}\"; end s;",
+            "var co = coroutine.create(lambda(a) var b = yield a + 1; return coroutine.yield(b * 2); end); [coroutine.resume(co, 1), coroutine.resume(co, 10), coroutine.resume(co, 3), coroutine.status(co)];",
+            "function inner() coroutine.yield(\"deep\"); end var co = coroutine.create(lambda() inner(); end); [coroutine.resume(co), coroutine.resume(co), coroutine.resume(co)];",
+            "try yield 1; catch e e.message; end",
+            "var co = coroutine.create(lambda() for x in lambda() coroutine.yield(1); end do end end); coroutine.resume(co);",
+            "var s = \"\"; function g() try yield 1; yield 2; finally s = \"{s}f\"; end end for x in g() do s = \"{s}{x}\"; end s;",
+            "var g = lambda() yield 1; yield 2; end; var s = 0; for v in g do s = s * 10 + v; end s;",
+            "function h(n) yield n; end for v in h do end",
+            "var co = coroutine.create(lambda() error(\"boom\"); end); var r = nil; try coroutine.resume(co); catch e r = e.message; end [r, coroutine.status(co)];",
+            "return 1; 2;",
+            "break; var b = 5; b;",
+            "var g = 1; function f() g = g * 3; end f(); [g < 3.5, g + 0.5, g == 3.0, 7 % 2 != 1];",
+            "var i = 9223372036854775806; i = i + 1; i + 1;",
+            "function f(x) x = x % 0; end f(5);",
+            "var x = \"a\"; x = x + 1; x - 1;",
+            "var x = 5; function f() var r = x; var x = 1; return r * 10 + x; end f();",
+            "var a = [0]; a[0] = a; var b = [0]; b[0] = b; var c = [1, a]; [a == b, a == c, [c] == [c]];",
+            "function f() function a(n) if n == 0 then return 0; end return b(n - 1); end function b(n) return a(n) + 1; end return a(3); end f();",
+        ];
+        for program in programs.iter() {
+            let walked = run(program, Backend::TreeWalker);
+            let compiled = run(program, Backend::Bytecode);
+            assert!(walked == compiled, "{}\n{}\n{}", program, walked, compiled);
+        }
+    }
+
+    #[test]
+    fn test_import_in_scopes() {
+        let dir = std::env::temp_dir().join("beans_import_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("answer.bean"), "return {answer: 42};").unwrap();
+        let module = dir.join("answer");
+        let module = module.to_str().unwrap();
+        let programs = [
+            format!(
+                "function f() import \"{}\"; return answer.answer; end f();",
+                module
+            ),
+            format!(
+                "var r = nil; if true then import \"{}\"; r = answer.answer; end r;",
+                module
+            ),
+        ];
+        for program in programs.iter() {
+            for backend in [Backend::TreeWalker, Backend::Bytecode].iter() {
+                assert!(run(program, *backend) == "Ok Int: 42", "{}", program);
+            }
+        }
+        let leaked = format!("function f() import \"{}\"; end f(); answer;", module);
+        assert!(run(&leaked, Backend::TreeWalker).starts_with("UndefinedVariable"));
+    }
+
+    /// Times both backends. Run with
+    /// `cargo test --release bench_backends -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_backends() {
+        let programs = [
+            ("loop", "var s = 0; var i = 0; while i < 1000000 do s = s + i % 7; i = i + 1; end s;"),
+            ("local loop", "function f() var s = 0; var i = 0; while i < 1000000 do s = s + i % 7; i = i + 1; end return s; end f();"),
+            ("calls", "function fib(n) if n < 2 then return n; end return fib(n - 1) + fib(n - 2); end fib(25);"),
+        ];
+        for (name, program) in programs.iter() {
+            let mut times = vec![];
+            for backend in [Backend::TreeWalker, Backend::Bytecode].iter() {
+                let start = Instant::now();
+                run(program, *backend);
+                times.push(start.elapsed());
+            }
+            println!(
+                "{:<10} walker {:>10.3?}  vm {:>10.3?}",
+                name, times[0], times[1]
+            );
+        }
+    }
+
+    #[test]
+    fn test_deep_recursion() {
+        let program = "function count(n) if n == 0 then return 0; end return 1 + count(n - 1); end count(5000);";
+        assert!(run(program, Backend::Bytecode) == "Ok Int: 5000");
+    }
+
+    #[test]
+    fn test_sample_programs() {
+        // Imports in the samples are relative to the repository root
+        std::env::set_current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/..")).unwrap();
+        let mut files: Vec<String> = std::fs::read_dir("tests")
+            .unwrap()
+            .map(|entry| entry.unwrap().path().to_string_lossy().into_owned())
+            .filter(|path| path.ends_with(".bean"))
+            .collect();
+        files.sort();
+        assert!(!files.is_empty());
+        for file in &files {
+            let results: Vec<String> = [Backend::TreeWalker, Backend::Bytecode]
+                .iter()
+                .map(|backend| {
+                    let glob = beans::create_global();
+                    glob.borrow_mut().build_stdlib();
+                    let mut evaluator = beans::create_evaluator_with(glob, *backend);
+                    show(beans::do_file(file, &mut evaluator))
+                })
+                .collect();
+            assert!(results[0] == results[1], "{}: {:?}", file, results);
+        }
+    }
+
+    #[test]
+    fn test_use_before_declaration() {
+        let program = "var x = 5; function f() var r = x; var x = 1; return r * 10 + x; end f();";
+        for backend in [Backend::TreeWalker, Backend::Bytecode].iter() {
+            assert!(run(program, *backend) == "Ok Int: 51");
+            // Top-level statements only see the globals declared before them
+            for early in ["print(y); var y = 1;", "y = 5; var y = 1;"].iter() {
+                let result = run(early, *backend);
+                assert!(result.starts_with("UndefinedVariable"), "{}", result);
+            }
+        }
+    }
+}
+
+struct CallFrame {
+    chunk: Rc<Chunk>,
+    ip: usize,
+    env: Rc<RefCell<Env>>,
+    stack_base: usize,
+    // None for top-level code
+    function: Option<Rc<Function>>,
+    call_site: Span,
+}
+
+/// A `try` statement whose body or `catch` clause is running
+struct Handler {
+    // Frames when it started, the last one running the statement
+    frames: usize,
+    ip: usize,
+    stack: usize,
+    env: Rc<RefCell<Env>>,
+}
+
+/// An error caught by a handler, on the stack until `catch` binds it
+struct Raised(BeansError);
+
+/// Operand stack and call frames of the bytecode backend
+#[derive(Default)]
+pub struct Vm {
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    handlers: Vec<Handler>,
+    // The status of the coroutine the VM runs, if it runs one
+    coroutine: Option<Rc<Cell<Status>>>,
+    // What the coroutine yielded when it last stopped
+    yielded: Option<Value>,
+}
+
+impl Vm {
+    /// The VM of a coroutine about to call `closure`
+    pub(crate) fn calling(closure: &Closure, args: Vec<Value>, status: Rc<Cell<Status>>) -> Vm {
+        let function = closure.get_function();
+        let env = Closure::call_env(closure, args);
+        let mut vm = Vm::default();
+        vm.frames.push(CallFrame {
+            chunk: function.chunk.clone(),
+            ip: 0,
+            env: Rc::new(RefCell::new(env)),
+            stack_base: 0,
+            function: Some(function),
+            call_site: Span::default(),
+        });
+        vm.coroutine = Some(status);
+        vm
+    }
+}
+
+impl Evaluator {
+    pub(crate) fn run_statement(&mut self, chunk: Chunk) -> StatementResult {
+        let env = self.current.clone();
+        self.run_frame(Rc::new(chunk), 0, env, None)
+    }
+
+    /// Runs the statements of a chunk from `Compiler::compile_program` in
+    /// order, stopping like `execute_program`
+    pub(crate) fn run_program(&mut self, chunk: Chunk) -> StatementResult {
+        let chunk = Rc::new(chunk);
+        let mut result = StatementResult::Continue(None);
+        for &start in &chunk.statements {
+            let env = self.current.clone();
+            result = self.run_frame(chunk.clone(), start, env, None);
+            if !result.goes_on() {
+                break;
+            }
+        }
+        result
+    }
+
+    pub(crate) fn call_closure(
+        &mut self,
+        closure: &Closure,
+        args: Vec<Value>,
+    ) -> Result<Value, BeansError> {
+        let function = closure.get_function();
+        let env = Rc::new(RefCell::new(Closure::call_env(closure, args)));
+        match self.run_frame(function.chunk.clone(), 0, env, Some(function)) {
+            StatementResult::Return(v) => Ok(v),
+            StatementResult::Failure(why) => Err(why),
+            _ => Ok(Value::Nil),
+        }
+    }
+
+    /// Runs the coroutine `vm` belongs to until it yields or returns,
+    /// pushing `arg` as the value of the `yield` it paused at
+    pub(crate) fn run_coroutine(
+        &mut self,
+        mut vm: Vm,
+        arg: Option<Value>,
+    ) -> (Vm, Result<Resumed, BeansError>) {
+        vm.stack.extend(arg);
+        std::mem::swap(&mut self.vm, &mut vm);
+        let result = self.execute(0);
+        std::mem::swap(&mut self.vm, &mut vm);
+        let result = match (vm.yielded.take(), result) {
+            (Some(value), _) => Ok(Resumed::Yielded(value)),
+            (None, StatementResult::Return(value)) => Ok(Resumed::Returned(value)),
+            (None, StatementResult::Failure(why)) => Err(why),
+            (None, _) => Ok(Resumed::Returned(Value::Nil)),
+        };
+        (vm, result)
+    }
+
+    pub(crate) fn running_coroutine(&self) -> Option<Rc<Cell<Status>>> {
+        self.vm.coroutine.clone()
+    }
+
+    /// Why the running code can't yield
+    pub(crate) fn yield_error(&self) -> BeansError {
+        let message = match self.vm.coroutine {
+            Some(_) => "Cannot yield across a native function call",
+            None => "Cannot yield outside of a coroutine",
+        };
+        BeansError::new(ErrorKind::InvalidControlFlow, String::from(message))
+    }
+
+    /// Suspends the running coroutine, unless native code called the
+    /// function yielding
+    fn yield_value(
+        &mut self,
+        value: Value,
+        base: usize,
+    ) -> Result<Option<StatementResult>, BeansError> {
+        if self.vm.coroutine.is_none() || base != 0 {
+            return Err(self.yield_error());
+        }
+        self.vm.yielded = Some(value);
+        Ok(Some(StatementResult::Ok(Value::Nil)))
+    }
+
+    fn run_frame(
+        &mut self,
+        chunk: Rc<Chunk>,
+        ip: usize,
+        env: Rc<RefCell<Env>>,
+        function: Option<Rc<Function>>,
+    ) -> StatementResult {
+        let base = self.vm.frames.len();
+        let stack_base = self.vm.stack.len();
+        let handlers = self.vm.handlers.len();
+        self.vm.frames.push(CallFrame {
+            chunk,
+            ip,
+            env,
+            stack_base,
+            function,
+            call_site: Span::default(),
+        });
+        let result = self.execute(base);
+        self.vm.frames.truncate(base);
+        self.vm.stack.truncate(stack_base);
+        self.vm.handlers.truncate(handlers);
+        result
+    }
+
+    fn pop(&mut self) -> Value {
+        self.vm.stack.pop().unwrap()
+    }
+
+    /// Drops the value on top of the stack where it lies
+    fn discard(&mut self) {
+        let n = self.vm.stack.len();
+        self.vm.stack.truncate(n - 1);
+    }
+
+    fn pop_many(&mut self, n: usize) -> Vec<Value> {
+        let at = self.vm.stack.len() - n;
+        self.vm.stack.split_off(at)
+    }
+
+    fn execute(&mut self, base: usize) -> StatementResult {
+        // The chunk of the innermost frame, refreshed whenever a call or return changes it
+        let mut chunk = self.vm.frames.last().unwrap().chunk.clone();
+        let mut depth = self.vm.frames.len();
+        loop {
+            let frame = self.vm.frames.last_mut().unwrap();
+            let op = chunk.code[frame.ip];
+            frame.ip += 1;
+            match self.step(op, &chunk, base) {
+                Ok(None) => {}
+                Ok(Some(result)) => return result,
+                Err(why) => match self.vm.handlers.last() {
+                    // Handlers of outer runs are left to them
+                    Some(handler) if handler.frames > base => self.catch(why, op),
+                    _ => return StatementResult::Failure(self.unwind(why, op, base + 1)),
+                },
+            }
+            if self.vm.frames.len() != depth {
+                depth = self.vm.frames.len();
+                chunk = self.vm.frames.last().unwrap().chunk.clone();
+            }
+        }
+    }
+
+    fn env(&self) -> &Rc<RefCell<Env>> {
+        &self.vm.frames.last().unwrap().env
+    }
+
+    /// Locates an error and records the calls it unwinds through, like the
+    /// tree-walker does on its way back up, until `frames` are left
+    fn unwind(&mut self, why: BeansError, op: Op, frames: usize) -> BeansError {
+        let frame = self.vm.frames.last().unwrap();
+        let mut why = match op {
+            Op::ControlFlowError => why,
+            _ => why.at(frame.chunk.spans[frame.ip - 1]),
+        };
+        while self.vm.frames.len() > frames {
+            let frame = self.vm.frames.pop().unwrap();
+            let name = frame.function.unwrap().name.clone();
+            why = why
+                .push_frame(Frame::new(name, frame.call_site))
+                .at(frame.call_site);
+        }
+        why
+    }
+
+    /// Resumes at the innermost handler with the error on the stack
+    fn catch(&mut self, why: BeansError, op: Op) {
+        let handler = self.vm.handlers.pop().unwrap();
+        let why = self.unwind(why, op, handler.frames);
+        self.vm.stack.truncate(handler.stack);
+        let frame = self.vm.frames.last_mut().unwrap();
+        frame.ip = handler.ip;
+        frame.env = handler.env;
+        self.vm
+            .stack
+            .push(Value::UserData(UserData::new(Raised(why))));
+    }
+
+    fn raised(&mut self) -> BeansError {
+        match self.pop() {
+            Value::UserData(data) => data.borrow_mut::<Raised>().unwrap().0.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    // Inlined into the dispatch loop, so instructions don't pay for a call
+    // and for moving its result around
+    #[inline(always)]
+    fn step(
+        &mut self,
+        op: Op,
+        chunk: &Chunk,
+        base: usize,
+    ) -> Result<Option<StatementResult>, BeansError> {
+        match op {
+            Op::Constant(i) => self.vm.stack.push(chunk.constants[i as usize].clone()),
+            Op::Nil => self.vm.stack.push(Value::Nil),
+            Op::True => self.vm.stack.push(Value::Bool(true)),
+            Op::False => self.vm.stack.push(Value::Bool(false)),
+            Op::Pop => self.discard(),
+            Op::GetVar(i) => {
+                let value = self.env().borrow().get(&chunk.names[i as usize]);
+                self.vm.stack.push(value);
+            }
+            Op::SetVar(i) => {
+                let value = self.vm.stack.last().unwrap().clone();
+                self.env()
+                    .borrow_mut()
+                    .set(chunk.names[i as usize].clone(), value)?;
+            }
+            Op::StoreVar(i) => {
+                let value = self.pop();
+                self.env()
+                    .borrow_mut()
+                    .set(chunk.names[i as usize].clone(), value)?;
+            }
+            Op::DefineVar(i) => {
+                let value = self.vm.stack.last().unwrap().clone();
+                self.env()
+                    .borrow_mut()
+                    .insert(chunk.names[i as usize].clone(), value);
+            }
+            Op::GetLocal(depth, slot) => {
+                let value = self.env().borrow().get_slot(depth as usize, slot as usize);
+                self.vm.stack.push(value);
+            }
+            Op::SetLocal(depth, slot) => {
+                let value = self.vm.stack.last().unwrap().clone();
+                self.env()
+                    .borrow_mut()
+                    .set_slot(depth as usize, slot as usize, value);
+            }
+            Op::StoreLocal(depth, slot) => {
+                let value = self.pop();
+                self.env()
+                    .borrow_mut()
+                    .set_slot(depth as usize, slot as usize, value);
+            }
+            Op::DefineLocal(slot) => {
+                let value = self.vm.stack.last().unwrap().clone();
+                self.env().borrow_mut().set_slot(0, slot as usize, value);
+            }
+            Op::Get(i) => {
+                let base = self.pop();
+                let value = Evaluator::get_key(base, chunk.keys[i as usize].as_key())?;
+                self.vm.stack.push(value);
+            }
+            Op::Set(i) => {
+                let base = self.pop();
+                let value = self.vm.stack.last().unwrap();
+                Evaluator::set_key(base, chunk.keys[i as usize].as_key(), value)?;
+            }
+            Op::Index => {
+                let key = self.pop();
+                let base = self.pop();
+                self.vm.stack.push(Evaluator::index(base, key)?);
+            }
+            Op::SetIndex => {
+                let key = self.pop();
+                let base = self.pop();
+                let value = self.vm.stack.last().unwrap();
+                Evaluator::set_index(base, key, value)?;
+            }
+            Op::Slice => {
+                let hi = self.pop();
+                let lo = self.pop();
+                let base = self.pop();
+                self.vm.stack.push(Evaluator::slice(base, lo, hi)?);
+            }
+            // Integers are worked on where they lie on the stack, which
+            // spares moving values around in the most common case
+            Op::Binary(op) => {
+                let n = self.vm.stack.len();
+                if let [Value::Int(a), Value::Int(b)] = self.vm.stack[n - 2..] {
+                    if let Some(value) = int_binary_op(a, op, b) {
+                        self.vm.stack.truncate(n - 1);
+                        self.vm.stack[n - 2] = value;
+                        return Ok(None);
+                    }
+                }
+                let r = self.pop();
+                let l = self.pop();
+                self.vm.stack.push(Evaluator::binary_op(l, op, r)?);
+            }
+            Op::BinaryConstant(op, i) => {
+                let r = &chunk.constants[i as usize];
+                let top = self.vm.stack.last_mut().unwrap();
+                if let (Value::Int(a), Value::Int(b)) = (&*top, r) {
+                    if let Some(value) = int_binary_op(*a, op, *b) {
+                        *top = value;
+                        return Ok(None);
+                    }
+                }
+                let l = self.pop();
+                self.vm.stack.push(Evaluator::binary_op(l, op, r.clone())?);
+            }
+            Op::Negate => {
+                let mut v = self.pop();
+                self.vm.stack.push(v.negate()?);
+            }
+            Op::Jump(target) => self.vm.frames.last_mut().unwrap().ip = target as usize,
+            Op::Iterate(pairs) => {
+                let iterable = self.pop();
+                let iteration = Iteration::new(self, iterable, pairs)?;
+                self.vm
+                    .stack
+                    .push(Value::UserData(UserData::new(iteration)));
+            }
+            Op::ForNext(exit) => {
+                let state = match self.vm.stack.last() {
+                    Some(Value::UserData(state)) => state.clone(),
+                    _ => unreachable!(),
+                };
+                let call_site = chunk.spans[self.vm.frames.last().unwrap().ip - 1];
+                let mut iteration = state.borrow_mut::<Iteration>().unwrap();
+                match iteration.next(self, call_site)? {
+                    Some((first, second)) => {
+                        if iteration.is_pairs() {
+                            self.vm.stack.push(second);
+                        }
+                        self.vm.stack.push(first);
+                    }
+                    None => {
+                        self.pop();
+                        self.vm.frames.last_mut().unwrap().ip = exit as usize;
+                    }
+                }
+            }
+            Op::JumpIfFalse(target) => {
+                let jump = !Evaluator::is_true(self.vm.stack.last().unwrap());
+                self.discard();
+                if jump {
+                    self.vm.frames.last_mut().unwrap().ip = target as usize;
+                }
+            }
+            Op::CheckCall(argc) => match self.vm.stack.last().unwrap() {
+                Value::Callable(call) => {
+                    if call.arity() != argc as i8 && call.arity() != -1 {
+                        return Err(BeansError::new(
+                            ErrorKind::ArityMismatch,
+                            format!(
+                                "Arguments differ in size! Expected {}, got {}",
+                                call.arity(),
+                                argc
+                            ),
+                        ));
+                    }
+                }
+                callable_maybe => {
+                    return Err(BeansError::new(
+                        ErrorKind::NotCallable,
+                        format!("Can't call value of type {}!", callable_maybe.stringify()),
+                    ))
+                }
+            },
+            Op::Call(argc) => {
+                let call_site = chunk.spans[self.vm.frames.last().unwrap().ip - 1];
+                let args = self.pop_many(argc as usize);
+                let call = match self.pop() {
+                    Value::Callable(call) => call,
+                    _ => unreachable!(),
+                };
+                match call.as_closure() {
+                    Some(closure) if !closure.is_generator() => {
+                        let function = closure.get_function();
+                        let env = Closure::call_env(closure, args);
+                        self.vm.frames.push(CallFrame {
+                            chunk: function.chunk.clone(),
+                            ip: 0,
+                            env: Rc::new(RefCell::new(env)),
+                            stack_base: self.vm.stack.len(),
+                            function: Some(function),
+                            call_site,
+                        });
+                    }
+                    _ if call.is_yield() => {
+                        let value = args.into_iter().next().unwrap_or(Value::Nil);
+                        return self
+                            .yield_value(value, base)
+                            .map_err(|why| why.push_frame(Frame::new(call.name(), call_site)));
+                    }
+                    _ => {
+                        let value = call
+                            .call(self, args)
+                            .map_err(|why| why.push_frame(Frame::new(call.name(), call_site)))?;
+                        self.vm.stack.push(value);
+                    }
+                }
+            }
+            Op::Closure(i) => {
+                let function = chunk.functions[i as usize].clone();
+                let closure = Closure::with_function(function, self.env().clone());
+                self.vm
+                    .stack
+                    .push(Value::Callable(Rc::new(Box::new(closure))));
+            }
+            Op::Struct(i) => {
+                let (name, members) = &chunk.structs[i as usize];
+                let base_strukt = BaseStruct::new(members.to_vec(), name.clone());
+                let factory = StructFactory::new(Rc::new(base_strukt));
+                self.vm
+                    .stack
+                    .push(Value::Callable(Rc::new(Box::new(factory))));
+            }
+            Op::EnumValue => {
+                if !self.vm.stack.last().unwrap().is_numeric() {
+                    return Err(BeansError::new(
+                        ErrorKind::TypeError,
+                        String::from("Enum variants can only be associated to numbers!"),
+                    ));
+                }
+            }
+            Op::Enum(i) => {
+                let (name, variants) = &chunk.enums[i as usize];
+                let explicit = variants.iter().filter(|(_, e)| *e).count();
+                let mut values = self.pop_many(explicit).into_iter();
+                let mut i = 0.0;
+                let mut fields: HashMap<String, f64> = HashMap::new();
+                for (variant, has_value) in variants {
+                    let assoc_value = if *has_value {
+                        values.next().unwrap().as_numeric()
+                    } else {
+                        i += 1.0;
+                        i - 1.0
+                    };
+                    fields.insert(variant.clone(), assoc_value);
+                }
+                self.vm.stack.push(Value::Enum(name.clone(), fields));
+            }
+            Op::List(n) => {
+                let elts = self.pop_many(n as usize);
+                self.vm.stack.push(Value::List(Rc::new(RefCell::new(elts))));
+            }
+            Op::Interpolate(n) => {
+                let parts = self.pop_many(n as usize);
+                self.vm.stack.push(Evaluator::interpolate(&parts));
+            }
+            Op::Dict(n) => {
+                let entries = self.pop_many(2 * n as usize);
+                let mut map = Dict::new();
+                for entry in entries.chunks(2) {
+                    map.insert(HashKey::from_value(&entry[0])?, entry[1].clone());
+                }
+                self.vm
+                    .stack
+                    .push(Value::Collection(Rc::new(RefCell::new(map))));
+            }
+            Op::Import(i) => {
+                let env = self.env().clone();
+                let module = self.import(&env, &chunk.names[i as usize])?;
+                self.vm.stack.push(module);
+            }
+            Op::InvalidAssignment => {
+                return Err(BeansError::new(
+                    ErrorKind::InvalidAssignment,
+                    String::from("Invalid assign target!"),
+                ))
+            }
+            Op::ControlFlowError => {
+                return Err(BeansError::new(
+                    ErrorKind::InvalidControlFlow,
+                    String::from("Cannot break or continue inside function!"),
+                ))
+            }
+            Op::Return => {
+                let value = self.pop();
+                let frame = self.vm.frames.pop().unwrap();
+                self.vm.stack.truncate(frame.stack_base);
+                let frames = self.vm.frames.len();
+                while self.vm.handlers.last().is_some_and(|h| h.frames > frames) {
+                    self.vm.handlers.pop();
+                }
+                if self.vm.frames.len() == base {
+                    return Ok(Some(StatementResult::Return(value)));
+                }
+                self.vm.stack.push(value);
+            }
+            Op::Done => return Ok(Some(StatementResult::Ok(self.pop()))),
+            Op::PushScope => {
+                let frame = self.vm.frames.last_mut().unwrap();
+                let env = Env::new_enclosing(frame.env.clone());
+                frame.env = Rc::new(RefCell::new(env));
+            }
+            Op::PopScope => {
+                let frame = self.vm.frames.last_mut().unwrap();
+                let enclosing = frame.env.borrow().enclosing().unwrap();
+                frame.env = enclosing;
+            }
+            Op::CopyScope => {
+                let frame = self.vm.frames.last_mut().unwrap();
+                let env = frame.env.borrow().copy();
+                frame.env = Rc::new(RefCell::new(env));
+            }
+            Op::Try(ip) => {
+                let handler = Handler {
+                    frames: self.vm.frames.len(),
+                    ip: ip as usize,
+                    stack: self.vm.stack.len(),
+                    env: self.env().clone(),
+                };
+                self.vm.handlers.push(handler);
+            }
+            Op::EndTry => {
+                self.vm.handlers.pop();
+            }
+            Op::Catch => {
+                let why = self.raised();
+                self.vm.stack.push(error_object(&why));
+            }
+            Op::Raise => return Err(self.raised()),
+            Op::Yield => {
+                let value = self.pop();
+                return self.yield_value(value, base);
+            }
+            Op::Break => return Ok(Some(StatementResult::Break(None))),
+            Op::Continue => return Ok(Some(StatementResult::Continue(None))),
+        }
+        Ok(None)
+    }
+}
+
+/// The common case of `Evaluator::binary_op`, on two integers. Leaves
+/// overflows, division by zero and the other operators to it.
+fn int_binary_op(a: i64, op: TokenType, b: i64) -> Option<Value> {
+    match op {
+        TokenType::Plus => a.checked_add(b).map(Value::Int),
+        TokenType::Minus => a.checked_sub(b).map(Value::Int),
+        TokenType::Star => a.checked_mul(b).map(Value::Int),
+        TokenType::Mod if b != 0 => Evaluator::floor_mod(a, b).map(Value::Int),
+        TokenType::Less => Some(Value::Bool(a < b)),
+        TokenType::LessEquals => Some(Value::Bool(a <= b)),
+        TokenType::More => Some(Value::Bool(a > b)),
+        TokenType::MoreEquals => Some(Value::Bool(a >= b)),
+        TokenType::EqualsEquals => Some(Value::Bool(a == b)),
+        TokenType::BangEquals => Some(Value::Bool(a != b)),
+        _ => None,
+    }
+}
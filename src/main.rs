@@ -1,13 +1,12 @@
 extern crate beans_lang;
-#[macro_use]
 extern crate structopt;
 
 use beans_lang::environments::Env;
+use beans_lang::evaluator::Backend;
 use beans_lang::evaluator::Evaluate;
 use beans_lang::evaluator::StatementResult;
 use beans_lang::*;
 use std::cell::RefCell;
-use std::env;
 use std::fs::File;
 use std::io::Read;
 use std::io::Write;
@@ -19,11 +18,13 @@ mod tests {
     use super::*;
     #[test]
     fn dynamic_dict() {
-        let prog = String::from("
+        let prog = String::from(
+            "
         var dic = {};
         dic.k = 42;
         dic.k;
-        ");
+        ",
+        );
 
         let env = beans::create_global();
         let mut evaluator = beans::create_evaluator(env);
@@ -42,6 +43,10 @@ struct Args {
 
     #[structopt(long = "no-stdlib")]
     pub no_stdlib: bool,
+
+    /// Run scripts on the bytecode VM instead of the tree-walking evaluator
+    #[structopt(long = "vm")]
+    pub vm: bool,
 }
 fn main() {
     let env = beans::create_global();
@@ -51,46 +56,61 @@ fn main() {
         env.as_ref().borrow_mut().build_stdlib();
     }
 
-    if args.files.len() > 0 {
-        execute_files(env, &args.files)
+    let backend = if args.vm {
+        Backend::Bytecode
+    } else {
+        Backend::TreeWalker
+    };
+
+    if !args.files.is_empty() {
+        execute_files(env, &args.files, backend)
     } else {
-        run_interpreter(env);
+        run_interpreter(env, backend);
     }
 }
 
-fn execute_files(global_env: Rc<RefCell<Env>>, file_names: &Vec<String>) {
+fn execute_files(global_env: Rc<RefCell<Env>>, file_names: &Vec<String>, backend: Backend) {
     for file_name in file_names {
         match File::open(file_name) {
             Ok(mut file) => {
                 let mut content: String = String::default();
-                if let Err(_) = file.read_to_string(&mut content) {
+                if file.read_to_string(&mut content).is_err() {
                     println!("Error! Failure while reading {}'s contents!", file_name);
                     continue;
                 }
 
-                let lexer = lexer::Lexer::new(content);
+                let lexer = lexer::Lexer::new_with_file(content, span::register_file(file_name));
                 let mut parser = parser::Parser::new(lexer);
 
                 let file_env = beans::create_enclosing(global_env.clone());
-                let mut evaluator = beans::create_evaluator(file_env);
+                let mut resolver = resolver::Resolver::new(file_env.clone());
+                let mut evaluator = beans::create_evaluator_with(file_env, backend);
                 let stmts = parser.parse();
 
                 if parser.error() {
-                    println!("Skipping file due to a parse error");
+                    for diagnostic in parser.diagnostics() {
+                        println!("{}: {}", file_name, diagnostic);
+                    }
+                    println!("Skipping file due to parse errors");
                     continue;
                 }
-                for stmt in stmts {
-                    match evaluator.execute_statement(&stmt) {
-                        StatementResult::Ok(_) | StatementResult::Return(_) => {}
-                        StatementResult::Failure(why) => {
-                            println!("Failure: {}, skipping file.", why);
-                            break;
-                        }
-                        _ => {
-                            println!("Unexpected result! Skipping file");
-                            break;
-                        }
+                resolver.resolve(&stmts);
+                if resolver.error() {
+                    for diagnostic in resolver.diagnostics() {
+                        println!("{}: {}", file_name, diagnostic);
                     }
+                    println!("Skipping file due to name resolution errors");
+                    continue;
+                }
+                match evaluator.execute_program(&stmts) {
+                    StatementResult::Failure(why) => {
+                        println!("{}", why.format_traceback());
+                        println!("Skipping file.");
+                    }
+                    StatementResult::Break(_) | StatementResult::Continue(_) => {
+                        println!("Unexpected result! Skipping file")
+                    }
+                    _ => {}
                 }
             }
             Err(_) => println!("Error! Could not open file {}", file_name),
@@ -102,12 +122,12 @@ fn get_line() -> String {
     let mut line = String::new();
     loop {
         let mut buf: [u8; 1] = [0; 1];
-        if let Err(_) = std::io::stdin().read_exact(&mut buf) {
+        if std::io::stdin().read_exact(&mut buf).is_err() {
             println!("Failure while reading from stdin");
             std::process::exit(1);
         }
 
-        let ch = char::from(*buf.get(0).unwrap());
+        let ch = char::from(buf[0]);
         line.push(ch);
         if ch == '\n' {
             break;
@@ -116,9 +136,9 @@ fn get_line() -> String {
     line
 }
 
-fn run_interpreter(global_env: Rc<RefCell<Env>>) {
-    let mut evaluator = evaluator::Evaluator::new_with_global(global_env.clone());
-    let scope_in = ["function", "if", "while", "for"];
+fn run_interpreter(global_env: Rc<RefCell<Env>>, backend: Backend) {
+    let mut evaluator = beans::create_evaluator_with(global_env.clone(), backend);
+    let scope_in = ["function", "if", "while", "for", "try"];
     let scope_out = ["end"];
     let mut current_scope = 0;
 
@@ -133,24 +153,16 @@ fn run_interpreter(global_env: Rc<RefCell<Env>>) {
             std::io::stdout().flush().unwrap();
             let current_line = get_line();
 
-            let should_scope_in = scope_in
-                .iter()
-                .map(|token| current_line.contains(token))
-                .fold(false, |acc, n| acc || n);
-            let should_scope_out = scope_out
-                .iter()
-                .map(|token| current_line.contains(token))
-                .fold(false, |acc, n| acc || n);
+            let should_scope_in = scope_in.iter().any(|token| current_line.contains(token));
+            let should_scope_out = scope_out.iter().any(|token| current_line.contains(token));
             if should_scope_in {
                 current_scope += 1;
             }
-            if should_scope_out {
-                if current_scope != 0 {
-                    current_scope -= 1;
-                }
+            if should_scope_out && current_scope != 0 {
+                current_scope -= 1;
             }
 
-            program_complete.push_str(&current_line.as_ref());
+            program_complete.push_str(current_line.as_ref());
 
             current_scope != 0
         } {}
@@ -158,6 +170,17 @@ fn run_interpreter(global_env: Rc<RefCell<Env>>) {
         let stmts = parser.parse();
 
         if parser.error() {
+            for diagnostic in parser.diagnostics() {
+                println!("{}", diagnostic);
+            }
+            continue;
+        }
+        let mut resolver = resolver::Resolver::new(global_env.clone());
+        resolver.resolve(&stmts);
+        if resolver.error() {
+            for diagnostic in resolver.diagnostics() {
+                println!("{}", diagnostic);
+            }
             continue;
         }
 
@@ -165,7 +188,7 @@ fn run_interpreter(global_env: Rc<RefCell<Env>>) {
             match evaluator.execute_statement(&stmt) {
                 StatementResult::Ok(v) => println!("{}", v.stringify()),
                 StatementResult::Return(v) => println!("{}", v.stringify()),
-                StatementResult::Failure(why) => println!("Failure: {}", why),
+                StatementResult::Failure(why) => println!("{}", why.format_traceback()),
                 _ => {
                     println!("Unexpected result while in interactive mode, stopping execution");
                     break;